@@ -0,0 +1,153 @@
+//! Timings for the code that runs on every request and every streamed chunk.
+//!
+//! Run with `cargo bench`. There is no stats engine here, each case is warmed up and then timed
+//! over a fixed number of iterations, so compare runs on the same machine only.
+//!
+//! Baseline, release profile on an x86_64 Linux VM:
+//!   chat_request_serialize   ~7.5 µs/iter
+//!   message_de_content       ~920 ns/iter
+//!   line_splitter_stream     ~28 µs/iter
+//!   markdown_reply_rows      ~7.0 µs/iter
+//!
+//! Wrapping and laying out whole messages needs the app state, so only the markdown styling of
+//! already wrapped rows is timed here, it runs for every row of every reply in view each frame.
+//! Laying out a whole history with a warm cache is timed by an ignored test in `src/ui` instead,
+//! `cargo test --release message_lines_timing -- --ignored --nocapture`:
+//!   100 messages             ~63 µs/frame
+//!   1k messages              ~780 µs/frame
+//!   10k messages             ~10 ms/frame
+
+use std::borrow::Cow;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+// without the test harness their `#[test]`s are dropped, leaving the test modules' imports unused
+#[allow(dead_code, unused_imports)]
+#[path = "../src/chat/mod.rs"]
+mod chat;
+
+#[allow(dead_code, unused_imports)]
+#[path = "../src/ui/markdown.rs"]
+mod markdown;
+
+const WARMUP: u32 = 100;
+const ITERATIONS: u32 = 2_000;
+
+fn bench(name: &str, mut f: impl FnMut()) {
+    for _ in 0..WARMUP {
+        f();
+    }
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let per_iter = start.elapsed() / ITERATIONS;
+
+    println!("{name:<26}{}", format_duration(per_iter));
+}
+
+fn format_duration(d: Duration) -> String {
+    match d.as_nanos() {
+        n if n < 1_000 => format!("{n} ns/iter"),
+        n if n < 1_000_000 => format!("{:.1} µs/iter", n as f64 / 1_000.0),
+        n => format!("{:.1} ms/iter", n as f64 / 1_000_000.0),
+    }
+}
+
+/// A conversation about as long as a typical session gets before anything is trimmed
+fn conversation() -> Vec<chat::Prompt<'static>> {
+    let system = chat::Prompt {
+        role: Cow::Borrowed("system"),
+        content: Cow::Owned("You answer only via valid, minified JSON. ".repeat(40)),
+    };
+    let turns = (0..20).map(|i| chat::Prompt {
+        role: Cow::Borrowed(if i % 2 == 0 { "user" } else { "assistant" }),
+        content: Cow::Owned(format!(
+            r#"{{"action":"chat","arguments":{{"response":"turn {i}: {}"}}}}"#,
+            "some words in a reply ".repeat(15)
+        )),
+    });
+
+    std::iter::once(system).chain(turns).collect()
+}
+
+/// An Ollama reply message, content is the packet as a JSON string like the API sends it
+fn reply_message() -> String {
+    let packet = serde_json::json!({
+        "action": "chat",
+        "arguments": { "response": "A crow is a bird. ".repeat(30) },
+    });
+    serde_json::json!({
+        "role": "assistant",
+        "content": packet.to_string(),
+    })
+    .to_string()
+}
+
+/// A streamed reply as it comes off the wire, cut into chunks that don't line up with the lines
+fn stream_chunks() -> Vec<Vec<u8>> {
+    let mut body = String::new();
+    for i in 0..200 {
+        body.push_str(&format!(
+            r#"{{"model":"mistral:latest","message":{{"role":"assistant","content":"tok{i} "}},"done":false}}"#
+        ));
+        body.push('\n');
+    }
+    body.push_str(r#"{"model":"mistral:latest","message":{"role":"assistant","content":""},"done":true,"eval_count":200}"#);
+    body.push('\n');
+
+    body.as_bytes().chunks(97).map(<[u8]>::to_vec).collect()
+}
+
+/// The rows of a reply with a bit of everything the markdown styling looks for
+fn markdown_rows() -> Vec<String> {
+    let mut rows = vec!["## Crows".to_string()];
+    for i in 0..10 {
+        rows.push(format!(
+            "Crow {i} is **very** clever and can use `tools` like a stick or a hook."
+        ));
+        rows.push(format!("- item {i} with `inline code` and **bold** text"));
+    }
+    rows.push("```rust".to_string());
+    rows.extend((0..20).map(|i| format!("    let crow_{i} = Crow::new({i});")));
+    rows.push("```".to_string());
+    rows
+}
+
+fn main() {
+    let messages = conversation();
+    bench("chat_request_serialize", || {
+        let req = chat::ChatRequest {
+            model: Cow::Borrowed("mistral:latest"),
+            messages: messages.clone(),
+            stream: true,
+            format: Some("json"),
+            stop: vec!["\n\n\n\n"],
+            options: None,
+        };
+        black_box(serde_json::to_vec(&req).unwrap());
+    });
+
+    let reply = reply_message();
+    bench("message_de_content", || {
+        black_box(serde_json::from_str::<chat::Message>(black_box(&reply)).unwrap());
+    });
+
+    let chunks = stream_chunks();
+    bench("line_splitter_stream", || {
+        let mut lines = chat::LineSplitter::default();
+        for chunk in &chunks {
+            black_box(lines.push(chunk));
+        }
+        black_box(lines.finish());
+    });
+
+    let rows = markdown_rows();
+    bench("markdown_reply_rows", || {
+        let mut in_code = false;
+        for row in &rows {
+            black_box(markdown::markdown_line(black_box(row), &mut in_code));
+        }
+    });
+}
@@ -0,0 +1,376 @@
+//! Wire formats of the chat APIs we can talk to. Sending, streaming and the watchdog are the same
+//! for all of them, only how a request is written and a reply read differs.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+use crate::chat::{self, ChatRequest, MessageRoles, StreamChunk, StreamMessage};
+
+pub trait ChatBackend {
+    /// Where chat requests go on `host`
+    fn chat_url(&self, host: &str) -> String;
+    /// The request body as this API wants it
+    fn body(&self, req: &ChatRequest<'_>) -> Vec<u8>;
+    /// A whole reply, as sent when streaming is off
+    fn parse_reply(&self, body: &[u8]) -> anyhow::Result<StreamChunk>;
+    /// One line of a streamed reply, `None` for lines that carry nothing
+    fn parse_stream_line(&self, line: &[u8]) -> Option<StreamChunk>;
+}
+
+/// Which API the host speaks, `--backend` overrides the config
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    #[default]
+    Ollama,
+    /// Anything with an OpenAI style `/v1/chat/completions`: llama.cpp, vLLM, LM Studio, OpenAI itself
+    #[value(name = "openai")]
+    OpenAi,
+}
+
+impl Backend {
+    /// Name as `--backend` takes it
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Backend::Ollama => "ollama",
+            Backend::OpenAi => "openai",
+        }
+    }
+
+    /// Whether a `/set` option has nowhere to go in this API's requests and is left out
+    pub fn drops_option(self, field: &str) -> bool {
+        match self {
+            Backend::Ollama => false,
+            Backend::OpenAi => matches!(field, "top_k" | "repeat_penalty"),
+        }
+    }
+
+    /// What the host calls itself, doubles as a health check
+    pub async fn probe(
+        self,
+        client: &reqwest::Client,
+        host: &str,
+        api_key: Option<&str>,
+        timeout: Duration,
+    ) -> Result<String, reqwest::Error> {
+        match self {
+            Backend::Ollama => chat::fetch_version(client, host, timeout)
+                .await
+                .map(|version| format!("ollama {version}")),
+            Backend::OpenAi => {
+                let mut builder = client
+                    .get(chat::endpoint(host, "v1/models"))
+                    .timeout(timeout);
+                if let Some(key) = api_key {
+                    builder = builder.bearer_auth(key);
+                }
+                builder.send().await?.error_for_status()?;
+                Ok("openai-compatible".to_string())
+            }
+        }
+    }
+}
+
+impl ChatBackend for Backend {
+    fn chat_url(&self, host: &str) -> String {
+        match self {
+            Backend::Ollama => Ollama.chat_url(host),
+            Backend::OpenAi => OpenAi.chat_url(host),
+        }
+    }
+
+    fn body(&self, req: &ChatRequest<'_>) -> Vec<u8> {
+        match self {
+            Backend::Ollama => Ollama.body(req),
+            Backend::OpenAi => OpenAi.body(req),
+        }
+    }
+
+    fn parse_reply(&self, body: &[u8]) -> anyhow::Result<StreamChunk> {
+        match self {
+            Backend::Ollama => Ollama.parse_reply(body),
+            Backend::OpenAi => OpenAi.parse_reply(body),
+        }
+    }
+
+    fn parse_stream_line(&self, line: &[u8]) -> Option<StreamChunk> {
+        match self {
+            Backend::Ollama => Ollama.parse_stream_line(line),
+            Backend::OpenAi => OpenAi.parse_stream_line(line),
+        }
+    }
+}
+
+/// Ollama's own `/api/chat`, `ChatRequest` and `StreamChunk` are its shapes already
+pub struct Ollama;
+
+impl ChatBackend for Ollama {
+    fn chat_url(&self, host: &str) -> String {
+        chat::endpoint(host, "api/chat")
+    }
+
+    fn body(&self, req: &ChatRequest<'_>) -> Vec<u8> {
+        serde_json::to_vec(req).expect("ChatRequest is always representable as JSON")
+    }
+
+    fn parse_reply(&self, body: &[u8]) -> anyhow::Result<StreamChunk> {
+        Ok(serde_json::from_slice(body)?)
+    }
+
+    fn parse_stream_line(&self, line: &[u8]) -> Option<StreamChunk> {
+        serde_json::from_slice(line).ok()
+    }
+}
+
+/// `/v1/chat/completions`. It has no `top_k` or `repeat_penalty`, those are left out, and no
+/// timings, so the nerd stats only get token counts.
+pub struct OpenAi;
+
+#[derive(Deserialize)]
+struct Completion {
+    #[serde(default)]
+    choices: Vec<Choice>,
+    usage: Option<CompletionUsage>,
+}
+
+#[derive(Deserialize)]
+struct Choice {
+    /// Whole replies have a message, streamed ones a delta, never both
+    message: Option<Delta>,
+    delta: Option<Delta>,
+}
+
+#[derive(Deserialize, Default)]
+struct Delta {
+    #[serde(default)]
+    content: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CompletionUsage {
+    prompt_tokens: Option<u64>,
+    completion_tokens: u64,
+}
+
+impl Completion {
+    fn into_chunk(self, done: bool) -> StreamChunk {
+        let content = self
+            .choices
+            .into_iter()
+            .next()
+            .and_then(|c| c.message.or(c.delta))
+            .and_then(|d| d.content)
+            .unwrap_or_default();
+
+        StreamChunk {
+            message: StreamMessage {
+                role: MessageRoles::Assistant,
+                content,
+            },
+            done,
+            eval_count: self.usage.as_ref().map(|u| u.completion_tokens),
+            prompt_eval_count: self.usage.and_then(|u| u.prompt_tokens),
+            total_duration: None,
+            eval_duration: None,
+        }
+    }
+}
+
+impl ChatBackend for OpenAi {
+    fn chat_url(&self, host: &str) -> String {
+        chat::endpoint(host, "v1/chat/completions")
+    }
+
+    fn body(&self, req: &ChatRequest<'_>) -> Vec<u8> {
+        let messages = req
+            .messages
+            .iter()
+            .map(|p| {
+                // a tool message needs the id of a native tool call here, ours are plain JSON
+                // replies, so the result goes back as a user message instead
+                let role = match p.role.as_ref() {
+                    "tool" => "user",
+                    role => role,
+                };
+                json!({ "role": role, "content": p.content })
+            })
+            .collect::<Vec<_>>();
+
+        let mut body = json!({
+            "model": req.model,
+            "messages": messages,
+            "stream": req.stream,
+        });
+        let fields = body.as_object_mut().expect("built as an object above");
+        if !req.stop.is_empty() {
+            fields.insert("stop".into(), json!(req.stop));
+        }
+        if req.format == Some("json") {
+            fields.insert("response_format".into(), json!({ "type": "json_object" }));
+        }
+        if req.stream {
+            // without it a stream never says how many tokens it took
+            fields.insert("stream_options".into(), json!({ "include_usage": true }));
+        }
+        if let Some(options) = &req.options {
+            let mapped = [
+                ("temperature", options.temperature.map(Value::from)),
+                ("top_p", options.top_p.map(Value::from)),
+                ("seed", options.seed.map(Value::from)),
+            ];
+            for (key, value) in mapped {
+                if let Some(value) = value {
+                    fields.insert(key.into(), value);
+                }
+            }
+        }
+
+        serde_json::to_vec(&body).expect("a json! value is always representable as JSON")
+    }
+
+    fn parse_reply(&self, body: &[u8]) -> anyhow::Result<StreamChunk> {
+        Ok(serde_json::from_slice::<Completion>(body)?.into_chunk(true))
+    }
+
+    fn parse_stream_line(&self, line: &[u8]) -> Option<StreamChunk> {
+        // server-sent events, every payload line starts with `data:` and the last one is [DONE]
+        let line = std::str::from_utf8(line).ok()?.trim();
+        let data = line.strip_prefix("data:")?.trim();
+        if data == "[DONE]" {
+            return Some(
+                Completion {
+                    choices: Vec::new(),
+                    usage: None,
+                }
+                .into_chunk(true),
+            );
+        }
+        serde_json::from_str::<Completion>(data)
+            .ok()
+            .map(|c| c.into_chunk(false))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::{ChatOptions, Prompt};
+
+    fn prompt(role: &'static str, content: &'static str) -> Prompt<'static> {
+        Prompt {
+            role: role.into(),
+            content: content.into(),
+        }
+    }
+
+    fn request(stream: bool, format: Option<&'static str>) -> ChatRequest<'static> {
+        ChatRequest {
+            model: "llama3".into(),
+            messages: vec![
+                prompt("system", "be brief"),
+                prompt("user", "what time is it?"),
+                prompt("tool", "12:00"),
+            ],
+            stream,
+            format,
+            stop: vec!["\n\n\n\n"],
+            options: Some(ChatOptions {
+                temperature: Some(0.5),
+                top_p: Some(0.9),
+                top_k: Some(40),
+                repeat_penalty: Some(1.1),
+                seed: Some(7),
+            }),
+        }
+    }
+
+    fn openai_body(req: &ChatRequest<'_>) -> Value {
+        serde_json::from_slice(&OpenAi.body(req)).unwrap()
+    }
+
+    #[test]
+    fn openai_body_has_the_completions_shape() {
+        let body = openai_body(&request(false, None));
+        assert_eq!(body["model"], "llama3");
+        assert_eq!(body["stream"], false);
+        assert_eq!(body["stop"], json!(["\n\n\n\n"]));
+        assert_eq!(
+            body["messages"][1],
+            json!({ "role": "user", "content": "what time is it?" })
+        );
+        assert!(body.get("format").is_none());
+        assert!(body.get("response_format").is_none());
+        assert!(body.get("stream_options").is_none());
+
+        let streamed = openai_body(&request(true, None));
+        assert_eq!(streamed["stream_options"], json!({ "include_usage": true }));
+    }
+
+    #[test]
+    fn openai_tool_results_go_back_as_user_messages() {
+        let body = openai_body(&request(false, None));
+        let roles: Vec<_> = body["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["role"].as_str().unwrap())
+            .collect();
+        assert_eq!(roles, ["system", "user", "user"]);
+        assert_eq!(body["messages"][2]["content"], "12:00");
+    }
+
+    #[test]
+    fn openai_json_format_becomes_a_response_format() {
+        let body = openai_body(&request(false, Some("json")));
+        assert_eq!(body["response_format"], json!({ "type": "json_object" }));
+    }
+
+    #[test]
+    fn openai_options_map_what_the_api_has() {
+        let body = openai_body(&request(false, None));
+        assert_eq!(body["temperature"], json!(0.5f32));
+        assert_eq!(body["top_p"], json!(0.9f32));
+        assert_eq!(body["seed"], 7);
+        assert!(body.get("top_k").is_none());
+        assert!(body.get("repeat_penalty").is_none());
+        assert!(body.get("options").is_none());
+
+        assert!(Backend::OpenAi.drops_option("top_k"));
+        assert!(Backend::OpenAi.drops_option("repeat_penalty"));
+        assert!(!Backend::OpenAi.drops_option("seed"));
+        assert!(!Backend::Ollama.drops_option("top_k"));
+    }
+
+    #[test]
+    fn openai_stream_reads_data_lines_until_done() {
+        let delta = br#"data: {"choices":[{"delta":{"content":"Hel"}}]}"#;
+        let chunk = OpenAi.parse_stream_line(delta).unwrap();
+        assert_eq!(chunk.message.content, "Hel");
+        assert!(!chunk.done);
+
+        let usage = br#"data: {"choices":[],"usage":{"prompt_tokens":12,"completion_tokens":3}}"#;
+        let chunk = OpenAi.parse_stream_line(usage).unwrap();
+        assert_eq!(chunk.message.content, "");
+        assert_eq!(chunk.eval_count, Some(3));
+        assert_eq!(chunk.prompt_eval_count, Some(12));
+
+        let done = OpenAi.parse_stream_line(b"data: [DONE]").unwrap();
+        assert!(done.done);
+
+        // keep-alives and blank lines between events carry nothing
+        assert!(OpenAi.parse_stream_line(b": keep-alive").is_none());
+        assert!(OpenAi.parse_stream_line(b"").is_none());
+    }
+
+    #[test]
+    fn openai_whole_reply_reads_the_message() {
+        let body = br#"{"choices":[{"message":{"role":"assistant","content":"Hi"}}],
+                        "usage":{"prompt_tokens":5,"completion_tokens":1}}"#;
+        let chunk = OpenAi.parse_reply(body).unwrap();
+        assert_eq!(chunk.message.content, "Hi");
+        assert!(chunk.done);
+        assert_eq!(chunk.eval_count, Some(1));
+    }
+}
@@ -1,22 +1,107 @@
-use serde::de::{self, Deserializer as DeDeserializer, IntoDeserializer, Visitor};
-use serde::{Deserialize, Serialize, Serializer, Deserializer};
+use serde::de::{self, Deserializer as DeDeserializer};
+use serde::{Deserialize, Serialize};
 
 use std::borrow::Cow;
 use std::collections::HashMap;
-use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fmt::{Display, Formatter, Result as FmtResult, Write as _};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 #[derive(Deserialize, Debug)]
 pub struct StreamChunk {
     pub message: StreamMessage,
+    #[serde(default)]
+    pub done: bool,
+    /// Only present on the final chunk (or a non-streamed reply)
+    pub eval_count: Option<u64>,
+    /// Tokens the prompt took up, only on the final chunk and left out when the prompt was cached
+    pub prompt_eval_count: Option<u64>,
+    /// Nanoseconds the whole request took, final chunk only
+    pub total_duration: Option<u64>,
+    /// Nanoseconds spent generating, final chunk only
+    pub eval_duration: Option<u64>,
+}
+
+impl StreamChunk {
+    pub fn usage(&self) -> Option<Usage> {
+        self.eval_count.map(|completion_tokens| Usage {
+            completion_tokens,
+            prompt_tokens: self.prompt_eval_count,
+            total_duration: self.total_duration,
+            eval_duration: self.eval_duration,
+        })
+    }
+}
+
+/// Token counts the backend reported for one reply, and how long it took (in nanoseconds)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Usage {
+    pub completion_tokens: u64,
+    pub prompt_tokens: Option<u64>,
+    pub total_duration: Option<u64>,
+    pub eval_duration: Option<u64>,
+}
+
+impl Usage {
+    /// Generation speed, `None` if the backend didn't say how long it took
+    pub fn tokens_per_sec(&self) -> Option<f64> {
+        let secs = self.eval_duration.filter(|&ns| ns > 0)? as f64 / 1e9;
+        Some(self.completion_tokens as f64 / secs)
+    }
+
+    /// Speed, latency and token counts for the status bar, `—` for anything not reported
+    pub fn stats(&self) -> String {
+        const MISSING: &str = "—";
+        let speed = self
+            .tokens_per_sec()
+            .map_or(MISSING.to_string(), |tps| format!("{tps:.1}"));
+        let latency = self
+            .total_duration
+            .map_or(MISSING.to_string(), |ns| (ns / 1_000_000).to_string());
+        let prompt = self
+            .prompt_tokens
+            .map_or(MISSING.to_string(), |n| n.to_string());
+        format!(
+            "{speed} tok/s, {latency} ms, {prompt} prompt / {} generated",
+            self.completion_tokens
+        )
+    }
+}
+
+/// Tokens a conversation has cost so far, saved along with it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(default)]
+pub struct TokenTotals {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+impl TokenTotals {
+    pub fn add(&mut self, usage: Usage) {
+        self.prompt_tokens += usage.prompt_tokens.unwrap_or(0);
+        self.completion_tokens += usage.completion_tokens;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+impl std::ops::AddAssign for TokenTotals {
+    fn add_assign(&mut self, other: Self) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+    }
 }
 
 #[derive(Deserialize, Debug)]
+#[allow(dead_code)]
 pub struct StreamMessage {
-    pub role: String,
+    pub role: MessageRoles,
     pub content: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Prompt<'a> {
     pub role: Cow<'a, str>,
     pub content: Cow<'a, str>,
@@ -25,66 +110,284 @@ pub struct Prompt<'a> {
 impl<'a> From<Message> for Prompt<'a> {
     fn from(message: Message) -> Self {
         Prompt {
-            role: Cow::Owned(message.role),
+            role: Cow::Owned(message.role.to_string()),
             content: Cow::Owned(message.content.to_string()),
         }
     }
 }
 
-#[derive(Serialize, Debug)]
+/// `text` in a code fence long enough that no run of backticks inside it closes the block early
+fn fenced(lang: &str, text: &str) -> String {
+    let longest = text.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    let fence = "`".repeat(longest.max(2) + 1);
+    format!("{fence}{lang}\n{}\n{fence}\n", text.trim_end())
+}
+
+/// Characters in a prompt list, what a token estimate works from
+pub fn prompt_chars(prompts: &[Prompt<'_>]) -> usize {
+    prompts
+        .iter()
+        .map(|p| p.role.chars().count() + p.content.chars().count())
+        .sum()
+}
+
+/// How replies are asked for and read back
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Mode {
+    /// Plain text both ways, no tools and no JSON format
+    Chat,
+    /// Every reply is an action packet, so the model can call tools
+    Agent,
+}
+
+impl Display for Mode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Mode::Chat => write!(f, "chat"),
+            Mode::Agent => write!(f, "agent"),
+        }
+    }
+}
+
+/// System instructions, layered so a note for one chat or one turn never clobbers the base rules
+#[derive(Default)]
+pub struct SystemLayers {
+    /// The assistant's rules and tool list, sent with every request
+    pub base: String,
+    /// Layout of the working directory, with `--project-tree` or after /tree
+    pub project: Option<String>,
+    /// Applies to the rest of this conversation
+    pub conversation: Option<String>,
+    /// Applies to the turn in progress only
+    pub turn: Option<String>,
+    /// Language replies should be written in, sent last so it is the freshest instruction
+    pub language: Option<String>,
+}
+
+impl SystemLayers {
+    /// One system message per active layer, broadest first
+    pub fn prompts(&self) -> impl Iterator<Item = Prompt<'static>> {
+        std::iter::once(self.base.clone())
+            .chain(self.project.as_deref().map(project_instruction))
+            .chain(self.conversation.clone())
+            .chain(self.turn.clone())
+            .chain(self.language.as_deref().map(language_instruction))
+            .map(|content| Prompt {
+                role: Cow::Owned(MessageRoles::System.to_string()),
+                content: Cow::Owned(content),
+            })
+    }
+}
+
+/// Hand the model the project's layout up front, so it knows what there is to ask for
+fn project_instruction(tree: &str) -> String {
+    format!("The working directory, which the file tools are confined to, holds:\n{tree}")
+}
+
+/// Ask for replies in `language` without letting the translation reach the JSON itself
+fn language_instruction(language: &str) -> String {
+    format!(
+        "Write every reply to the user in {language}. Action names, argument keys and tool arguments stay exactly as specified, only the text meant for the user is in {language}."
+    )
+}
+
+/// Sampling options sent with every turn, a field left at `None` is left to the host's default
+#[derive(Serialize, Debug, Clone, Default)]
 pub struct ChatOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub top_k: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub repeat_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub seed: Option<u32>,
 }
 
+impl ChatOptions {
+    /// The names `/set` takes
+    pub const FIELDS: [&str; 5] = ["temperature", "top_p", "top_k", "repeat_penalty", "seed"];
+
+    /// Change one field from `/set`, `none` hands it back to the host's default
+    pub fn set(&mut self, field: &str, value: &str) -> Result<(), String> {
+        fn parsed<T>(
+            value: &str,
+            parse: fn(&str) -> Result<T, String>,
+        ) -> Result<Option<T>, String> {
+            match value {
+                "none" => Ok(None),
+                _ => parse(value).map(Some),
+            }
+        }
+
+        match field {
+            "temperature" => self.temperature = parsed(value, parse_non_negative)?,
+            "top_p" => self.top_p = parsed(value, parse_probability)?,
+            "top_k" => self.top_k = parsed(value, parse_count)?,
+            "repeat_penalty" => self.repeat_penalty = parsed(value, parse_non_negative)?,
+            "seed" => self.seed = parsed(value, parse_count)?,
+            _ => return Err(format!("no option named {field}")),
+        }
+        Ok(())
+    }
+}
+
+impl Display for ChatOptions {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        fn show<T: Display>(value: Option<T>) -> String {
+            value.map_or_else(|| "default".to_string(), |v| v.to_string())
+        }
+        write!(
+            f,
+            "temperature {}, top_p {}, top_k {}, repeat_penalty {}, seed {}",
+            show(self.temperature),
+            show(self.top_p),
+            show(self.top_k),
+            show(self.repeat_penalty),
+            show(self.seed)
+        )
+    }
+}
+
+/// Temperature or repeat penalty, anything from 0 up
+pub fn parse_non_negative(value: &str) -> Result<f32, String> {
+    match value.parse::<f32>() {
+        Ok(v) if v.is_finite() && v >= 0.0 => Ok(v),
+        Ok(_) => Err(format!("{value} is out of range, it has to be 0 or more")),
+        Err(_) => Err(format!("{value} is not a number")),
+    }
+}
+
+/// Top p, a share of the probability mass from 0 to 1
+pub fn parse_probability(value: &str) -> Result<f32, String> {
+    match value.parse::<f32>() {
+        Ok(v) if (0.0..=1.0).contains(&v) => Ok(v),
+        Ok(_) => Err(format!("{value} is out of range, it has to be from 0 to 1")),
+        Err(_) => Err(format!("{value} is not a number")),
+    }
+}
+
+/// Top k or a seed, a whole number from 0 up
+pub fn parse_count(value: &str) -> Result<u32, String> {
+    value
+        .parse::<u32>()
+        .map_err(|_| format!("{value} is not a whole number from 0 up"))
+}
+
 #[derive(Serialize, Debug)]
 pub struct ChatRequest<'a> {
-    pub model: &'a str,
+    pub model: Cow<'a, str>,
     pub messages: Vec<Prompt<'a>>,
     pub stream: bool,
-    pub format: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<&'a str>,
     pub stop: Vec<&'a str>,
     pub options: Option<ChatOptions>,
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub enum MessageRoles {
-    System = 0,
+    System,
     Tool,
     User,
     Assistant,
-    Other,
+    /// A failed request, shown in the chat pane but never sent to the model or saved
+    Error,
+    /// A role we don't know, kept as it was given so it can be shown and saved back unchanged
+    Other(String),
 }
 
-impl Display for MessageRoles {
-    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
-        let role: &str = match self {
+impl MessageRoles {
+    pub fn as_str(&self) -> &str {
+        match self {
             MessageRoles::System => "system",
             MessageRoles::Tool => "tool",
             MessageRoles::User => "user",
             MessageRoles::Assistant => "assistant",
-            //HACK: Handle this cleanly, if the model hallucinates a role we crash :^)
-            MessageRoles::Other => todo!(),
-        };
+            MessageRoles::Error => "error",
+            MessageRoles::Other(role) => role,
+        }
+    }
+}
 
-        write!(f, "{}", role)
+impl std::str::FromStr for MessageRoles {
+    type Err = std::convert::Infallible;
+
+    /// Never fails, anything unrecognized becomes `Other`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "system" => MessageRoles::System,
+            "tool" => MessageRoles::Tool,
+            "user" => MessageRoles::User,
+            "assistant" => MessageRoles::Assistant,
+            "error" => MessageRoles::Error,
+            other => MessageRoles::Other(other.to_string()),
+        })
     }
 }
 
+impl Display for MessageRoles {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for MessageRoles {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for MessageRoles {
+    fn deserialize<D: DeDeserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let role = String::deserialize(deserializer)?;
+        Ok(role.parse().unwrap_or_else(|never| match never {}))
+    }
+}
+
+/// Whether a message made it to the model, only tracked for the session, saved ones count as sent
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SendStatus {
+    /// The request it went out with hasn't been answered yet
+    Pending,
+    #[default]
+    Sent,
+    /// The request failed or was cancelled before the reply came back
+    Failed,
+}
+
 #[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct Message {
-    pub role: String,
-    #[serde(deserialize_with = "Message::de_content")]
+    /// Assigned by the UI when the message joins the conversation, 0 until then
+    #[serde(default)]
+    pub id: u64,
+    pub role: MessageRoles,
+    #[serde(
+        serialize_with = "Message::ser_content",
+        deserialize_with = "Message::de_content"
+    )]
     pub content: ActionPacket,
+    /// Marked by the user to find again later with /bookmarks
+    #[serde(default)]
+    pub bookmarked: bool,
+    /// The user message whose turn this belongs to, for tool calls, tool results and replies
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<u64>,
+    #[serde(skip)]
+    pub status: SendStatus,
 }
 
 impl Message {
     pub fn new(role: MessageRoles, action: Action, arguments: HashMap<String, String>) -> Self {
         Self {
-            role: role.to_string(),
+            id: 0,
+            role,
             content: ActionPacket::new(action, arguments),
+            bookmarked: false,
+            parent_id: None,
+            status: SendStatus::Sent,
         }
     }
 
@@ -94,7 +397,38 @@ impl Message {
         D: DeDeserializer<'de>,
     {
         let s = String::deserialize(deserializer)?;
-        serde_json::from_str(&s).map_err(de::Error::custom)
+        validate_envelope(&s).map_err(de::Error::custom)
+    }
+
+    /// Inverse of `de_content`, the packet goes out as a JSON string the same way it comes in
+    fn ser_content<S>(content: &ActionPacket, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&content.to_json())
+    }
+
+    /// What the chat pane shows for the message: the text of a chat reply, or the whole arguments
+    /// object when the text isn't under any key we know. Tool calls show the action as well.
+    pub fn render_text(&self) -> Cow<'_, str> {
+        match (&self.content.action, self.content.response()) {
+            (Action::Chat, Some(text)) => Cow::Borrowed(text),
+            (Action::Chat, None) => {
+                Cow::Owned(serde_json::to_string(&self.content.arguments).unwrap_or_default())
+            }
+            (Action::Tool(_), _) => Cow::Owned(self.content.to_string()),
+        }
+    }
+
+    /// Whether the role is one we know how to send back to the model
+    pub fn has_known_role(&self) -> bool {
+        matches!(
+            self.role,
+            MessageRoles::System
+                | MessageRoles::Tool
+                | MessageRoles::User
+                | MessageRoles::Assistant
+        )
     }
 }
 
@@ -110,9 +444,7 @@ impl Display for Message {
     }
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
-#[serde(rename_all = "snake_case")]
-#[serde(untagged)]
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
 pub enum AssistantTool {
     WikiSearch,
     WebSearch,
@@ -124,15 +456,49 @@ pub enum AssistantTool {
 
 impl Display for AssistantTool {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
-        let res = match self {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for AssistantTool {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for AssistantTool {
+    /// Goes by the same names as `Display`, a name we don't know is `InvalidTool` rather than an
+    /// error so one bad tool call doesn't throw away the rest of the reply
+    fn deserialize<D: DeDeserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = Cow::<str>::deserialize(deserializer)?;
+        Ok(AssistantTool::from_name(&name).unwrap_or(AssistantTool::InvalidTool))
+    }
+}
+
+impl AssistantTool {
+    /// Every tool the model can actually call
+    pub const ALL: [AssistantTool; 5] = [
+        AssistantTool::WikiSearch,
+        AssistantTool::WebSearch,
+        AssistantTool::GetDateTime,
+        AssistantTool::GetDirectoryTree,
+        AssistantTool::GetFileContents,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
             AssistantTool::WikiSearch => "wiki_search",
             AssistantTool::WebSearch => "web_search",
             AssistantTool::GetDateTime => "get_date_time",
             AssistantTool::GetDirectoryTree => "get_dir_tree",
             AssistantTool::GetFileContents => "get_file_contents",
             AssistantTool::InvalidTool => "invalid_tool",
-        };
-        write!(f, "{}", res)
+        }
+    }
+
+    /// Look a tool up by the name the model uses for it
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|t| t.as_str() == name)
     }
 }
 
@@ -146,13 +512,17 @@ pub enum Action {
 impl Display for Action {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
         match self {
-            Action::Chat => write!(f, "{}", "chat"),
+            Action::Chat => write!(f, "chat"),
             Action::Tool(tool_name) => write!(f, "{tool_name}"),
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, PartialEq)]
+/// Argument keys a chat reply's text is looked for under, in order. Rule 3 asks for `response`
+/// but small models regularly drift to one of the others.
+pub const RESPONSE_KEYS: [&str; 4] = ["response", "text", "message", "content"];
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub struct ActionPacket {
     pub action: Action,
     pub arguments: HashMap<String, String>,
@@ -162,6 +532,23 @@ impl ActionPacket {
     pub fn new(action: Action, arguments: HashMap<String, String>) -> Self {
         Self { action, arguments }
     }
+
+    /// Text of a chat reply, under whichever of `RESPONSE_KEYS` it was sent with
+    pub fn response(&self) -> Option<&str> {
+        RESPONSE_KEYS
+            .iter()
+            .find_map(|key| self.arguments.get(*key))
+            .map(String::as_str)
+    }
+
+    /// The packet in the `{"action":...,"arguments":{...}}` form `validate_envelope` reads back
+    pub fn to_json(&self) -> String {
+        serde_json::json!({
+            "action": self.action.to_string(),
+            "arguments": self.arguments,
+        })
+        .to_string()
+    }
 }
 
 impl Display for ActionPacket {
@@ -173,7 +560,186 @@ impl Display for ActionPacket {
     }
 }
 
+/// A conversation as saved to disk
+#[derive(Serialize, Deserialize)]
+pub struct Conversation {
+    /// Base system prompt the conversation was held under, if it was recorded
+    #[serde(default)]
+    pub system: Option<String>,
+    pub messages: Vec<Message>,
+    #[serde(default)]
+    pub totals: TokenTotals,
+}
+
+impl Conversation {
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let raw = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, raw)
+            .map_err(|e| anyhow::anyhow!("unable to write {}: {e}", path.display()))
+    }
+
+    /// The conversation as a Markdown transcript for reading and sharing, a heading per message.
+    /// Replies are prose, tool calls and their results go in fenced blocks.
+    pub fn to_markdown(&self, assistant_name: &str) -> String {
+        let mut out = String::from("# Conversation\n");
+        for message in &self.messages {
+            let name = match message.role {
+                MessageRoles::Assistant => assistant_name,
+                ref role => role.as_str(),
+            };
+            match (&message.role, &message.content.action) {
+                (MessageRoles::Tool, Action::Tool(tool)) => {
+                    let result = message
+                        .content
+                        .arguments
+                        .get("result")
+                        .map_or_else(|| message.render_text(), |r| Cow::Borrowed(r.as_str()));
+                    let _ = write!(out, "\n## {tool} result\n\n{}", fenced("", &result));
+                }
+                (_, Action::Tool(tool)) => {
+                    let args = serde_json::to_string_pretty(&message.content.arguments)
+                        .unwrap_or_default();
+                    let _ = write!(
+                        out,
+                        "\n## {name} called {tool}\n\n{}",
+                        fenced("json", &args)
+                    );
+                }
+                (_, Action::Chat) => {
+                    let _ = write!(out, "\n## {name}\n\n{}\n", message.render_text().trim_end());
+                }
+            }
+        }
+        out
+    }
+
+    /// Write `to_markdown` to `path`
+    pub fn export(&self, path: &Path, assistant_name: &str) -> anyhow::Result<()> {
+        std::fs::write(path, self.to_markdown(assistant_name))
+            .map_err(|e| anyhow::anyhow!("unable to write {}: {e}", path.display()))
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let raw = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("unable to read {}: {e}", path.display()))?;
+        let conversation: Self = serde_json::from_str(&raw)
+            .map_err(|e| anyhow::anyhow!("unable to parse {}: {e}", path.display()))?;
+
+        if let Some((idx, m)) = conversation
+            .messages
+            .iter()
+            .enumerate()
+            .find(|(_, m)| !m.has_known_role())
+        {
+            anyhow::bail!("message {} has an unknown role `{}`", idx + 1, m.role);
+        }
+
+        Ok(conversation)
+    }
+}
+
+/// Check a raw assistant reply against the `{"action":...,"arguments":{...}}` contract, describing
+/// the first problem found so it can be quoted back to the model
+pub fn validate_envelope(content: &str) -> Result<ActionPacket, String> {
+    let value: serde_json::Value =
+        serde_json::from_str(content.trim()).map_err(|e| format!("not valid JSON ({e})"))?;
+    let object = value
+        .as_object()
+        .ok_or("the reply must be a single JSON object")?;
+
+    let action = match object.get("action") {
+        Some(serde_json::Value::String(action)) => action,
+        Some(_) => return Err("`action` must be a string".to_string()),
+        None => return Err("missing `action`".to_string()),
+    };
+    let action = match action.as_str() {
+        "chat" => Action::Chat,
+        name => match AssistantTool::from_name(name) {
+            Some(tool) => Action::Tool(tool),
+            None => return Err(format!("unknown action `{name}`")),
+        },
+    };
+
+    let arguments = match object.get("arguments") {
+        Some(serde_json::Value::Object(args)) => args
+            .iter()
+            .map(|(k, v)| match v {
+                serde_json::Value::String(v) => Ok((k.clone(), v.clone())),
+                _ => Err(format!("argument `{k}` must be a string")),
+            })
+            .collect::<Result<HashMap<_, _>, _>>()?,
+        Some(_) => return Err("`arguments` must be an object".to_string()),
+        None => return Err("missing `arguments`".to_string()),
+    };
+
+    if action == Action::Chat && !RESPONSE_KEYS.iter().any(|k| arguments.contains_key(*k)) {
+        return Err("chat replies need a `response` argument".to_string());
+    }
+
+    Ok(ActionPacket::new(action, arguments))
+}
+
+/// The first balanced `{...}` in a reply that wraps its JSON in prose, braces inside strings don't
+/// count. `None` when there is no complete object.
+pub fn extract_json(content: &str) -> Option<&str> {
+    let start = content.find('{')?;
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (i, c) in content[start..].char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(&content[start..start + i + 1]);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// A packet from a reply that isn't one by itself but has a valid one inside its prose
+pub fn salvage_envelope(content: &str) -> Option<ActionPacket> {
+    validate_envelope(extract_json(content)?).ok()
+}
+
+/// Sent once per turn when a reply in agent mode is plain prose instead of JSON. Small models
+/// tend to drift out of the format altogether, a blunt instruction brings most of them back.
+pub const REINFORCE_PROMPT: &str = r#"You MUST reply with a single JSON object and nothing else, no prose before or after it. To answer the user, reply {"action":"chat","arguments":{"response":"<your answer>"}}."#;
+
+/// Whether a reply isn't JSON at all, as opposed to JSON that doesn't follow the contract
+pub fn is_prose(content: &str) -> bool {
+    serde_json::from_str::<serde_json::Value>(content.trim()).is_err()
+}
+
+/// Terse reminder of the reply format, sent back to the model after it broke the contract
+pub fn correction_prompt(problem: &str) -> String {
+    let actions = std::iter::once("chat".to_string())
+        .chain(AssistantTool::ALL.iter().map(ToString::to_string))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    format!(
+        r#"Your last reply was rejected: {problem}. Reply with exactly one JSON object, either {{"action":"chat","arguments":{{"response":"..."}}}} or {{"action":"<tool>","arguments":{{...}}}}, all argument values are strings. Valid actions: {actions}."#
+    )
+}
+
 #[derive(Deserialize)]
+#[allow(dead_code)]
 pub struct ChatResponse {
     pub model: String,
     pub created_at: String,
@@ -187,6 +753,512 @@ pub struct ChatResponse {
     pub prompt_eval_duration: Option<u64>,
 }
 
+/// Raw record of one round trip with the backend, kept around so it can be dumped for bug reports
+pub struct Exchange {
+    pub started_at: chrono::DateTime<chrono::Local>,
+    pub elapsed: Duration,
+    pub method: String,
+    pub url: String,
+    pub request_headers: Vec<(String, String)>,
+    pub request_body: Vec<u8>,
+    pub status: Option<String>,
+    pub response_headers: Vec<(String, String)>,
+    /// For streamed replies this is every chunk, concatenated in the order they arrived
+    pub response_body: Vec<u8>,
+    pub error: Option<String>,
+}
+
+impl Exchange {
+    pub fn new(request: &reqwest::Request, request_body: Vec<u8>) -> Self {
+        Self {
+            started_at: chrono::Local::now(),
+            elapsed: Duration::ZERO,
+            method: request.method().to_string(),
+            url: request.url().to_string(),
+            request_headers: header_pairs(request.headers()),
+            request_body,
+            status: None,
+            response_headers: Vec::new(),
+            response_body: Vec::new(),
+            error: None,
+        }
+    }
+
+    pub fn record_response(&mut self, response: &reqwest::Response) {
+        self.status = Some(response.status().to_string());
+        self.response_headers = header_pairs(response.headers());
+    }
+
+    /// Plain text report of the exchange, with every occurrence of `secrets` blanked out
+    pub fn render(&self, secrets: &[&str]) -> String {
+        let mut out = String::new();
+
+        // writing to a String can't fail
+        let _ = writeln!(out, "=== oxiai request dump ===");
+        let _ = writeln!(out, "started: {}", self.started_at.to_rfc3339());
+        let _ = writeln!(out, "elapsed: {} ms", self.elapsed.as_millis());
+
+        let _ = writeln!(out, "\n--- request ---");
+        let _ = writeln!(out, "{} {}", self.method, self.url);
+        for (name, value) in &self.request_headers {
+            let _ = writeln!(out, "{name}: {value}");
+        }
+        let _ = writeln!(out, "\n{}", String::from_utf8_lossy(&self.request_body));
+
+        let _ = writeln!(out, "\n--- response ---");
+        match &self.status {
+            Some(status) => {
+                let _ = writeln!(out, "status: {status}");
+            }
+            None => {
+                let _ = writeln!(out, "status: <no response>");
+            }
+        }
+        if let Some(error) = &self.error {
+            let _ = writeln!(out, "error: {error}");
+        }
+        for (name, value) in &self.response_headers {
+            let _ = writeln!(out, "{name}: {value}");
+        }
+        let _ = writeln!(out, "\n{}", String::from_utf8_lossy(&self.response_body));
+
+        redact(out, secrets)
+    }
+}
+
+fn header_pairs(headers: &reqwest::header::HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            (
+                name.to_string(),
+                String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            )
+        })
+        .collect()
+}
+
+pub fn redact(mut text: String, secrets: &[&str]) -> String {
+    for secret in secrets.iter().filter(|s| !s.is_empty()) {
+        text = text.replace(secret, "[REDACTED]");
+    }
+    text
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TagsResponse {
+    pub models: Vec<ModelTag>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ModelTag {
+    pub name: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct VersionResponse {
+    version: String,
+}
+
+/// Ollama version reported by `host`, doubles as a health check
+pub async fn fetch_version(
+    client: &reqwest::Client,
+    host: &str,
+    timeout: Duration,
+) -> Result<String, reqwest::Error> {
+    let resp = client
+        .get(endpoint(host, "api/version"))
+        .timeout(timeout)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<VersionResponse>()
+        .await?;
+    Ok(resp.version)
+}
+
+/// What `/api/show` says about a model
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ShowResponse {
+    /// Modelfile `PARAMETER` lines, one `name value` pair per line
+    pub parameters: String,
+    /// Prompt template the model's messages are rendered with
+    pub template: String,
+    pub details: ShowDetails,
+    /// GGUF metadata, keys are prefixed with the architecture, e.g. `llama.context_length`
+    pub model_info: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ShowDetails {
+    pub format: String,
+    pub family: String,
+    pub parameter_size: String,
+    pub quantization_level: String,
+}
+
+impl ShowResponse {
+    /// Longest context the model was trained for, which can be more than it is run with
+    pub fn context_length(&self) -> Option<u64> {
+        self.model_info
+            .iter()
+            .find(|(key, _)| key.ends_with(".context_length"))
+            .and_then(|(_, value)| value.as_u64())
+    }
+
+    /// Everything worth knowing about `model`, laid out for the /show popup
+    pub fn render(&self, model: &str) -> String {
+        let or_unknown = |s: &str| match s {
+            "" => "unknown".to_string(),
+            s => s.to_string(),
+        };
+
+        let mut out = String::new();
+        let _ = writeln!(out, "model: {model}");
+        let _ = writeln!(out, "family: {}", or_unknown(&self.details.family));
+        let _ = writeln!(
+            out,
+            "parameters: {}",
+            or_unknown(&self.details.parameter_size)
+        );
+        let _ = writeln!(
+            out,
+            "quantization: {}",
+            or_unknown(&self.details.quantization_level)
+        );
+        let _ = writeln!(out, "format: {}", or_unknown(&self.details.format));
+        let _ = writeln!(
+            out,
+            "context length: {}",
+            self.context_length()
+                .map_or("unknown".to_string(), |n| n.to_string())
+        );
+        let _ = writeln!(
+            out,
+            "num_ctx: {}",
+            self.num_ctx()
+                .map_or("not set".to_string(), |n| n.to_string())
+        );
+        let _ = writeln!(out, "\n--- parameters ---\n{}", self.parameters.trim_end());
+        let _ = writeln!(out, "\n--- template ---\n{}", self.template.trim_end());
+        out
+    }
+
+    /// `num_ctx` as set in the model's parameters, if it sets one
+    pub fn num_ctx(&self) -> Option<u64> {
+        self.parameters.lines().find_map(|line| {
+            let (name, value) = line.trim().split_once(char::is_whitespace)?;
+            (name == "num_ctx").then(|| value.trim().parse().ok())?
+        })
+    }
+}
+
+pub async fn fetch_show(
+    client: &reqwest::Client,
+    host: &str,
+    model: &str,
+    timeout: Duration,
+) -> Result<ShowResponse, reqwest::Error> {
+    client
+        .post(endpoint(host, "api/show"))
+        .json(&serde_json::json!({ "model": model }))
+        .timeout(timeout)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<ShowResponse>()
+        .await
+}
+
+/// Names of every model the host has pulled
+pub async fn fetch_tags(
+    client: &reqwest::Client,
+    host: &str,
+    timeout: Duration,
+) -> Result<Vec<String>, reqwest::Error> {
+    let resp = client
+        .get(endpoint(host, "api/tags"))
+        .timeout(timeout)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<TagsResponse>()
+        .await?;
+    Ok(resp.models.into_iter().map(|m| m.name).collect())
+}
+
+/// Whether `model` is in `available`, a model given without a tag means `:latest` like ollama does
+pub fn has_model(available: &[String], model: &str) -> bool {
+    let wanted = match model.contains(':') {
+        true => Cow::Borrowed(model),
+        false => Cow::Owned(format!("{model}:latest")),
+    };
+    available.iter().any(|m| *m == wanted)
+}
+
+/// Join an api path onto a host base url, tolerating a trailing slash on the host
+pub fn endpoint(host: &str, path: &str) -> String {
+    format!(
+        "{}/{}",
+        host.trim_end_matches('/'),
+        path.trim_start_matches('/')
+    )
+}
+
+/// A markdown heading in a reply: where it starts in the text and what it says
+pub struct Heading {
+    /// Byte offset of the line the heading is on
+    pub offset: usize,
+    pub level: usize,
+    pub title: String,
+}
+
+/// The `#` to `###` headings of `text`, in order. Lines inside code fences are skipped, a `#`
+/// there is a comment, not a section.
+pub fn outline(text: &str) -> Vec<Heading> {
+    let mut headings = Vec::new();
+    let mut in_fence = false;
+    let mut offset = 0;
+
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim();
+        if trimmed.starts_with("```") {
+            in_fence = !in_fence;
+        } else if !in_fence {
+            let level = trimmed.chars().take_while(|c| *c == '#').count();
+            let title = trimmed[level..].trim();
+            if (1..=3).contains(&level) && trimmed[level..].starts_with(' ') && !title.is_empty() {
+                headings.push(Heading {
+                    offset,
+                    level,
+                    title: title.to_string(),
+                });
+            }
+        }
+        offset += line.len();
+    }
+    headings
+}
+
+/// Slash commands typed into the input line
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Dump(PathBuf),
+    /// Write the conversation, and the tokens it has cost, to a file `/import merge` can read
+    Save(PathBuf),
+    /// Write the conversation as a Markdown transcript, for reading rather than loading
+    Export(PathBuf),
+    /// Switch to another backend host, or show the current one when no url is given
+    Host(Option<String>),
+    /// Show how much of the token budget is left, or start it over
+    Budget {
+        reset: bool,
+    },
+    /// Show exactly what the next turn would send to the model
+    Context,
+    /// Put the working directory's layout in the system context, fresh, or take it out again
+    Tree {
+        off: bool,
+    },
+    /// Show how the caches are doing, or empty them
+    Cache {
+        clear: bool,
+    },
+    /// Take back the last turn, the user's message and everything that answered it
+    Undo,
+    /// Bring back the last turn taken back with /undo
+    Redo,
+    /// List the headings of a long answer, or jump to the nth one
+    Outline(Option<usize>),
+    /// List the bookmarked messages, or jump to the nth one
+    Bookmarks(Option<usize>),
+    /// Copy the nth message's text to the clipboard, the last reply when no number is given
+    Copy(Option<usize>),
+    /// Show what the host says about a model, the active one if none is given
+    Show(Option<String>),
+    /// Switch between plain chat and the JSON tool protocol, or show the current mode
+    Mode(Option<Mode>),
+    /// Append a saved conversation to the current one
+    ImportMerge(PathBuf),
+    /// Replace the current conversation with a saved one
+    Load(PathBuf),
+    /// Set the system note for the rest of this conversation, clear it with `None`
+    Note(Option<String>),
+    /// Set a system note for the next turn only, clear it with `None`
+    Nudge(Option<String>),
+    /// Empty the conversation
+    Clear,
+    /// Switch to another model on the same host, or show the current one when none is given
+    Model(Option<String>),
+    /// Ask again for the last user turn, dropping whatever answered it
+    Retry,
+    /// Replace the system prompt for this session, back to the built-in one with `None`
+    System(Option<String>),
+    /// Drop the oldest turns until the next prompt fits the context window again
+    Prune,
+    /// List the tools and whether each is on, or turn one on (`true`) or off
+    Tools(Option<(bool, AssistantTool)>),
+    /// Change a sampling option, field then value, or show them all when none is given
+    Set(Option<(String, String)>),
+    Quit,
+    /// Something that looked like a command but wasn't one we understand, with a message for the user
+    Invalid(String),
+}
+
+/// Returns `None` for anything that isn't a slash command so it can be sent as a normal chat message
+pub fn parse_command(line: &str) -> Option<Command> {
+    let line = line.trim();
+    let rest = line.strip_prefix('/')?;
+    let (name, args) = match rest.split_once(char::is_whitespace) {
+        Some((name, args)) => (name, args.trim()),
+        None => (rest, ""),
+    };
+
+    let command = match name {
+        "dump" if args.is_empty() => Command::Invalid("usage: /dump <path>".to_string()),
+        "dump" => Command::Dump(PathBuf::from(args)),
+        "save" if args.is_empty() => Command::Invalid("usage: /save <path>".to_string()),
+        "save" => Command::Save(PathBuf::from(args)),
+        "export" if args.is_empty() => Command::Invalid("usage: /export <path.md>".to_string()),
+        "export" => Command::Export(PathBuf::from(args)),
+        "load" if args.is_empty() => Command::Invalid("usage: /load <path>".to_string()),
+        "load" => Command::Load(PathBuf::from(args)),
+        "host" if args.is_empty() => Command::Host(None),
+        "host" => match reqwest::Url::parse(args) {
+            Ok(_) => Command::Host(Some(args.trim_end_matches('/').to_string())),
+            Err(e) => Command::Invalid(format!("invalid host url {args}: {e}")),
+        },
+        "context" if args.is_empty() => Command::Context,
+        "context" => Command::Invalid("usage: /context".to_string()),
+        "tree" => match args {
+            "" => Command::Tree { off: false },
+            "off" => Command::Tree { off: true },
+            _ => Command::Invalid("usage: /tree [off]".to_string()),
+        },
+        "import" => match args.strip_prefix("merge") {
+            Some(path) if path.starts_with(char::is_whitespace) => {
+                Command::ImportMerge(PathBuf::from(path.trim()))
+            }
+            _ => Command::Invalid("usage: /import merge <file>".to_string()),
+        },
+        "cache" => match args {
+            "" | "stats" => Command::Cache { clear: false },
+            "clear" => Command::Cache { clear: true },
+            _ => Command::Invalid("usage: /cache [stats|clear]".to_string()),
+        },
+        "undo" if args.is_empty() => Command::Undo,
+        "redo" if args.is_empty() => Command::Redo,
+        "undo" | "redo" => Command::Invalid(format!("usage: /{name}")),
+        "outline" if args.is_empty() => Command::Outline(None),
+        "outline" => match args.parse::<usize>() {
+            Ok(n) if n > 0 => Command::Outline(Some(n)),
+            _ => Command::Invalid("usage: /outline [number]".to_string()),
+        },
+        "bookmarks" if args.is_empty() => Command::Bookmarks(None),
+        "bookmarks" => match args.parse::<usize>() {
+            Ok(n) if n > 0 => Command::Bookmarks(Some(n)),
+            _ => Command::Invalid("usage: /bookmarks [number]".to_string()),
+        },
+        "copy" if args.is_empty() => Command::Copy(None),
+        "copy" => match args.parse::<usize>() {
+            Ok(n) if n > 0 => Command::Copy(Some(n)),
+            _ => Command::Invalid("usage: /copy [number]".to_string()),
+        },
+        "show" => Command::Show((!args.is_empty()).then(|| args.to_string())),
+        "mode" => match args {
+            "" => Command::Mode(None),
+            "chat" => Command::Mode(Some(Mode::Chat)),
+            "agent" => Command::Mode(Some(Mode::Agent)),
+            _ => Command::Invalid("usage: /mode [chat|agent]".to_string()),
+        },
+        "note" => Command::Note((!args.is_empty()).then(|| args.to_string())),
+        "nudge" => Command::Nudge((!args.is_empty()).then(|| args.to_string())),
+        "tools" if args.is_empty() => Command::Tools(None),
+        "tools" => {
+            let toggle = match args.split_once(char::is_whitespace) {
+                Some(("enable", name)) => Some((true, name.trim())),
+                Some(("disable", name)) => Some((false, name.trim())),
+                _ => None,
+            };
+            match toggle {
+                Some((on, name)) => match AssistantTool::from_name(name) {
+                    Some(tool) => Command::Tools(Some((on, tool))),
+                    None => Command::Invalid(format!("no tool named {name}")),
+                },
+                None => Command::Invalid("usage: /tools [enable|disable <name>]".to_string()),
+            }
+        }
+        "clear" if args.is_empty() => Command::Clear,
+        "prune" if args.is_empty() => Command::Prune,
+        "retry" if args.is_empty() => Command::Retry,
+        "quit" if args.is_empty() => Command::Quit,
+        "clear" | "retry" | "quit" | "prune" => Command::Invalid(format!("usage: /{name}")),
+        "model" => Command::Model((!args.is_empty()).then(|| args.to_string())),
+        "system" => Command::System((!args.is_empty()).then(|| args.to_string())),
+        "set" if args.is_empty() => Command::Set(None),
+        "set" => match args.split_once(char::is_whitespace) {
+            Some((field, value))
+                if ChatOptions::FIELDS.contains(&field.replace('-', "_").as_str()) =>
+            {
+                Command::Set(Some((field.replace('-', "_"), value.trim().to_string())))
+            }
+            _ => Command::Invalid(format!(
+                "usage: /set <{}> <value|none>",
+                ChatOptions::FIELDS.join("|")
+            )),
+        },
+        "budget" => match args {
+            "" => Command::Budget { reset: false },
+            "reset" => Command::Budget { reset: true },
+            _ => Command::Invalid("usage: /budget [reset]".to_string()),
+        },
+        _ => Command::Invalid(format!("unknown command: /{name}")),
+    };
+    Some(command)
+}
+
+/// Check a line as it's being typed without running anything: `None` if it isn't a slash command,
+/// otherwise whether it would parse, with the reason when it wouldn't
+pub fn validate_command(line: &str) -> Option<Result<(), String>> {
+    parse_command(line).map(|command| match command {
+        Command::Invalid(reason) => Err(reason),
+        _ => Ok(()),
+    })
+}
+
+/// Splits a chunked NDJSON body back into whole lines.
+///
+/// Chunk boundaries don't line up with newlines, so any trailing partial line is held on to until
+/// the rest of it arrives rather than being parsed (and dropped) early.
+#[derive(Default)]
+pub struct LineSplitter {
+    buf: Vec<u8>,
+}
+
+impl LineSplitter {
+    /// Feed a chunk in, get back every line it completed
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<Vec<u8>> {
+        self.buf.extend_from_slice(chunk);
+
+        let mut lines = Vec::new();
+        while let Some(pos) = self.buf.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buf.drain(..=pos).take(pos).collect();
+            if !line.is_empty() {
+                lines.push(line);
+            }
+        }
+        lines
+    }
+
+    /// Whatever is left once the body has ended, if the last line had no trailing newline
+    pub fn finish(&mut self) -> Option<Vec<u8>> {
+        let rest = std::mem::take(&mut self.buf);
+        (!rest.is_empty()).then_some(rest)
+    }
+}
+
 #[macro_export]
 macro_rules! args_builder {
     ( $( $key:expr => $value:expr ),* $(,)? ) => {{
@@ -197,3 +1269,465 @@ macro_rules! args_builder {
         map
     }};
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_envelope_accepts_chat_and_tool_packets() {
+        let packet =
+            validate_envelope(r#" {"action":"chat","arguments":{"response":"hi"}} "#).unwrap();
+        assert_eq!(packet.action, Action::Chat);
+        assert_eq!(packet.response(), Some("hi"));
+
+        let packet =
+            validate_envelope(r#"{"action":"wiki_search","arguments":{"query":"Black Bear"}}"#)
+                .unwrap();
+        assert_eq!(packet.action, Action::Tool(AssistantTool::WikiSearch));
+        assert_eq!(packet.arguments["query"], "Black Bear");
+    }
+
+    #[test]
+    fn validate_envelope_takes_any_response_key() {
+        for key in RESPONSE_KEYS {
+            let reply = format!(r#"{{"action":"chat","arguments":{{"{key}":"hi"}}}}"#);
+            assert!(validate_envelope(&reply).is_ok(), "{key}");
+        }
+    }
+
+    #[test]
+    fn validate_envelope_names_the_problem() {
+        let cases = [
+            ("hello there", "not valid JSON"),
+            ("[1, 2]", "the reply must be a single JSON object"),
+            (r#"{"arguments":{}}"#, "missing `action`"),
+            (
+                r#"{"action":1,"arguments":{}}"#,
+                "`action` must be a string",
+            ),
+            (r#"{"action":"fly","arguments":{}}"#, "unknown action `fly`"),
+            (r#"{"action":"chat"}"#, "missing `arguments`"),
+            (
+                r#"{"action":"chat","arguments":[]}"#,
+                "`arguments` must be an object",
+            ),
+            (
+                r#"{"action":"chat","arguments":{"response":3}}"#,
+                "argument `response` must be a string",
+            ),
+            (
+                r#"{"action":"chat","arguments":{"answer":"hi"}}"#,
+                "chat replies need a `response` argument",
+            ),
+        ];
+        for (reply, problem) in cases {
+            let err = validate_envelope(reply).unwrap_err();
+            assert!(err.starts_with(problem), "{reply}: {err}");
+        }
+    }
+
+    #[test]
+    fn extract_json_finds_the_object_in_prose() {
+        let reply =
+            r#"Sure! Here you go: {"action":"chat","arguments":{"response":"hi"}} Hope it helps"#;
+        assert_eq!(
+            extract_json(reply),
+            Some(r#"{"action":"chat","arguments":{"response":"hi"}}"#)
+        );
+    }
+
+    #[test]
+    fn extract_json_ignores_braces_in_strings() {
+        let reply = r#"x {"a":"} not the end \" {","b":{"c":"}"}} y"#;
+        assert_eq!(
+            extract_json(reply),
+            Some(r#"{"a":"} not the end \" {","b":{"c":"}"}}"#)
+        );
+    }
+
+    #[test]
+    fn extract_json_needs_a_complete_object() {
+        assert_eq!(extract_json("no json here"), None);
+        assert_eq!(extract_json(r#"cut off {"action":"chat""#), None);
+    }
+
+    #[test]
+    fn salvage_envelope_only_takes_valid_packets() {
+        let packet = salvage_envelope(r#"Answer: {"action":"chat","arguments":{"response":"hi"}}"#);
+        assert_eq!(packet.unwrap().response(), Some("hi"));
+        assert!(salvage_envelope(r#"Answer: {"action":"fly","arguments":{}}"#).is_none());
+    }
+
+    #[test]
+    fn outline_lists_the_top_three_heading_levels() {
+        let text = "# One\nintro\n## Two\n### Three\n#### Four\n";
+        let headings = outline(text);
+        let found = headings
+            .iter()
+            .map(|h| (h.level, h.title.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(found, [(1, "One"), (2, "Two"), (3, "Three")]);
+        assert_eq!(&text[headings[1].offset..][..6], "## Two");
+    }
+
+    #[test]
+    fn outline_skips_fenced_code_and_non_headings() {
+        let text = "```sh\n# a comment\n```\n#hashtag\n# \n## Real\n";
+        let headings = outline(text);
+        assert_eq!(headings.len(), 1);
+        assert_eq!(headings[0].title, "Real");
+        assert_eq!(headings[0].offset, text.find("## Real").unwrap());
+    }
+
+    #[test]
+    fn unknown_roles_round_trip_unchanged() {
+        let role: MessageRoles = serde_json::from_str(r#""narrator""#).unwrap();
+        assert_eq!(role, MessageRoles::Other("narrator".to_string()));
+        assert_eq!(serde_json::to_string(&role).unwrap(), r#""narrator""#);
+
+        for role in [MessageRoles::System, MessageRoles::Tool, MessageRoles::User] {
+            let json = serde_json::to_string(&role).unwrap();
+            assert_eq!(serde_json::from_str::<MessageRoles>(&json).unwrap(), role);
+        }
+    }
+
+    #[test]
+    fn tools_serialize_by_their_tool_name() {
+        for tool in AssistantTool::ALL {
+            let json = serde_json::to_string(&tool).unwrap();
+            assert_eq!(json, format!(r#""{}""#, tool.as_str()));
+            assert_eq!(serde_json::from_str::<AssistantTool>(&json).unwrap(), tool);
+        }
+    }
+
+    #[test]
+    fn unknown_tool_names_become_invalid_tool() {
+        let tool: AssistantTool = serde_json::from_str(r#""launch_rockets""#).unwrap();
+        assert_eq!(tool, AssistantTool::InvalidTool);
+    }
+
+    #[test]
+    fn saved_messages_round_trip() {
+        let message = Message::new(
+            MessageRoles::Other("narrator".to_string()),
+            Action::Tool(AssistantTool::GetFileContents),
+            HashMap::from([("path".to_string(), "src/main.rs".to_string())]),
+        );
+        let json = serde_json::to_string(&message).unwrap();
+        let back: Message = serde_json::from_str(&json).unwrap();
+        assert!(back == message, "{json}");
+    }
+
+    #[test]
+    fn line_splitter_holds_partial_lines() {
+        let mut lines = LineSplitter::default();
+        assert!(lines.push(b"{\"a\":").is_empty());
+        assert_eq!(lines.push(b"1}\n{\"b\""), [b"{\"a\":1}".to_vec()]);
+        assert_eq!(lines.push(b":2}\n\n"), [b"{\"b\":2}".to_vec()]);
+        assert_eq!(lines.finish(), None);
+    }
+
+    #[test]
+    fn line_splitter_finishes_an_unterminated_line() {
+        let mut lines = LineSplitter::default();
+        assert!(lines.push(b"{\"done\":true}").is_empty());
+        assert_eq!(lines.finish(), Some(b"{\"done\":true}".to_vec()));
+    }
+
+    #[test]
+    fn dump_needs_a_path() {
+        assert_eq!(
+            parse_command("/dump out.json"),
+            Some(Command::Dump(PathBuf::from("out.json")))
+        );
+        assert!(matches!(parse_command("/dump"), Some(Command::Invalid(_))));
+        assert_eq!(parse_command("not a command"), None);
+    }
+
+    #[test]
+    fn redact_hides_every_secret() {
+        let text = "Authorization: Bearer sk-123, again sk-123".to_string();
+        assert_eq!(
+            redact(text, &["sk-123", ""]),
+            "Authorization: Bearer [REDACTED], again [REDACTED]"
+        );
+    }
+
+    #[test]
+    fn host_takes_a_url() {
+        assert_eq!(parse_command("/host"), Some(Command::Host(None)));
+        assert_eq!(
+            parse_command("/host http://gpu-box:11434/"),
+            Some(Command::Host(Some("http://gpu-box:11434".to_string())))
+        );
+        assert!(matches!(
+            parse_command("/host not a url"),
+            Some(Command::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn endpoint_joins_without_doubled_slashes() {
+        assert_eq!(endpoint("http://h:1/", "/api/chat"), "http://h:1/api/chat");
+        assert_eq!(endpoint("http://h:1", "api/chat"), "http://h:1/api/chat");
+    }
+
+    #[test]
+    fn budget_takes_only_reset() {
+        assert_eq!(
+            parse_command("/budget"),
+            Some(Command::Budget { reset: false })
+        );
+        assert_eq!(
+            parse_command("/budget reset"),
+            Some(Command::Budget { reset: true })
+        );
+        assert!(matches!(
+            parse_command("/budget 100"),
+            Some(Command::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn token_totals_add_up_usage() {
+        let mut totals = TokenTotals::default();
+        totals.add(Usage {
+            completion_tokens: 20,
+            prompt_tokens: Some(100),
+            ..Usage::default()
+        });
+        // a cached prompt isn't reported, only the generated tokens count then
+        totals.add(Usage {
+            completion_tokens: 5,
+            ..Usage::default()
+        });
+        assert_eq!(totals.prompt_tokens, 100);
+        assert_eq!(totals.completion_tokens, 25);
+        assert_eq!(totals.total(), 125);
+    }
+
+    #[test]
+    fn context_takes_no_arguments() {
+        assert_eq!(parse_command("/context"), Some(Command::Context));
+        assert!(matches!(
+            parse_command("/context all"),
+            Some(Command::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn system_layers_go_broadest_first() {
+        let layers = SystemLayers {
+            base: "rules".to_string(),
+            conversation: Some("be brief".to_string()),
+            turn: Some("use a table".to_string()),
+            ..SystemLayers::default()
+        };
+        let prompts = layers.prompts().collect::<Vec<_>>();
+        assert!(prompts.iter().all(|p| p.role == "system"));
+        let contents = prompts
+            .iter()
+            .map(|p| p.content.as_ref())
+            .collect::<Vec<_>>();
+        assert_eq!(contents, ["rules", "be brief", "use a table"]);
+    }
+
+    #[test]
+    fn system_layers_put_the_language_last() {
+        let layers = SystemLayers {
+            base: "rules".to_string(),
+            project: Some("src/".to_string()),
+            language: Some("Norwegian".to_string()),
+            ..SystemLayers::default()
+        };
+        let prompts = layers.prompts().collect::<Vec<_>>();
+        assert_eq!(prompts.len(), 3);
+        assert!(prompts[1].content.ends_with("src/"));
+        assert!(prompts[2].content.contains("Norwegian"));
+    }
+
+    #[test]
+    fn import_needs_merge_and_a_file() {
+        assert_eq!(
+            parse_command("/import merge old.json"),
+            Some(Command::ImportMerge(PathBuf::from("old.json")))
+        );
+        for line in [
+            "/import",
+            "/import old.json",
+            "/import merge",
+            "/import mergeold.json",
+        ] {
+            assert!(
+                matches!(parse_command(line), Some(Command::Invalid(_))),
+                "{line}"
+            );
+        }
+    }
+
+    #[test]
+    fn render_text_shows_the_reply_under_any_key() {
+        let reply = |key: &str| {
+            Message::new(
+                MessageRoles::Assistant,
+                Action::Chat,
+                HashMap::from([(key.to_string(), "hi".to_string())]),
+            )
+        };
+        assert_eq!(reply("response").render_text(), "hi");
+        assert_eq!(reply("content").render_text(), "hi");
+        // a key we don't know shows the whole arguments object rather than nothing
+        assert_eq!(reply("answer").render_text(), r#"{"answer":"hi"}"#);
+    }
+
+    #[test]
+    fn mode_takes_chat_or_agent() {
+        assert_eq!(parse_command("/mode"), Some(Command::Mode(None)));
+        assert_eq!(
+            parse_command("/mode chat"),
+            Some(Command::Mode(Some(Mode::Chat)))
+        );
+        assert_eq!(
+            parse_command("/mode agent"),
+            Some(Command::Mode(Some(Mode::Agent)))
+        );
+        assert!(matches!(
+            parse_command("/mode json"),
+            Some(Command::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn prose_is_anything_that_is_not_json() {
+        assert!(is_prose("Hello there"));
+        assert!(!is_prose(r#" {"action":"chat"} "#));
+    }
+
+    #[test]
+    fn validate_command_says_why_a_line_would_not_parse() {
+        assert_eq!(validate_command("hello"), None);
+        assert_eq!(validate_command("/clear"), Some(Ok(())));
+        assert_eq!(
+            validate_command("/frobnicate"),
+            Some(Err("unknown command: /frobnicate".to_string()))
+        );
+        assert_eq!(
+            validate_command("/save"),
+            Some(Err("usage: /save <path>".to_string()))
+        );
+    }
+
+    #[test]
+    fn bookmarks_take_an_optional_number() {
+        assert_eq!(parse_command("/bookmarks"), Some(Command::Bookmarks(None)));
+        assert_eq!(
+            parse_command("/bookmarks 2"),
+            Some(Command::Bookmarks(Some(2)))
+        );
+        assert!(matches!(
+            parse_command("/bookmarks 0"),
+            Some(Command::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn bookmarks_are_saved_with_the_message() {
+        let mut message = Message::new(
+            MessageRoles::User,
+            Action::Chat,
+            HashMap::from([("response".to_string(), "hi".to_string())]),
+        );
+        message.bookmarked = true;
+        let json = serde_json::to_string(&message).unwrap();
+        assert!(serde_json::from_str::<Message>(&json).unwrap().bookmarked);
+    }
+
+    #[test]
+    fn undo_and_redo_take_no_arguments() {
+        assert_eq!(parse_command("/undo"), Some(Command::Undo));
+        assert_eq!(parse_command("/redo"), Some(Command::Redo));
+        assert!(matches!(
+            parse_command("/undo 2"),
+            Some(Command::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn cache_shows_stats_or_clears() {
+        assert_eq!(
+            parse_command("/cache"),
+            Some(Command::Cache { clear: false })
+        );
+        assert_eq!(
+            parse_command("/cache stats"),
+            Some(Command::Cache { clear: false })
+        );
+        assert_eq!(
+            parse_command("/cache clear"),
+            Some(Command::Cache { clear: true })
+        );
+        assert!(matches!(
+            parse_command("/cache drop"),
+            Some(Command::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn tree_can_be_turned_off() {
+        assert_eq!(parse_command("/tree"), Some(Command::Tree { off: false }));
+        assert_eq!(
+            parse_command("/tree off"),
+            Some(Command::Tree { off: true })
+        );
+        assert!(matches!(
+            parse_command("/tree on"),
+            Some(Command::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn exchange_dump_has_both_bodies_and_no_secrets() {
+        let request = reqwest::Client::new()
+            .post("http://localhost:11434/api/chat")
+            .bearer_auth("sk-123")
+            .build()
+            .unwrap();
+        let mut exchange = Exchange::new(&request, b"{\"model\":\"llama3\"}".to_vec());
+        exchange.status = Some("200 OK".to_string());
+        exchange.response_body = b"{\"done\":true}".to_vec();
+
+        let dump = exchange.render(&["sk-123"]);
+        assert!(dump.contains("POST http://localhost:11434/api/chat"));
+        assert!(dump.contains("{\"model\":\"llama3\"}"));
+        assert!(dump.contains("status: 200 OK"));
+        assert!(dump.contains("{\"done\":true}"));
+        assert!(dump.contains("authorization: Bearer [REDACTED]"));
+        assert!(!dump.contains("sk-123"));
+    }
+
+    #[test]
+    fn show_response_reads_the_context_sizes() {
+        let body = r#"{
+            "modelfile": "FROM llama3",
+            "parameters": "stop \"<|eot_id|>\"\nnum_ctx 8192\ntemperature 0.7",
+            "template": "{{ .Prompt }}",
+            "details": { "format": "gguf", "family": "llama", "parameter_size": "8.0B",
+                         "quantization_level": "Q4_0" },
+            "model_info": { "general.architecture": "llama", "llama.context_length": 131072 }
+        }"#;
+        let show: ShowResponse = serde_json::from_str(body).unwrap();
+        assert_eq!(show.details.family, "llama");
+        assert_eq!(show.context_length(), Some(131072));
+        assert_eq!(show.num_ctx(), Some(8192));
+
+        let rendered = show.render("llama3");
+        assert!(rendered.contains("num_ctx: 8192"), "{rendered}");
+
+        // a model that sets neither, and a host that leaves fields out
+        let bare: ShowResponse =
+            serde_json::from_str(r#"{"parameters": "temperature 0.7"}"#).unwrap();
+        assert_eq!(bare.context_length(), None);
+        assert_eq!(bare.num_ctx(), None);
+    }
+}
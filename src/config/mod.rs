@@ -0,0 +1,335 @@
+use serde::{Deserialize, Serialize};
+
+use std::path::{Path, PathBuf};
+
+const DEFAULT_ASSISTANT_NAME: &str = "OxiAI";
+const DEFAULT_MODEL: &str = "mistral:latest";
+const DEFAULT_HOST: &str = "http://localhost:11434";
+const DEFAULT_MAX_MESSAGE_LINES: usize = 30;
+const DEFAULT_TOOL_RESULT_LIMIT: usize = 8000;
+const DEFAULT_FILE_SIZE_LIMIT: u64 = 64 * 1024;
+const DEFAULT_SEARCH_RESULTS: usize = 5;
+const DEFAULT_STREAM_CURSOR_BLINK_MS: u64 = 500;
+const DEFAULT_STREAM_IDLE_SECS: u64 = 10;
+const DEFAULT_STREAM_STALL_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_TURN_TIMEOUT_SECS: u64 = 600;
+const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 120;
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_GREETING: &str = "Hi, I'm {assistant_name}. Ask me anything, press Esc to quit.";
+
+/// What to do with a tool result that is over `tool_result_limit`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolOverflow {
+    /// Cut it off at the limit with a marker
+    Truncate,
+    /// Have the model condense it first, falling back to truncating if that fails
+    Summarize,
+}
+
+/// Models for the side jobs done on the chat's behalf, each falls back to the chat model when unset
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct AssistModels {
+    /// Condenses oversized tool results, a small fast model is usually plenty
+    pub summarize: Option<String>,
+}
+
+/// What a paid backend charges, in dollars per million tokens
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct TokenPrice {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+impl TokenPrice {
+    pub fn cost(&self, totals: &crate::chat::TokenTotals) -> f64 {
+        (totals.prompt_tokens as f64 * self.prompt
+            + totals.completion_tokens as f64 * self.completion)
+            / 1_000_000.0
+    }
+}
+
+/// What Enter does while a reply is still on its way
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BusyEnter {
+    /// Nothing, the line stays in the input box
+    Ignore,
+    /// Hold the line and send it once the reply is in
+    Queue,
+}
+
+/// Caret drawn at the end of a reply while it is streaming in
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamCursor {
+    Block,
+    Underscore,
+    Spinner,
+    None,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    /// Name the assistant introduces itself as, also used as its role label in the chat pane
+    pub assistant_name: String,
+    /// Show `greeting` while the conversation is empty, it is only ever displayed, never sent
+    pub show_greeting: bool,
+    /// `{assistant_name}` is replaced with the assistant's name
+    pub greeting: String,
+    /// Start in the minimal theme, no pane borders or titles, F3 toggles it at runtime
+    pub minimal_ui: bool,
+    pub stream_cursor: StreamCursor,
+    /// How often the streaming caret blinks (or the spinner turns), in milliseconds, 0 to hold still
+    pub stream_cursor_blink_ms: u64,
+    /// Base url of the Ollama server
+    pub host: String,
+    /// API the host speaks, `ollama` or `openai` for an OpenAI compatible server
+    pub backend: crate::backend::Backend,
+    /// Model to chat with, `--model` overrides it
+    pub model: String,
+    /// Tallest a single message is drawn in the chat pane before it gets an inner scroll, `null` to never cap
+    pub max_message_lines: Option<usize>,
+    /// Longest tool result, in characters, handed back to the model as is
+    pub tool_result_limit: usize,
+    pub tool_result_overflow: ToolOverflow,
+    pub assist_models: AssistModels,
+    /// Root the file tools are sandboxed to, defaults to the directory oxiai was started in
+    pub workdir: Option<PathBuf>,
+    /// Largest file, in bytes, `get_file_contents` will hand over in one go
+    pub file_size_limit: u64,
+    /// Search API behind `web_search`, a SearxNG instance or Brave's
+    /// `https://api.search.brave.com/res/v1/web/search`, `null` leaves the tool unavailable
+    pub search_url: Option<String>,
+    /// Key for `search_url`, Brave needs one, SearxNG doesn't
+    pub search_key: Option<String>,
+    /// Results `web_search` hands back to the model
+    pub search_results: usize,
+    /// Most tokens the model may generate in one session before sending is blocked, `null` for no cap
+    pub token_budget: Option<u64>,
+    /// Seconds a stream may go without a chunk before it is shown as stalled
+    pub stream_idle_secs: u64,
+    /// Seconds without a chunk after which a stalled stream is given up on and what arrived is kept
+    pub stream_stall_timeout_secs: u64,
+    /// Seconds to wait on the host for any single read, a reply that isn't streamed has to arrive
+    /// whole within it, a streamed one only has to keep sending. `--timeout` overrides it
+    pub request_timeout_secs: u64,
+    /// Times a request is sent again when the host can't be reached or doesn't answer, waiting
+    /// twice as long before each. Errors the host does answer with are never retried
+    pub max_retries: u32,
+    /// Seconds a whole turn may take, every request and tool call in it, before it is given up
+    /// on and whatever arrived is kept, `null` for no limit
+    pub turn_timeout_secs: Option<u64>,
+    /// Context window, in tokens, to warn against when a prompt nears it. `null` uses the model's
+    /// `num_ctx`, or Ollama's default if it doesn't set one
+    pub context_size: Option<u64>,
+    /// Drop the oldest turns before sending rather than only warning when a prompt would come
+    /// near the context window
+    pub auto_prune: bool,
+    pub busy_enter: BusyEnter,
+    /// Ask before quitting with Ctrl+Q or Esc while a reply is on its way in any tab, rather than
+    /// dropping it
+    pub confirm_quit: bool,
+    /// Resend once with a stronger instruction when an agent mode reply comes back as plain
+    /// prose, on top of the usual corrections
+    pub reinforce_json: bool,
+    /// Language the assistant should reply in, e.g. "German", `null` leaves it to the model
+    pub response_language: Option<String>,
+    /// Sent as a bearer token with every request, for backends sitting behind an authenticating proxy
+    pub api_key: Option<String>,
+    /// Pricing of the backend, for an estimated cost next to the conversation's token count,
+    /// `null` for local models that cost nothing
+    pub token_price: Option<TokenPrice>,
+    /// Make urls in the chat clickable with OSC 8 hyperlinks, turn it off for terminals that
+    /// print the escapes instead of hiding them
+    pub hyperlinks: bool,
+    /// Hosts that charge for requests, on top of every `openai` backend, marked as paid in the
+    /// status bar
+    pub paid_hosts: Vec<String>,
+    /// Ask once per session, and again after every host switch, before the first send to a paid backend
+    pub confirm_paid: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            assistant_name: DEFAULT_ASSISTANT_NAME.to_string(),
+            show_greeting: true,
+            greeting: DEFAULT_GREETING.to_string(),
+            minimal_ui: false,
+            stream_cursor: StreamCursor::Block,
+            stream_cursor_blink_ms: DEFAULT_STREAM_CURSOR_BLINK_MS,
+            host: DEFAULT_HOST.to_string(),
+            backend: crate::backend::Backend::Ollama,
+            model: DEFAULT_MODEL.to_string(),
+            max_message_lines: Some(DEFAULT_MAX_MESSAGE_LINES),
+            tool_result_limit: DEFAULT_TOOL_RESULT_LIMIT,
+            tool_result_overflow: ToolOverflow::Truncate,
+            assist_models: AssistModels::default(),
+            workdir: None,
+            file_size_limit: DEFAULT_FILE_SIZE_LIMIT,
+            search_url: None,
+            search_key: None,
+            search_results: DEFAULT_SEARCH_RESULTS,
+            token_budget: None,
+            stream_idle_secs: DEFAULT_STREAM_IDLE_SECS,
+            stream_stall_timeout_secs: DEFAULT_STREAM_STALL_TIMEOUT_SECS,
+            request_timeout_secs: DEFAULT_REQUEST_TIMEOUT_SECS,
+            max_retries: DEFAULT_MAX_RETRIES,
+            turn_timeout_secs: Some(DEFAULT_TURN_TIMEOUT_SECS),
+            context_size: None,
+            auto_prune: false,
+            busy_enter: BusyEnter::Ignore,
+            confirm_quit: true,
+            reinforce_json: true,
+            response_language: None,
+            api_key: None,
+            token_price: None,
+            hyperlinks: true,
+            paid_hosts: Vec::new(),
+            confirm_paid: true,
+        }
+    }
+}
+
+impl Config {
+    /// Whether sending to `host` costs money, anything on the OpenAI API is taken to
+    pub fn is_paid(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('/');
+        self.backend == crate::backend::Backend::OpenAi
+            || self
+                .paid_hosts
+                .iter()
+                .any(|paid| paid.trim_end_matches('/') == host)
+    }
+
+    /// Load the config from `path`, or from the default location if no path was given.
+    ///
+    /// A missing file at the default location is not an error, we just use the defaults.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let path = match path {
+            Some(p) => p.to_path_buf(),
+            None => match Self::default_path() {
+                Some(p) if p.exists() => p,
+                _ => return Ok(Self::default()),
+            },
+        };
+
+        let raw = std::fs::read_to_string(&path)
+            .map_err(|e| anyhow::anyhow!("unable to read config {}: {e}", path.display()))?;
+        let config = serde_json::from_str(&raw)
+            .map_err(|e| anyhow::anyhow!("unable to parse config {}: {e}", path.display()))?;
+
+        Ok(config)
+    }
+
+    /// Write the config out as pretty JSON, creating the directory it goes in if needed
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| anyhow::anyhow!("unable to create {}: {e}", dir.display()))?;
+        }
+        let raw = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, raw + "\n")
+            .map_err(|e| anyhow::anyhow!("unable to write config {}: {e}", path.display()))?;
+        Ok(())
+    }
+
+    /// The greeting to show on an empty conversation, if it is turned on
+    pub fn greeting(&self) -> Option<String> {
+        self.show_greeting.then(|| {
+            self.greeting
+                .replace("{assistant_name}", &self.assistant_name)
+        })
+    }
+
+    /// Values that must never end up in logs or dumps
+    pub fn secrets(&self) -> Vec<&str> {
+        self.api_key.iter().map(String::as_str).collect()
+    }
+
+    /// `$XDG_CONFIG_HOME/oxiai/config.json`, falling back to `$HOME/.config/oxiai/config.json`
+    pub fn default_path() -> Option<PathBuf> {
+        let base = std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))?;
+
+        Some(base.join("oxiai").join("config.json"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_partial_config_keeps_the_defaults() {
+        let config: Config = serde_json::from_str(r#"{"assistant_name": "Corvid"}"#).unwrap();
+        assert_eq!(config.assistant_name, "Corvid");
+        assert_eq!(config.host, Config::default().host);
+        assert_eq!(config.model, Config::default().model);
+    }
+
+    #[test]
+    fn load_reads_the_given_file() {
+        let path = std::env::temp_dir().join(format!("oxiai-config-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"assistant_name": "Corvid"}"#).unwrap();
+        let config = Config::load(Some(&path));
+        std::fs::write(&path, "{ not json").unwrap();
+        let broken = Config::load(Some(&path));
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(config.unwrap().assistant_name, "Corvid");
+        let err = broken.unwrap_err().to_string();
+        assert!(err.starts_with("unable to parse config"), "{err}");
+    }
+
+    #[test]
+    fn greeting_names_the_assistant_when_it_is_on() {
+        let mut config = Config {
+            assistant_name: "Corvid".to_string(),
+            greeting: "Hi, I'm {assistant_name}.".to_string(),
+            show_greeting: true,
+            ..Config::default()
+        };
+        assert_eq!(config.greeting().as_deref(), Some("Hi, I'm Corvid."));
+        config.show_greeting = false;
+        assert_eq!(config.greeting(), None);
+    }
+
+    #[test]
+    fn paid_hosts_match_with_or_without_a_trailing_slash() {
+        let config = Config {
+            paid_hosts: vec!["https://api.example.com/".to_string()],
+            ..Config::default()
+        };
+        assert!(config.is_paid("https://api.example.com"));
+        assert!(config.is_paid("https://api.example.com/"));
+        assert!(!config.is_paid("http://localhost:11434"));
+    }
+
+    #[test]
+    fn the_openai_backend_is_always_paid() {
+        let config = Config {
+            backend: crate::backend::Backend::OpenAi,
+            ..Config::default()
+        };
+        assert!(config.is_paid("http://localhost:8080"));
+    }
+
+    #[test]
+    fn cost_is_priced_per_million_tokens() {
+        let price = TokenPrice {
+            prompt: 2.0,
+            completion: 10.0,
+        };
+        let totals = crate::chat::TokenTotals {
+            prompt_tokens: 500_000,
+            completion_tokens: 100_000,
+        };
+        assert!((price.cost(&totals) - 2.0).abs() < 1e-9);
+    }
+}
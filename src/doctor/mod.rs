@@ -0,0 +1,179 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::IsTerminal;
+use std::path::Path;
+
+use crate::{PROBE_TIMEOUT, chat, config};
+
+#[derive(PartialEq)]
+pub enum Outcome {
+    Pass,
+    Fail,
+    Skip,
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Outcome::Pass => write!(f, "pass"),
+            Outcome::Fail => write!(f, "FAIL"),
+            Outcome::Skip => write!(f, "skip"),
+        }
+    }
+}
+
+pub struct Check {
+    pub name: &'static str,
+    pub outcome: Outcome,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &'static str, outcome: Outcome, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            outcome,
+            detail: detail.into(),
+        }
+    }
+}
+
+impl Display for Check {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "  [{}] {}: {}", self.outcome, self.name, self.detail)
+    }
+}
+
+/// Run every setup check, print the report and return the process exit code
+pub async fn run(config_path: Option<&Path>, host: Option<&str>, model: Option<&str>) -> i32 {
+    let checks = run_checks(config_path, host, model).await;
+
+    println!("oxiai doctor");
+    for check in &checks {
+        println!("{check}");
+    }
+
+    match checks.iter().any(|c| c.outcome == Outcome::Fail) {
+        true => 1,
+        false => 0,
+    }
+}
+
+pub async fn run_checks(
+    config_path: Option<&Path>,
+    host: Option<&str>,
+    model: Option<&str>,
+) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    let config = match config::Config::load(config_path) {
+        Ok(c) => {
+            let source = config_path
+                .map(Path::to_path_buf)
+                .or_else(config::Config::default_path)
+                .filter(|p| p.exists());
+            checks.push(match source {
+                Some(p) => Check::new("config", Outcome::Pass, format!("{} parsed", p.display())),
+                None => Check::new("config", Outcome::Pass, "no config file, using defaults"),
+            });
+            c
+        }
+        Err(e) => {
+            checks.push(Check::new("config", Outcome::Fail, e.to_string()));
+            config::Config::default()
+        }
+    };
+
+    let client = reqwest::Client::new();
+    let host = host.unwrap_or(&config.host);
+    let model = model.unwrap_or(&config.model);
+
+    let reachable = match chat::fetch_version(&client, host, PROBE_TIMEOUT).await {
+        Ok(version) => {
+            checks.push(Check::new(
+                "ollama",
+                Outcome::Pass,
+                format!("reachable at {host} (ollama {version})"),
+            ));
+            true
+        }
+        Err(e) => {
+            checks.push(Check::new(
+                "ollama",
+                Outcome::Fail,
+                format!("unable to reach {host}: {e}"),
+            ));
+            false
+        }
+    };
+
+    checks.push(match reachable {
+        false => Check::new("model", Outcome::Skip, "host unreachable"),
+        true => match chat::fetch_tags(&client, host, PROBE_TIMEOUT).await {
+            Ok(tags) if chat::has_model(&tags, model) => {
+                Check::new("model", Outcome::Pass, format!("{model} is available"))
+            }
+            Ok(tags) => Check::new(
+                "model",
+                Outcome::Fail,
+                format!(
+                    "{model} not found, available: {} (try `ollama pull {model}`)",
+                    tags.join(", ")
+                ),
+            ),
+            Err(e) => Check::new(
+                "model",
+                Outcome::Fail,
+                format!("unable to list models: {e}"),
+            ),
+        },
+    });
+
+    // wiki_search is still backed by bundled pages, none of the tools talk to the network yet
+    checks.push(Check::new(
+        "tools",
+        Outcome::Skip,
+        "no tool endpoints configured",
+    ));
+
+    checks.push(
+        match std::io::stdin().is_terminal() && std::io::stdout().is_terminal() {
+            true => Check::new("terminal", Outcome::Pass, "stdin and stdout are a tty"),
+            false => Check::new(
+                "terminal",
+                Outcome::Fail,
+                "stdin or stdout is not a tty, the chat UI needs an interactive terminal",
+            ),
+        },
+    );
+
+    checks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A host on a port that was just free and nothing listens on any more
+    fn closed_host() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}", listener.local_addr().unwrap())
+    }
+
+    #[tokio::test]
+    async fn an_unreachable_host_fails() {
+        let host = closed_host();
+        let checks = run_checks(None, Some(&host), Some("mistral")).await;
+
+        let ollama = checks.iter().find(|c| c.name == "ollama").unwrap();
+        assert!(ollama.outcome == Outcome::Fail);
+        assert!(
+            ollama
+                .detail
+                .starts_with(&format!("unable to reach {host}"))
+        );
+        let model = checks.iter().find(|c| c.name == "model").unwrap();
+        assert!(model.outcome == Outcome::Skip);
+
+        assert_eq!(run(None, Some(&host), Some("mistral")).await, 1);
+    }
+}
@@ -0,0 +1,509 @@
+//! Sending a turn to the host and getting the reply back, shared by the chat UI and `serve`.
+//!
+//! The engine builds the request, retries a host that can't be reached, and streams the reply
+//! under the stall watchdog. What happens along the way is reported through `Events`, so each
+//! front end can show it in its own way.
+
+use std::borrow::Cow;
+use std::time::{Duration, Instant};
+
+use futures_util::StreamExt;
+use reqwest::Client;
+
+use crate::backend::{Backend, ChatBackend};
+use crate::{PROBE_TIMEOUT, chat, config, logging};
+
+/// Wait before the first retry of a request the host didn't take, doubled for each one after
+pub const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+/// Longest wait between retries, however many are allowed
+pub const RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// How long a stream may go quiet before it is reported as stalled, and then given up on
+#[derive(Clone, Copy)]
+pub struct Watchdog {
+    pub idle: Duration,
+    pub timeout: Duration,
+}
+
+/// How a stream that didn't fail came to an end
+pub enum StreamEnd {
+    /// Ollama sent its final chunk, or closed the body
+    Finished,
+    /// Nothing arrived for the whole stall timeout, what did arrive is all there is
+    TimedOut,
+}
+
+/// What a request ended with, in the shape it was asked for
+pub enum Done {
+    /// The body of a reply sent all at once
+    Whole(Result<bytes::Bytes, reqwest::Error>),
+    /// A streamed reply, its pieces already went out as `Event::Delta`
+    Streamed(Result<StreamEnd, reqwest::Error>),
+}
+
+/// Progress of a request, reported while it is under way
+pub enum Event {
+    /// Raw copy of the finished round trip, always sent before the request returns
+    Exchange(Box<chat::Exchange>),
+    /// A piece of a streamed reply
+    Delta(String),
+    /// Token counts from the final chunk of a streamed reply
+    Usage(chat::Usage),
+    /// Sending failed on the connection and is being tried again, attempt n of max
+    Retrying(u32, u32),
+    /// The stream went quiet for longer than the idle period (`true`), or picked back up (`false`)
+    Stalled(bool),
+}
+
+/// Where a request's `Event`s go
+pub trait Events {
+    fn emit(&self, event: Event);
+}
+
+impl Events for tokio::sync::mpsc::UnboundedSender<Event> {
+    fn emit(&self, event: Event) {
+        // a receiver that went away has stopped listening, there is no one left to tell
+        let _ = self.send(event);
+    }
+}
+
+pub struct Engine {
+    client: Client,
+    backend: Backend,
+    api_key: Option<String>,
+    watchdog: Watchdog,
+    max_retries: u32,
+}
+
+impl Engine {
+    pub fn new(config: &config::Config) -> reqwest::Result<Self> {
+        // a read timeout rather than a total one, so a long stream is fine as long as it keeps coming
+        let client = Client::builder()
+            .read_timeout(Duration::from_secs(config.request_timeout_secs))
+            .build()?;
+        Ok(Self {
+            client,
+            backend: config.backend,
+            api_key: config.api_key.clone(),
+            watchdog: Watchdog {
+                idle: Duration::from_secs(config.stream_idle_secs),
+                timeout: Duration::from_secs(config.stream_stall_timeout_secs),
+            },
+            max_retries: config.max_retries,
+        })
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// What the host calls itself, or why it couldn't be reached
+    pub async fn probe(&self, host: &str) -> Result<String, reqwest::Error> {
+        self.backend
+            .probe(&self.client, host, self.api_key.as_deref(), PROBE_TIMEOUT)
+            .await
+    }
+
+    /// Send `req` to `host` and see it through to the end of the reply
+    pub async fn chat(
+        &self,
+        host: &str,
+        req: &chat::ChatRequest<'_>,
+        events: &impl Events,
+    ) -> Done {
+        // serialize it ourselves so the exact bytes sent can be kept for /dump
+        let body = self.backend.body(req);
+
+        let mut builder = self
+            .client
+            .post(self.backend.chat_url(host))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.clone());
+        if let Some(key) = &self.api_key {
+            builder = builder.bearer_auth(key);
+        }
+
+        let request = match builder.build() {
+            Ok(r) => r,
+            Err(e) => {
+                return match req.stream {
+                    true => Done::Streamed(Err(e)),
+                    false => Done::Whole(Err(e)),
+                };
+            }
+        };
+
+        let mut exchange = chat::Exchange::new(&request, body);
+        let start = Instant::now();
+        let mut resp = self.client.execute(retry_copy(&request)).await;
+
+        // a host that is down or still loading the model gets a few more tries, one that answered
+        // with an error status meant it. `execute` resolves once the headers are in, a streamed
+        // reply gets those before generating, a whole one only after, so timing out on a whole
+        // one could mean the host is busy generating it and sending it again would start that over.
+        let mut attempt = 0;
+        while let Err(e) = &resp
+            && (e.is_connect() || (e.is_timeout() && req.stream))
+            && attempt < self.max_retries
+        {
+            attempt += 1;
+            crate::log!(Warn, "{e}, retrying ({attempt}/{})", self.max_retries);
+            events.emit(Event::Retrying(attempt, self.max_retries));
+            tokio::time::sleep(retry_delay(attempt)).await;
+            resp = self.client.execute(retry_copy(&request)).await;
+        }
+
+        if let Ok(r) = &resp {
+            exchange.record_response(r);
+        }
+
+        let done = if !req.stream {
+            let result = match resp {
+                Ok(r) => r.bytes().await,
+                Err(e) => Err(e),
+            };
+            match &result {
+                Ok(body) => exchange.response_body = body.to_vec(),
+                Err(e) => exchange.error = Some(e.to_string()),
+            }
+            Done::Whole(result)
+        } else {
+            let result = match resp {
+                Ok(r) => {
+                    self.stream_response(r.bytes_stream(), &mut exchange, events)
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = &result {
+                exchange.error = Some(e.to_string());
+            }
+            Done::Streamed(result)
+        };
+
+        exchange.elapsed = start.elapsed();
+        log_exchange(&exchange);
+        events.emit(Event::Exchange(Box::new(exchange)));
+        done
+    }
+
+    /// Read a streamed reply under the watchdog, `stream` is the response body
+    async fn stream_response(
+        &self,
+        mut stream: impl futures_util::Stream<Item = Result<bytes::Bytes, reqwest::Error>> + Unpin,
+        exchange: &mut chat::Exchange,
+        events: &impl Events,
+    ) -> Result<StreamEnd, reqwest::Error> {
+        let mut lines = chat::LineSplitter::default();
+        let mut stalled = false;
+
+        loop {
+            // the idle timer starts over with every chunk, once stalled it runs on to the timeout
+            let wait = match stalled {
+                false => self.watchdog.idle,
+                true => self.watchdog.timeout.saturating_sub(self.watchdog.idle),
+            };
+            let chunk = match tokio::time::timeout(wait, stream.next()).await {
+                Ok(Some(chunk)) => chunk?,
+                Ok(None) => break,
+                Err(_) if !stalled => {
+                    stalled = true;
+                    events.emit(Event::Stalled(true));
+                    continue;
+                }
+                Err(_) => return Ok(StreamEnd::TimedOut),
+            };
+
+            if stalled {
+                stalled = false;
+                events.emit(Event::Stalled(false));
+            }
+            exchange.response_body.extend_from_slice(&chunk);
+
+            for line in lines.push(&chunk) {
+                if self.forward_chunk(&line, events) {
+                    return Ok(StreamEnd::Finished);
+                }
+            }
+        }
+
+        if let Some(line) = lines.finish() {
+            self.forward_chunk(&line, events);
+        }
+
+        Ok(StreamEnd::Finished)
+    }
+
+    /// Pass one NDJSON line on as events, returns whether it was the final chunk
+    fn forward_chunk(&self, line: &[u8], events: &impl Events) -> bool {
+        crate::log!(Trace, "stream line: {}", logging::body(line));
+        let Some(parsed) = self.backend.parse_stream_line(line) else {
+            return false;
+        };
+
+        if let Some(usage) = parsed.usage() {
+            events.emit(Event::Usage(usage));
+        }
+        events.emit(Event::Delta(parsed.message.content));
+        parsed.done
+    }
+}
+
+/// The request for a turn, the same whichever front end sends it
+pub fn chat_request(
+    model: &str,
+    mode: chat::Mode,
+    stream: bool,
+    options: chat::ChatOptions,
+    messages: Vec<chat::Prompt<'static>>,
+) -> chat::ChatRequest<'static> {
+    chat::ChatRequest {
+        model: Cow::Owned(model.to_string()),
+        stream,
+        format: (mode == chat::Mode::Agent).then_some("json"),
+        stop: vec!["\n\n\n\n"],
+        options: Some(options),
+        messages,
+    }
+}
+
+/// Wait before retry number `attempt`, doubling from `RETRY_BASE_DELAY` up to `RETRY_MAX_DELAY`
+fn retry_delay(attempt: u32) -> Duration {
+    RETRY_BASE_DELAY
+        .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+        .min(RETRY_MAX_DELAY)
+}
+
+/// A fresh copy of a request to send, the body is always bytes so it can always be copied
+fn retry_copy(request: &reqwest::Request) -> reqwest::Request {
+    request
+        .try_clone()
+        .expect("chat requests have an in-memory body")
+}
+
+/// A finished round trip in the log, bodies only at debug
+fn log_exchange(exchange: &chat::Exchange) {
+    crate::log!(
+        Info,
+        "{} {} -> {} in {} ms",
+        exchange.method,
+        exchange.url,
+        exchange.status.as_deref().unwrap_or("no response"),
+        exchange.elapsed.as_millis()
+    );
+    crate::log!(
+        Debug,
+        "request body: {}",
+        logging::body(&exchange.request_body)
+    );
+    crate::log!(
+        Debug,
+        "response body: {}",
+        logging::body(&exchange.response_body)
+    );
+    if let Some(error) = &exchange.error {
+        crate::log!(
+            Error,
+            "{} {} failed: {error}",
+            exchange.method,
+            exchange.url
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl Events for std::sync::Mutex<Vec<Event>> {
+        fn emit(&self, event: Event) {
+            self.lock().unwrap().push(event);
+        }
+    }
+
+    fn engine(max_retries: u32) -> Engine {
+        let config = config::Config {
+            max_retries,
+            ..Default::default()
+        };
+        Engine::new(&config).unwrap()
+    }
+
+    /// Send one empty turn to `host`, what it ended with and every event on the way
+    async fn call(engine: &Engine, host: &str, stream: bool) -> (Done, Vec<Event>) {
+        let req = chat_request(
+            "mock",
+            chat::Mode::Chat,
+            stream,
+            Default::default(),
+            Vec::new(),
+        );
+        let events = std::sync::Mutex::new(Vec::new());
+        let done = engine.chat(host, &req, &events).await;
+        (done, events.into_inner().unwrap())
+    }
+
+    async fn mock_host(name: &str, script: &str) -> String {
+        let path = std::env::temp_dir().join(format!("oxiai-engine-{name}-{}", std::process::id()));
+        std::fs::write(&path, script).unwrap();
+        let host = crate::mock::start(&path).await.unwrap();
+        let _ = std::fs::remove_file(&path);
+        host
+    }
+
+    #[test]
+    fn retry_delay_doubles_up_to_the_cap() {
+        assert_eq!(retry_delay(1), RETRY_BASE_DELAY);
+        assert_eq!(retry_delay(2), RETRY_BASE_DELAY * 2);
+        assert_eq!(retry_delay(3), RETRY_BASE_DELAY * 4);
+        assert_eq!(retry_delay(40), RETRY_MAX_DELAY);
+        assert_eq!(retry_delay(u32::MAX), RETRY_MAX_DELAY);
+    }
+
+    #[test]
+    fn only_agent_mode_asks_for_json() {
+        let agent = chat_request(
+            "m",
+            chat::Mode::Agent,
+            false,
+            Default::default(),
+            Vec::new(),
+        );
+        assert_eq!(agent.format, Some("json"));
+        let plain = chat_request("m", chat::Mode::Chat, false, Default::default(), Vec::new());
+        assert_eq!(plain.format, None);
+    }
+
+    #[tokio::test]
+    async fn streams_the_reply_then_finishes() {
+        let script = r#"[{"reply": "all good", "chunks": ["all ", "good"]}]"#;
+        let host = mock_host("stream", script).await;
+        let (done, events) = call(&engine(0), &host, true).await;
+
+        let deltas: Vec<_> = events
+            .iter()
+            .filter_map(|e| match e {
+                Event::Delta(text) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(deltas.concat(), "all good");
+        assert!(events.iter().any(|e| matches!(e, Event::Usage(_))));
+        assert!(matches!(events.last(), Some(Event::Exchange(_))));
+        assert!(matches!(done, Done::Streamed(Ok(StreamEnd::Finished))));
+    }
+
+    #[tokio::test]
+    async fn hands_an_error_status_back_without_retrying() {
+        let script = r#"[{"error": {"status": 503, "message": "model runner crashed"}}]"#;
+        let host = mock_host("status", script).await;
+        let (done, events) = call(&engine(3), &host, false).await;
+
+        assert!(!events.iter().any(|e| matches!(e, Event::Retrying(..))));
+        let Some(Event::Exchange(exchange)) = events.last() else {
+            panic!("no exchange was recorded");
+        };
+        assert_eq!(exchange.status.as_deref(), Some("503 Service Unavailable"));
+        let Done::Whole(Ok(body)) = done else {
+            panic!("the request didn't finish with a body");
+        };
+        assert!(String::from_utf8_lossy(&body).contains("model runner crashed"));
+    }
+
+    #[tokio::test]
+    async fn retries_a_host_that_is_not_there() {
+        // a port that was just free and nothing listens on any more
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let host = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+
+        let (done, events) = call(&engine(1), &host, true).await;
+        assert!(matches!(events.first(), Some(Event::Retrying(1, 1))));
+        let Done::Streamed(Err(e)) = done else {
+            panic!("the request didn't fail");
+        };
+        assert!(e.is_connect());
+    }
+
+    /// A response body that sends each line after its delay, then stays open without a word
+    fn slow_body(
+        lines: Vec<(Duration, String)>,
+    ) -> impl futures_util::Stream<Item = Result<bytes::Bytes, reqwest::Error>> + Unpin {
+        let timed = futures_util::stream::unfold(lines.into_iter(), |mut lines| async move {
+            let (delay, line) = lines.next()?;
+            tokio::time::sleep(delay).await;
+            Some((Ok(bytes::Bytes::from(format!("{line}\n"))), lines))
+        });
+        Box::pin(timed.chain(futures_util::stream::pending()))
+    }
+
+    /// Run `body` through the watchdog of an engine with a 10s idle period and a 60s timeout
+    async fn watch(
+        body: impl futures_util::Stream<Item = Result<bytes::Bytes, reqwest::Error>> + Unpin,
+    ) -> (Result<StreamEnd, reqwest::Error>, Vec<Event>) {
+        let config = config::Config {
+            stream_idle_secs: 10,
+            stream_stall_timeout_secs: 60,
+            ..Default::default()
+        };
+        let engine = Engine::new(&config).unwrap();
+        let request = engine
+            .client
+            .post("http://localhost/api/chat")
+            .build()
+            .unwrap();
+        let mut exchange = chat::Exchange::new(&request, Vec::new());
+        let events = std::sync::Mutex::new(Vec::new());
+        let end = engine.stream_response(body, &mut exchange, &events).await;
+        (end, events.into_inner().unwrap())
+    }
+
+    fn delta(text: &str) -> String {
+        serde_json::json!({ "message": { "role": "assistant", "content": text }, "done": false })
+            .to_string()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_stream_that_goes_quiet_is_reported_stalled_then_recovered() {
+        let done = r#"{"message":{"role":"assistant","content":""},"done":true}"#;
+        let body = slow_body(vec![
+            (Duration::ZERO, delta("slow ")),
+            (Duration::from_secs(25), delta("but steady")),
+            (Duration::from_secs(1), done.to_string()),
+        ]);
+        let (end, events) = watch(body).await;
+
+        assert!(matches!(end, Ok(StreamEnd::Finished)));
+        let seen: Vec<_> = events
+            .iter()
+            .map(|e| match e {
+                Event::Delta(text) => text.clone(),
+                Event::Stalled(stalled) => format!("stalled {stalled}"),
+                _ => String::new(),
+            })
+            .collect();
+        assert_eq!(
+            seen,
+            ["slow ", "stalled true", "stalled false", "but steady", ""]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_stream_that_stays_quiet_times_out_at_the_total() {
+        let started = tokio::time::Instant::now();
+        let (end, events) =
+            watch(slow_body(vec![(Duration::ZERO, delta("and then nothing"))])).await;
+
+        assert!(matches!(end, Ok(StreamEnd::TimedOut)));
+        // the idle period is part of the timeout, not on top of it
+        assert_eq!(started.elapsed(), Duration::from_secs(60));
+        assert!(matches!(
+            events[..],
+            [Event::Delta(_), Event::Stalled(true)]
+        ));
+    }
+}
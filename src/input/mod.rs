@@ -0,0 +1,126 @@
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Byte index of the start of the grapheme cluster before `cursor`, so a flag or an accented
+/// letter built from several scalar values is stepped over (and deleted) as one character
+pub fn prev_boundary(text: &str, cursor: usize) -> Option<usize> {
+    text[..cursor]
+        .grapheme_indices(true)
+        .next_back()
+        .map(|(idx, _)| idx)
+}
+
+/// Byte index just past the grapheme cluster starting at `cursor`
+pub fn next_boundary(text: &str, cursor: usize) -> Option<usize> {
+    text[cursor..]
+        .graphemes(true)
+        .next()
+        .map(|g| cursor + g.len())
+}
+
+/// Byte index of the start of the line `cursor` is on
+pub fn line_start(text: &str, cursor: usize) -> usize {
+    text[..cursor].rfind('\n').map_or(0, |idx| idx + 1)
+}
+
+/// Byte index of the end of the line `cursor` is on, just before its newline
+pub fn line_end(text: &str, cursor: usize) -> usize {
+    text[cursor..]
+        .find('\n')
+        .map_or(text.len(), |idx| cursor + idx)
+}
+
+/// Incremental reverse search through previously sent prompts, newest match first
+#[derive(Default)]
+pub struct HistorySearch {
+    pub query: String,
+    /// How many matches to skip over, pressing Ctrl+R again reaches further back
+    skip: usize,
+}
+
+impl HistorySearch {
+    pub fn push(&mut self, c: char) {
+        self.query.push(c);
+        self.skip = 0;
+    }
+
+    pub fn pop(&mut self) {
+        self.query.pop();
+        self.skip = 0;
+    }
+
+    /// Move on to the next older match, staying put if there isn't one
+    pub fn older(&mut self, history: &[String]) {
+        if self.matches(history).nth(self.skip + 1).is_some() {
+            self.skip += 1;
+        }
+    }
+
+    pub fn current<'a>(&self, history: &'a [String]) -> Option<&'a str> {
+        self.matches(history).nth(self.skip)
+    }
+
+    fn matches<'s, 'a>(
+        &'s self,
+        history: &'a [String],
+    ) -> impl Iterator<Item = &'a str> + use<'s, 'a> {
+        history
+            .iter()
+            .rev()
+            .filter(|line| line.contains(&self.query))
+            .map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boundaries_step_over_whole_clusters() {
+        // an e with a combining accent, then a flag, both more than one scalar value
+        let text = "ae\u{301}🇳🇴";
+        let flag = text.len() - "🇳🇴".len();
+        assert_eq!(prev_boundary(text, text.len()), Some(flag));
+        assert_eq!(prev_boundary(text, flag), Some(1));
+        assert_eq!(next_boundary(text, 1), Some(flag));
+        assert_eq!(next_boundary(text, flag), Some(text.len()));
+        assert_eq!(prev_boundary(text, 0), None);
+        assert_eq!(next_boundary(text, text.len()), None);
+    }
+
+    #[test]
+    fn line_bounds_stop_at_newlines() {
+        let text = "one\ntwo\nthree";
+        assert_eq!(line_start(text, 5), 4);
+        assert_eq!(line_end(text, 5), 7);
+        assert_eq!(line_start(text, 2), 0);
+        assert_eq!(line_end(text, 9), text.len());
+    }
+
+    #[test]
+    fn history_search_goes_newest_first() {
+        let history = ["cargo build", "ls", "cargo test", "cargo run"].map(String::from);
+        let mut search = HistorySearch::default();
+        "cargo".chars().for_each(|c| search.push(c));
+        assert_eq!(search.current(&history), Some("cargo run"));
+        search.older(&history);
+        assert_eq!(search.current(&history), Some("cargo test"));
+        search.older(&history);
+        search.older(&history);
+        // no older match, it stays on the last one
+        assert_eq!(search.current(&history), Some("cargo build"));
+    }
+
+    #[test]
+    fn history_search_starts_over_when_the_query_changes() {
+        let history = ["cargo build", "cargo test"].map(String::from);
+        let mut search = HistorySearch::default();
+        search.push('c');
+        search.older(&history);
+        assert_eq!(search.current(&history), Some("cargo build"));
+        search.push('x');
+        assert_eq!(search.current(&history), None);
+        search.pop();
+        assert_eq!(search.current(&history), Some("cargo test"));
+    }
+}
@@ -0,0 +1,93 @@
+//! Optional log file for `--log-file`. The UI owns the terminal, so this is the only place
+//! diagnostics can go while it runs. Nothing is ever written to stdout or stderr from here.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+struct Logger {
+    level: Level,
+    file: Mutex<File>,
+}
+
+/// How much goes into the log, each level includes the ones above it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum Level {
+    Error,
+    Warn,
+    /// Requests sent, tools run
+    Info,
+    /// Request and response bodies as well
+    Debug,
+    /// Every streamed line as it arrives
+    Trace,
+}
+
+impl Level {
+    fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+/// Start logging to `path`, appending to whatever is there already
+pub fn init(path: &Path, level: Level) -> anyhow::Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| anyhow::anyhow!("unable to open the log file {}: {e}", path.display()))?;
+    LOGGER
+        .set(Logger {
+            level,
+            file: Mutex::new(file),
+        })
+        .map_err(|_| anyhow::anyhow!("logging was already started"))
+}
+
+/// Whether anything at `level` would be written, to skip building messages nobody reads
+pub fn enabled(level: Level) -> bool {
+    LOGGER.get().is_some_and(|logger| level <= logger.level)
+}
+
+/// Write one line, use the `log!` macro rather than calling this
+pub fn write(level: Level, args: std::fmt::Arguments<'_>) {
+    let Some(logger) = LOGGER.get() else {
+        return;
+    };
+    if level > logger.level {
+        return;
+    }
+    let line = format!(
+        "{} {:<5} {args}\n",
+        chrono::Local::now().format("%Y-%m-%dT%H:%M:%S%.3f%:z"),
+        level.as_str()
+    );
+    // a log that can't be written to is not worth taking the UI down over
+    if let Ok(mut file) = logger.file.lock() {
+        let _ = file.write_all(line.as_bytes());
+    }
+}
+
+/// Body bytes as text for the log, invalid UTF-8 is replaced rather than hidden
+pub fn body(bytes: &[u8]) -> std::borrow::Cow<'_, str> {
+    String::from_utf8_lossy(bytes)
+}
+
+/// `log!(Info, "sent {url}")`, formatted only when the level is enabled
+#[macro_export]
+macro_rules! log {
+    ($level:ident, $($arg:tt)+) => {
+        if $crate::logging::enabled($crate::logging::Level::$level) {
+            $crate::logging::write($crate::logging::Level::$level, format_args!($($arg)+));
+        }
+    };
+}
@@ -1,308 +1,4193 @@
 use std::borrow::Cow;
-use std::pin::Pin;
+use std::collections::BTreeMap;
 use std::time::{Duration, Instant};
 
+use backend::ChatBackend;
 use chat::{Action, Message};
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use engine::StreamEnd;
 use futures_util::StreamExt;
-use reqwest::Client;
+use tokio::sync::mpsc;
+use tokio::time::MissedTickBehavior;
 
-use crossterm::event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode};
+use crossterm::event::{
+    DisableBracketedPaste, DisableMouseCapture, EnableBracketedPaste, EnableMouseCapture, Event,
+    EventStream, KeyCode, KeyModifiers, MouseButton, MouseEvent, MouseEventKind,
+};
 use crossterm::terminal::{
     EnterAlternateScreen, LeaveAlternateScreen, disable_raw_mode, enable_raw_mode,
 };
 
 use ratatui::{Terminal, backend::CrosstermBackend};
 
+mod backend;
 mod chat;
+mod config;
+mod doctor;
+mod engine;
+mod input;
+mod logging;
+mod mock;
+mod replay;
+mod search;
+mod serve;
+mod tool;
 mod ui;
+mod wizard;
+
+const HEADER_PROMPT: &str = r#"SYSTEM: You are "{assistant_name}", a logical, personal assistant that answers *only* via valid, minified, UTF-8 JSON."#;
+
+const TOOLS_LIST: &str = include_str!("data/tools_list.json");
+
+/// How many times a turn may ask the model to fix a malformed reply before giving up
+const MAX_CORRECTIONS: u32 = 2;
+/// Tool calls the model gets in one turn before it has to answer with what it has
+const MAX_TOOL_HOPS: u32 = 5;
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Context window Ollama runs a model with when neither the model nor the request sets `num_ctx`
+const DEFAULT_NUM_CTX: u64 = 4096;
+
+/// Share of the context window a prompt can fill before it's worth warning about
+const CONTEXT_WARN_RATIO: f64 = 0.8;
+/// Characters per token a prompt is estimated at until the host has reported a real count
+const CHARS_PER_TOKEN: f64 = 4.0;
+
+// Draws only happen on a tick so bursts of state changes get coalesced into one frame. ~60fps
+// while there is something to draw, idle ticks are only there to notice there still isn't.
+const BUSY_TICK: Duration = Duration::from_millis(16);
+/// How far down, and how many entries, the working directory tree given to the model goes
+const PROJECT_TREE_DEPTH: usize = 3;
+const PROJECT_TREE_ENTRIES: usize = 200;
+/// Most of an unparseable reply body quoted in the error shown for it
+const ERROR_BODY_LIMIT: usize = 200;
+/// Columns Left/Right move the chat pane by while wrapping is off
+const H_SCROLL_STEP: usize = 8;
+const IDLE_TICK: Duration = Duration::from_millis(250);
+/// How long a status line stays up once nothing is in flight
+const STATUS_TTL: Duration = Duration::from_secs(6);
+
+/// System prompt for plain chat mode, where there are no tools and no reply format to follow
+const PLAIN_PROMPT: &str = r#"You are "{assistant_name}", a logical, personal assistant. Answer in plain text. Base claims on what you know, if unsure, say so."#;
+
+const RULES_PROMPT: &str = r#"Rules:
+1. Think silently, Never reveal your chain-of-thought.
+2. To use a tool: {"action":"<tool>","arguments":{...}}
+3. To reply directly: {"action":"chat","arguments":{"response":"..."}
+4. If a question is vague, comparative, descriptive, or about ideas rather than specifics: use the web_search tool.
+5. If a question clearly names a specific object, animal, person, place: use the wiki_search tool.
+6. Base claims strictly on provided data or tool results. If unsure, say so.
+7. Check your output; If you reach four consecutive newlines: *stop*"#;
 
 #[derive(Parser)]
 struct Args {
     #[arg(
         short,
         long,
-        default_value = "mistral:latest",
-        help = "Model name to use"
+        global = true,
+        help = "Model name to use (defaults to the config's model, mistral:latest if it has none)"
     )]
-    model: String,
+    model: Option<String>,
+
+    #[arg(
+        long,
+        global = true,
+        value_parser = parse_host,
+        help = "Base url of the Ollama server (defaults to the config's host, http://localhost:11434 if it has none)"
+    )]
+    host: Option<String>,
+
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        help = "API the host speaks (defaults to the config's backend, ollama if it has none)"
+    )]
+    backend: Option<backend::Backend>,
 
     #[arg(
         short,
         long,
-        help = "(Broken) Should the response be streamed from ollama or sent all at once"
+        help = "Should the response be streamed from ollama or sent all at once"
     )]
     stream: bool,
 
-    #[arg(short, long, help = "(Broken) Show statistics in non-stream mode?")]
+    #[arg(
+        short,
+        long,
+        help = "Show the size, speed, latency and token counts of the last request in the status bar"
+    )]
     nerd_stats: bool,
+
+    #[arg(
+        short,
+        long,
+        help = "Path to a JSON config file (defaults to ~/.config/oxiai/config.json)"
+    )]
+    config: Option<std::path::PathBuf>,
+
+    #[arg(
+        long,
+        help = "Don't run the setup wizard, even if there is no config file yet"
+    )]
+    no_wizard: bool,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Directory the file tools are confined to (defaults to the config's workdir, then the current directory)"
+    )]
+    workdir: Option<std::path::PathBuf>,
+
+    #[arg(
+        long,
+        help = "Times to resend a request the host couldn't be reached for (defaults to the config's max_retries, 3 if it has none)"
+    )]
+    max_retries: Option<u32>,
+
+    #[arg(
+        long,
+        value_name = "TOKENS",
+        help = "Context window to keep prompts under (defaults to the config's context_size, then the model's num_ctx)"
+    )]
+    context_size: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Drop the oldest turns before a prompt would outgrow the context window, /prune does it by hand"
+    )]
+    auto_prune: bool,
+
+    #[arg(
+        long,
+        value_name = "SECS",
+        help = "Give up on a request after this long without hearing from the host (defaults to the config's request_timeout_secs, 120 if it has none)"
+    )]
+    timeout: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Keep the conversation in this file, loaded at startup and written back on quit"
+    )]
+    session: Option<std::path::PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Write the conversation as a Markdown transcript to this file on quit, like /export"
+    )]
+    export_on_quit: Option<std::path::PathBuf>,
+
+    #[arg(
+        long,
+        help = "Read the system prompt's header from a file instead of the built-in one, {assistant_name} is filled in"
+    )]
+    system_file: Option<std::path::PathBuf>,
+
+    #[arg(
+        long,
+        help = "Read the tools list offered to the model from a JSON file instead of the built-in one"
+    )]
+    tools_file: Option<std::path::PathBuf>,
+
+    #[arg(
+        long,
+        help = "Search API for the web_search tool, a SearxNG instance or Brave's (defaults to the config's search_url)"
+    )]
+    search_url: Option<String>,
+
+    #[arg(
+        long,
+        help = "Key for the search API, Brave needs one (defaults to the config's search_key)"
+    )]
+    search_key: Option<String>,
+
+    #[arg(
+        long,
+        help = "Give the model the working directory's layout at the start, /tree refreshes it"
+    )]
+    project_tree: bool,
+
+    #[arg(
+        long,
+        value_parser = chat::parse_non_negative,
+        help = "Sampling temperature, 0 or more (defaults to the host's, /set temperature changes it)"
+    )]
+    temperature: Option<f32>,
+
+    #[arg(
+        long,
+        value_parser = chat::parse_probability,
+        help = "Only sample from the most likely tokens that make up this share, 0 to 1 (defaults to the host's)"
+    )]
+    top_p: Option<f32>,
+
+    #[arg(
+        long,
+        value_parser = chat::parse_count,
+        help = "Only sample from this many of the most likely tokens (defaults to the host's)"
+    )]
+    top_k: Option<u32>,
+
+    #[arg(
+        long,
+        value_parser = chat::parse_non_negative,
+        help = "How hard to push back on repeated tokens, 0 or more (defaults to the host's)"
+    )]
+    repeat_penalty: Option<f32>,
+
+    #[arg(
+        long,
+        value_parser = chat::parse_count,
+        help = "Fixed sampling seed, for reproducible runs while changing a prompt"
+    )]
+    seed: Option<u32>,
+
+    #[arg(
+        long,
+        help = "Append what happens behind the UI to this file: requests, replies, tool calls and errors"
+    )]
+    log_file: Option<std::path::PathBuf>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value = "info",
+        help = "How much goes into the log file, debug adds request and reply bodies, trace every streamed line"
+    )]
+    log_level: logging::Level,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "SCRIPT",
+        help = "Answer from a script of canned replies on a local stand-in host instead of a real one, for demos and testing"
+    )]
+    mock: Option<std::path::PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<CliCommand>,
 }
 
-struct App {
-    args: Args,
+/// One section of the system prompt from a file, the tools list has to at least be valid JSON
+fn read_prompt_section(path: &std::path::Path, json: bool) -> Result<String, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("unable to read {}: {e}", path.display()))?;
+    if json && let Err(e) = text.parse::<serde_json::Value>() {
+        return Err(format!("{} is not valid JSON: {e}", path.display()));
+    }
+    Ok(text)
+}
+
+/// A host url as given on the command line, checked up front rather than on the first request
+fn parse_host(host: &str) -> Result<String, String> {
+    reqwest::Url::parse(host).map_err(|e| format!("invalid url: {e}"))?;
+    Ok(host.trim_end_matches('/').to_string())
+}
+
+#[derive(Subcommand)]
+enum CliCommand {
+    /// Check the config, the Ollama host, the model and the terminal, then exit
+    Doctor,
+    /// Run without the UI, taking JSON-RPC requests for editor plugins
+    Serve {
+        /// Speak line-delimited JSON-RPC over stdin/stdout, the only transport for now
+        #[arg(long)]
+        stdio: bool,
+    },
+    /// Send a saved conversation's user turns to the model again and save the new answers
+    Replay {
+        /// Conversation to replay, as written by /save
+        conversation: std::path::PathBuf,
+        /// Where to write the new transcript, defaults to next to the original, named after the model
+        #[arg(long)]
+        out: Option<std::path::PathBuf>,
+        /// Print each saved answer next to the new one
+        #[arg(long)]
+        compare: bool,
+    },
+}
+
+/// What a request was sent for, handed back with its reply so it gets routed to the right place
+#[derive(Clone, Copy)]
+enum RequestKind {
+    Chat,
+    /// Condensing an oversized result from this tool before it goes back to the model
+    Summarize(chat::AssistantTool),
+}
+
+/// A line in the status bar, errors and progress alike
+struct Status {
+    text: String,
+    /// When it was set, shown in front of it
+    at: chrono::DateTime<chrono::Local>,
+}
+
+/// Work requested by the UI, handled by `run_workers`
+enum Cmd {
+    RunChat {
+        epoch: u64,
+        request: u64,
+        host: String,
+        kind: RequestKind,
+        req: chat::ChatRequest<'static>,
+    },
+    /// Check the host is up, answered with `Msg::Probe`
+    Probe {
+        epoch: u64,
+        request: u64,
+        host: String,
+        model: String,
+    },
+    /// Look a model up with `/api/show`, answered with `Msg::Show`
+    Show {
+        epoch: u64,
+        request: u64,
+        host: String,
+        model: String,
+    },
+    /// Run a `web_search` tool call, answered with `Msg::ToolDone`
+    Search {
+        epoch: u64,
+        request: u64,
+        query: String,
+    },
+    /// Abort everything still in flight for an epoch. Dropping a request's task drops its
+    /// response stream and with it the connection, which is what makes Ollama stop generating on
+    /// its side.
+    Cancel { epoch: u64 },
+}
+
+/// Results sent back to the UI from the workers.
+///
+/// This is the data channel, it is unbounded and nothing sent on it is ever dropped. Redraws are
+/// requested separately through `AppState::dirty` so they can be coalesced under load.
+enum Msg {
+    /// Raw copy of a finished round trip, always sent before the matching `HttpDone`/`StreamDone`
+    Exchange(Box<chat::Exchange>),
+    HttpDone(RequestKind, Result<bytes::Bytes, reqwest::Error>),
+    StreamDelta(String),
+    /// Token counts from the final chunk of a streamed reply
+    Usage(chat::Usage),
+    StreamDone(Result<StreamEnd, reqwest::Error>),
+    /// Sending failed on the connection and is being tried again, attempt n of max
+    Retrying(u32, u32),
+    /// The stream went quiet for longer than the idle period (`true`), or picked back up (`false`)
+    StreamStalled(bool),
+    /// Ollama version reported by the host, or why it couldn't be reached
+    Probe(Result<String, String>),
+    /// `num_ctx` the model sets for itself, `None` if it doesn't
+    ModelContext(Option<u64>),
+    /// Details of the named model for /show, or why they couldn't be fetched
+    Show(String, Result<chat::ShowResponse, String>),
+    /// Result of a tool the worker ran, an `Err` still goes back to the model
+    ToolDone(chat::AssistantTool, Result<String, String>),
+}
+
+/// A `Msg` tagged with the epoch that was current when its request was sent, and that request's id.
+/// Every tab has epochs of its own, so the epoch also says which tab a reply belongs to.
+struct Envelope {
+    epoch: u64,
+    request: u64,
+    msg: Msg,
+}
+
+/// Longest a tab's title gets in the tab bar
+const TAB_TITLE_WIDTH: usize = 16;
+
+/// A conversation in a background tab, the part of `AppState` every tab has its own of. The
+/// active tab's lives in `AppState` itself, switching tabs swaps the two.
+struct Tab {
+    system: chat::SystemLayers,
+    mode: chat::Mode,
+    next_turn_note: Option<String>,
     prompt: String,
+    cursor: usize,
     messages: Vec<Message>,
+    undone: Vec<Vec<Message>>,
+    streaming: BTreeMap<u64, String>,
     waiting: bool,
+    turn_started: Instant,
+    queued: std::collections::VecDeque<String>,
+    requests: BTreeMap<u64, Option<u64>>,
+    early_replies: BTreeMap<u64, Msg>,
+    reply_slot: Option<usize>,
+    corrections: Vec<chat::Prompt<'static>>,
+    corrective_retries: u32,
+    tool_hops: u32,
+    reinforced: bool,
+    totals: chat::TokenTotals,
+    pending_tool_result: Option<String>,
+    epoch: u64,
+    h_scroll: usize,
+    collapsed: std::collections::HashSet<u64>,
+    selected: Option<usize>,
+    inner_scroll: usize,
+    scrollback: Option<usize>,
+    last_sent_chars: Option<usize>,
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    // parse arguments
-    let args = match Args::try_parse() {
-        Ok(args) => args,
-        Err(e) => {
-            e.print().expect("Error writing clap error");
-            std::process::exit(0);
+impl Tab {
+    fn new(system: chat::SystemLayers, mode: chat::Mode, epoch: u64) -> Self {
+        Self {
+            system,
+            mode,
+            next_turn_note: None,
+            prompt: String::new(),
+            cursor: 0,
+            messages: Vec::new(),
+            undone: Vec::new(),
+            streaming: BTreeMap::new(),
+            waiting: false,
+            turn_started: Instant::now(),
+            queued: Default::default(),
+            requests: BTreeMap::new(),
+            early_replies: BTreeMap::new(),
+            reply_slot: None,
+            corrections: Vec::new(),
+            corrective_retries: 0,
+            tool_hops: 0,
+            reinforced: false,
+            totals: chat::TokenTotals::default(),
+            pending_tool_result: None,
+            epoch,
+            h_scroll: 0,
+            collapsed: Default::default(),
+            selected: None,
+            inner_scroll: 0,
+            scrollback: None,
+            last_sent_chars: None,
         }
-    };
+    }
+}
 
-    // setup crossterm
-    enable_raw_mode()?;
-    let mut stdout_handle = std::io::stdout();
-    crossterm::execute!(stdout_handle, EnterAlternateScreen, EnableMouseCapture)?;
-    let backend = CrosstermBackend::new(stdout_handle);
-    let mut terminal = Terminal::new(backend)?;
+enum ConnectionState {
+    Checking,
+    Connected(String),
+    Unreachable(String),
+}
 
-    let mut app = App {
-        args,
-        prompt: String::new(),
-        messages: vec![],
-        waiting: false,
-    };
+impl std::fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionState::Checking => write!(f, "checking..."),
+            ConnectionState::Connected(version) => write!(f, "connected, {version}"),
+            ConnectionState::Unreachable(e) => write!(f, "unreachable: {e}"),
+        }
+    }
+}
 
-    let client = Client::new();
+struct AppState {
+    args: Args,
+    config: config::Config,
+    system: chat::SystemLayers,
+    mode: chat::Mode,
+    /// Sampling options sent with every turn, from the command line and `/set`
+    options: chat::ChatOptions,
+    /// Tools turned off with `/tools disable`, left out of the system prompt and refused if called
+    disabled_tools: std::collections::HashSet<chat::AssistantTool>,
+    /// First section of the agent system prompt, `HEADER_PROMPT` unless `--system-file` gave one
+    header_prompt: String,
+    /// Tools offered in the agent system prompt, `TOOLS_LIST` unless `--tools-file` gave one
+    tools_list: String,
+    /// Set with /nudge, becomes `system.turn` when the next turn starts
+    next_turn_note: Option<String>,
+    prompt: String,
+    /// Byte index into `prompt` where typing goes, always on a grapheme boundary
+    cursor: usize,
+    /// Every line entered this session, oldest first
+    history: Vec<String>,
+    /// Read-only overlay, while it is open keys go to it instead of the input box
+    popup: Option<ui::Popup>,
+    /// Ctrl+R search through `history`, while it is open keys go to it instead of the input box
+    search: Option<input::HistorySearch>,
+    messages: Vec<Message>,
+    next_message_id: u64,
+    /// Turns taken back with /undo, newest last, emptied once a new turn starts
+    undone: Vec<Vec<Message>>,
+    /// Content of the assistant replies currently being streamed in by request id, every delta is
+    /// kept here
+    streaming: BTreeMap<u64, String>,
+    waiting: bool,
+    /// Worker tasks actually running, what the busy spinner goes by
+    busy: BusyLot,
+    /// When the turn in progress was sent, it is cut off after `config.turn_timeout_secs`
+    turn_started: Instant,
+    /// Lines entered while waiting with `busy_enter` set to queue, sent one turn at a time
+    queued: std::collections::VecDeque<String>,
+    next_request_id: u64,
+    /// Chat requests still waiting on a reply, in the order they were sent, along with the id of
+    /// the message their reply belongs after
+    requests: BTreeMap<u64, Option<u64>>,
+    /// Replies that finished before a request sent ahead of them did, held back until it has
+    early_replies: BTreeMap<u64, Msg>,
+    /// Where messages go while a reply is being put in place, `None` appends
+    reply_slot: Option<usize>,
+    /// Set whenever something visible changes, cleared when a frame is drawn
+    dirty: bool,
+    /// When the app started, the clock the streaming caret blinks by
+    started: Instant,
+    should_quit: bool,
+    /// Ctrl+Q was pressed mid-request, the next key says whether to go through with it
+    quit_pending: bool,
+    /// One line of feedback shown under the input box
+    status: Option<Status>,
+    last_exchange: Option<Box<chat::Exchange>>,
+    /// Rejected replies and the corrections sent for them this turn, only ever sent, never kept
+    corrections: Vec<chat::Prompt<'static>>,
+    corrective_retries: u32,
+    /// Tool calls run so far this turn, capped at `MAX_TOOL_HOPS`. One past it once the model
+    /// has been told to answer without them.
+    tool_hops: u32,
+    /// Whether this turn already had its one resend with `chat::REINFORCE_PROMPT`
+    reinforced: bool,
+    /// Tokens generated so far this session, counted against `config.token_budget`
+    tokens_used: u64,
+    /// Prompt and completion tokens this conversation has cost, kept with it by /save
+    totals: chat::TokenTotals,
+    /// `prompt_eval_count` of the last reply, for the nerd stats
+    last_prompt_tokens: Option<u64>,
+    /// Counts and timings of the last reply, for the nerd stats
+    last_usage: Option<chat::Usage>,
+    /// Context window of the model on the current host, once it's known
+    model_context: Option<u64>,
+    /// Characters of the last chat prompt sent, matched against the token count it comes back with
+    last_sent_chars: Option<usize>,
+    /// How many characters make a token for this model, learned from the host's counts
+    chars_per_token: f64,
+    /// Where the file tools are allowed to look
+    sandbox: tool::Sandbox,
+    /// Full tool result kept while it is being summarized, in case the summary fails
+    pending_tool_result: Option<String>,
+    /// Base url of the backend, can be changed at runtime with /host
+    host: String,
+    /// Bumped whenever in-flight requests are abandoned (cancel, host switch), replies tagged with
+    /// an older epoch are dropped
+    epoch: u64,
+    connection: ConnectionState,
+    /// Sending to the current host was confirmed, only matters if it is a paid one
+    paid_confirmed: bool,
+    /// What the terminal can show, colors beyond it are brought down to the nearest it has
+    color_depth: ui::ColorDepth,
+    /// Drop the pane borders and titles, starts out as `config.minimal_ui`
+    minimal: bool,
+    /// Show tool calls and results in their own column, on terminals wide enough for it
+    side_panel: bool,
+    /// Wrap long lines in the chat pane, F4 turns it off to scroll them sideways instead
+    wrap: bool,
+    /// Columns the chat pane is scrolled right by while wrapping is off
+    h_scroll: usize,
+    /// User messages whose turns are folded down to the question alone
+    collapsed: std::collections::HashSet<u64>,
+    /// Message picked out in the chat pane, if any
+    selected: Option<usize>,
+    /// How far the selected message has been scrolled within its capped height
+    inner_scroll: usize,
+    /// Top line the chat pane was scrolled back to, `None` to follow the newest message
+    scrollback: Option<usize>,
+    /// Where things ended up on screen in the last frame, used to map mouse clicks back to state
+    layout: std::cell::RefCell<ui::FrameLayout>,
+    /// Messages already laid out for the chat and side panes
+    render_cache: std::cell::RefCell<ui::RenderCache>,
+    /// Every open tab, the active one's slot only holds a stand-in while its state is live above
+    tabs: Vec<Tab>,
+    active_tab: usize,
+    /// Last epoch handed out, epochs are never reused across tabs
+    last_epoch: u64,
+    tx_cmd: mpsc::UnboundedSender<Cmd>,
+}
 
-    let header_prompt = r#"SYSTEM: You are "OxiAI", a logical, personal assistant that answers *only* via valid, minified, UTF-8 JSON."#;
+impl AppState {
+    /// A fresh session with the built-in system prompt for agent mode, nothing sent yet
+    fn new(
+        args: Args,
+        config: config::Config,
+        sandbox: tool::Sandbox,
+        header_prompt: String,
+        tools_list: String,
+        busy: BusyLot,
+        tx_cmd: mpsc::UnboundedSender<Cmd>,
+    ) -> anyhow::Result<Self> {
+        let options = chat::ChatOptions {
+            temperature: args.temperature,
+            top_p: args.top_p,
+            top_k: args.top_k,
+            repeat_penalty: args.repeat_penalty,
+            seed: args.seed,
+        };
+        let mut app = AppState {
+            args,
+            config,
+            system: Default::default(),
+            mode: chat::Mode::Agent,
+            options,
+            disabled_tools: Default::default(),
+            header_prompt,
+            tools_list,
+            next_turn_note: None,
+            prompt: String::new(),
+            cursor: 0,
+            history: Vec::new(),
+            popup: None,
+            search: None,
+            messages: vec![],
+            next_message_id: 0,
+            undone: Vec::new(),
+            streaming: BTreeMap::new(),
+            waiting: false,
+            busy,
+            turn_started: Instant::now(),
+            queued: Default::default(),
+            next_request_id: 0,
+            requests: BTreeMap::new(),
+            early_replies: BTreeMap::new(),
+            reply_slot: None,
+            dirty: true,
+            started: Instant::now(),
+            should_quit: false,
+            quit_pending: false,
+            status: None,
+            last_exchange: None,
+            corrections: Vec::new(),
+            corrective_retries: 0,
+            tool_hops: 0,
+            reinforced: false,
+            tokens_used: 0,
+            totals: chat::TokenTotals::default(),
+            last_prompt_tokens: None,
+            last_usage: None,
+            model_context: None,
+            last_sent_chars: None,
+            chars_per_token: CHARS_PER_TOKEN,
+            sandbox,
+            pending_tool_result: None,
+            host: String::new(),
+            epoch: 0,
+            connection: ConnectionState::Checking,
+            paid_confirmed: false,
+            color_depth: ui::ColorDepth::detect(),
+            minimal: false,
+            side_panel: false,
+            wrap: true,
+            h_scroll: 0,
+            collapsed: Default::default(),
+            selected: None,
+            inner_scroll: 0,
+            scrollback: None,
+            layout: Default::default(),
+            render_cache: Default::default(),
+            tabs: vec![Tab::new(Default::default(), chat::Mode::Agent, u64::MAX)],
+            active_tab: 0,
+            last_epoch: 0,
+            tx_cmd,
+        };
+        app.host = app.config.host.clone();
+        app.minimal = app.config.minimal_ui;
+        app.system.base = app.get_system_prompt()?;
+        app.system.language = app.config.response_language.clone();
+        Ok(app)
+    }
 
-    let tools_list = include_str!("data/tools_list.json")
-        .parse::<serde_json::Value>()?
-        .to_string();
+    fn get_system_prompt(&self) -> anyhow::Result<String> {
+        if self.mode == chat::Mode::Chat {
+            return Ok(PLAIN_PROMPT.replace("{assistant_name}", &self.config.assistant_name));
+        }
 
-    let rules_prompt = r#"Rules:
-1. Think silently, Never reveal your chain-of-thought.
-2. To use a tool: {"action":"<tool>","arguments":{...}}
-3. To reply directly: {"action":"chat","arguments":{"response":"..."}
-4. If a question is vague, comparative, descriptive, or about ideas rather than specifics: use the web_search tool.
-5. If a question clearly names a specific object, animal, person, place: use the wiki_search tool.
-6. Base claims strictly on provided data or tool results. If unsure, say so.
-7. Check your output; If you reach four consecutive newlines: *stop*"#;
+        let header_prompt = self
+            .header_prompt
+            .replace("{assistant_name}", &self.config.assistant_name);
+        let mut tools_list = self.tools_list.parse::<serde_json::Value>()?;
+        if let Some(tools) = tools_list
+            .get_mut("tools")
+            .and_then(serde_json::Value::as_array_mut)
+        {
+            tools.retain(|tool| {
+                let name = tool["function"]["name"].as_str().unwrap_or_default();
+                !chat::AssistantTool::from_name(name)
+                    .is_some_and(|t| self.disabled_tools.contains(&t))
+            });
+        }
 
-    //let user_info_prompt = r#""#;
-    let system_prompt = format!(
-        "{header_prompt}\n
+        //let user_info_prompt = r#""#;
+        Ok(format!(
+            "{header_prompt}\n
         {tools_list}\n\n
-        {rules_prompt}\n"
-    );
-
-    loop {
-        terminal.draw(|f| ui::chat_ui(f, &app))?;
-
-        if event::poll(Duration::from_millis(100))? {
-            if let Event::Key(key) = event::read()? {
-                match key.code {
-                    KeyCode::Char(c) => app.prompt.push(c),
-                    KeyCode::Backspace => {
-                        app.prompt.pop();
-                    }
-                    KeyCode::Enter => {
-                        //TODO: refactor to a parser function to take the contents of the app.prompt vec and do fancy stuff with it (like commands)
-                        let message_args = args_builder! {
-                            "response" => app.prompt.clone(),
-                        };
-                        app.prompt.clear();
-
-                        app.messages.push(chat::Message::new(
-                            chat::MessageRoles::User,
-                            chat::Action::Chat,
-                            message_args,
-                        ));
-
-                        let mut prompts = vec![chat::Prompt {
-                            role: Cow::Borrowed("system"),
-                            content: Cow::Borrowed(&system_prompt),
-                        }];
-                        prompts.extend(
-                            app.messages
-                                .iter()
-                                .map(|msg| chat::Prompt::from(msg.clone())),
-                        );
-
-                        let req = chat::ChatRequest {
-                            model: &app.args.model.clone(),
-                            stream: app.args.stream,
-                            format: "json",
-                            stop: vec!["\n\n\n\n"],
-                            options: Some(chat::ChatOptions {
-                                temperature: Some(0.3),
-                                top_p: Some(0.92),
-                                top_k: Some(50),
-                                repeat_penalty: Some(1.1),
-                                seed: None,
-                            }),
-                            messages: prompts,
-                        };
+        {RULES_PROMPT}\n"
+        ))
+    }
 
-                        app.waiting = true;
-                        match app.args.stream {
-                            true => {
-                                todo!();
-                                stream_ollama_response(&mut app, client.clone(), req).await?;
-                            }
-                            false => {
-                                batch_ollama_response(&mut app, client.clone(), req).await?;
-                            }
-                        }
-                    }
-                    KeyCode::Esc => {
-                        break;
-                    }
-                    _ => {}
+    fn handle_input(&mut self, event: Event) {
+        let key = match event {
+            Event::Key(key) => key,
+            Event::Mouse(mouse) => {
+                self.handle_mouse(mouse);
+                self.dirty = true;
+                return;
+            }
+            Event::Paste(text) => {
+                self.handle_paste(&text);
+                self.dirty = true;
+                return;
+            }
+            _ => {
+                // resizes and the like still need a new frame
+                self.dirty = true;
+                return;
+            }
+        };
+
+        if let Some(popup) = &mut self.popup {
+            let view = self.layout.borrow().popup_view;
+            if let Some(offset) = view.scroll_key(popup.scroll, key.code) {
+                popup.scroll = offset;
+            } else if matches!(key.code, KeyCode::Esc | KeyCode::Enter) {
+                self.popup = None;
+            }
+            self.dirty = true;
+            return;
+        }
+
+        if self.search.is_some() {
+            self.handle_search_key(key.code, key.modifiers);
+            self.dirty = true;
+            return;
+        }
+
+        if self.quit_pending {
+            self.quit_pending = false;
+            match (key.code, key.modifiers) {
+                (KeyCode::Char('y' | 'Y'), _) | (KeyCode::Char('q'), KeyModifiers::CONTROL) => {
+                    self.should_quit = true;
+                }
+                _ => self.status = None,
+            }
+            self.dirty = true;
+            return;
+        }
+
+        match (key.code, key.modifiers) {
+            (KeyCode::Char('r'), KeyModifiers::CONTROL) => {
+                self.search = Some(input::HistorySearch::default());
+            }
+            (KeyCode::F(2), _) => self.side_panel = !self.side_panel,
+            (KeyCode::F(3), _) => self.minimal = !self.minimal,
+            (KeyCode::F(4), _) => {
+                self.wrap = !self.wrap;
+                self.h_scroll = 0;
+            }
+            // with a message selected the arrows browse the chat rather than edit the prompt
+            (KeyCode::Left, KeyModifiers::NONE) if !self.wrap && self.selected.is_some() => {
+                self.h_scroll = self.h_scroll.saturating_sub(H_SCROLL_STEP);
+            }
+            (KeyCode::Right, KeyModifiers::NONE) if !self.wrap && self.selected.is_some() => {
+                let max = self.layout.borrow().h_scroll_max;
+                self.h_scroll = (self.h_scroll + H_SCROLL_STEP).min(max);
+            }
+            (KeyCode::Up, KeyModifiers::ALT) => {
+                let last = self.messages.len().checked_sub(1);
+                self.select(match self.selected {
+                    Some(idx) => Some(idx.saturating_sub(1)),
+                    None => last,
+                });
+            }
+            (KeyCode::Down, KeyModifiers::ALT) => {
+                // moving down past the newest message drops the selection
+                self.select(
+                    self.selected
+                        .map(|idx| idx + 1)
+                        .filter(|idx| *idx < self.messages.len()),
+                );
+            }
+            (KeyCode::PageUp | KeyCode::PageDown, _) => self.scroll_chat(key.code),
+            // Enter sends, with Shift or Alt it starts a new line instead. Not every terminal
+            // reports Shift+Enter apart from Enter, Alt+Enter works nearly everywhere
+            (KeyCode::Enter, m) if m.intersects(KeyModifiers::SHIFT | KeyModifiers::ALT) => {
+                self.prompt.insert(self.cursor, '\n');
+                self.cursor += 1;
+            }
+            (KeyCode::Up, KeyModifiers::SHIFT) => {
+                self.inner_scroll = self.inner_scroll.saturating_sub(1);
+            }
+            (KeyCode::Down, KeyModifiers::SHIFT) => {
+                let max = self.layout.borrow().inner_scroll_max;
+                self.inner_scroll = (self.inner_scroll + 1).min(max);
+            }
+            (KeyCode::Char('b'), KeyModifiers::CONTROL) => {
+                if let Some(message) = self.selected.and_then(|idx| self.messages.get_mut(idx)) {
+                    message.bookmarked = !message.bookmarked;
+                }
+            }
+            (KeyCode::Char('o'), KeyModifiers::CONTROL) => self.toggle_collapsed(),
+            (KeyCode::Char('t'), KeyModifiers::CONTROL) => self.new_tab(),
+            (KeyCode::Char('w'), KeyModifiers::CONTROL) => self.close_tab(),
+            (KeyCode::Tab, KeyModifiers::CONTROL) => {
+                self.switch_tab((self.active_tab + 1) % self.tabs.len())
+            }
+            (KeyCode::BackTab, m) if m.contains(KeyModifiers::CONTROL) => {
+                self.switch_tab((self.active_tab + self.tabs.len() - 1) % self.tabs.len())
+            }
+            (KeyCode::Char(c @ '1'..='9'), KeyModifiers::ALT) => {
+                self.switch_tab(c as usize - '1' as usize)
+            }
+            (KeyCode::Char('y'), KeyModifiers::CONTROL) => {
+                let idx = self.selected.or_else(|| self.last_reply());
+                match idx {
+                    Some(idx) => self.copy_message(idx),
+                    None => self.set_status("no reply to copy yet"),
+                }
+            }
+            (KeyCode::Char('q'), KeyModifiers::CONTROL) => self.request_quit(),
+            (KeyCode::Esc, _) if self.selected.is_some() => self.select(None),
+            (KeyCode::Esc, _) if self.waiting => self.cancel(),
+            (KeyCode::Char('c'), KeyModifiers::CONTROL) => {
+                if self.waiting {
+                    self.cancel();
                 }
             }
+            _ => self.handle_key(key.code),
         }
+        self.dirty = true;
     }
 
-    disable_raw_mode()?;
-    crossterm::execute!(
-        terminal.backend_mut(),
-        LeaveAlternateScreen,
-        DisableMouseCapture
-    )?;
-    terminal.show_cursor()?;
-    Ok(())
-}
+    fn handle_search_key(&mut self, code: KeyCode, modifiers: KeyModifiers) {
+        let Some(search) = &mut self.search else {
+            return;
+        };
 
-//FIXME: streaming replies are harder to work with for now, save this for the future
-async fn stream_ollama_response(
-    app: &mut App,
-    client: Client,
-    req: chat::ChatRequest<'_>,
-) -> anyhow::Result<()> {
-    let mut resp = client
-        .post("http://localhost:11434/api/chat")
-        .json(&req)
-        .send()
-        .await?
-        .bytes_stream();
+        match (code, modifiers) {
+            (KeyCode::Char('r'), KeyModifiers::CONTROL) => search.older(&self.history),
+            (KeyCode::Char('g'), KeyModifiers::CONTROL) | (KeyCode::Esc, _) => self.search = None,
+            (KeyCode::Char(c), _) => search.push(c),
+            (KeyCode::Backspace, _) => search.pop(),
+            (KeyCode::Enter, _) => {
+                // accepting only fills the input box, it still has to be sent with another Enter
+                if let Some(line) = search.current(&self.history) {
+                    self.prompt = line.to_string();
+                    self.cursor = self.prompt.len();
+                }
+                self.search = None;
+            }
+            _ => {}
+        }
+    }
 
-    //TODO: since we haven't decoded the Steam we don't know if its sent the role part of the message
-    // we'll need to figure out how to 'see the future' so to speak
-    let mut assistant_line = String::from("Assistant : ");
+    /// The selected message if it is a reply, otherwise the latest reply
+    fn outline_target(&self) -> Option<usize> {
+        let assistant = chat::MessageRoles::Assistant;
+        self.selected
+            .filter(|&idx| self.messages[idx].role == assistant)
+            .or_else(|| self.messages.iter().rposition(|m| m.role == assistant))
+    }
 
-    while let Some(chunk) = resp.next().await {
-        let chunk = chunk?;
-        for line in chunk.split(|b| *b == b'\n') {
-            if line.is_empty() {
-                continue;
-            }
-            let parsed: serde_json::Result<chat::StreamChunk> = serde_json::from_slice(line);
-            if let Ok(parsed) = parsed {
-                assistant_line.push_str(&parsed.message.content.to_string());
-            }
+    /// Show a reply's headings, or select it and scroll the nth heading to the top of it
+    fn outline(&mut self, n: Option<usize>) {
+        let Some(idx) = self.outline_target() else {
+            self.set_status("no reply to outline");
+            return;
+        };
+        let message = &self.messages[idx];
+        let text = message.render_text();
+        let headings = chat::outline(&text);
+        if headings.is_empty() {
+            self.set_status("that reply has no headings");
+            return;
         }
+
+        let Some(n) = n else {
+            let list = headings
+                .iter()
+                .enumerate()
+                .map(|(i, h)| {
+                    format!(
+                        "{:indent$}{}. {}",
+                        "",
+                        i + 1,
+                        h.title,
+                        indent = 2 * (h.level - 1)
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+            self.popup = Some(ui::Popup::new(
+                "Outline",
+                format!("/outline <number> jumps to one\n\n{list}"),
+            ));
+            return;
+        };
+        let Some(heading) = headings.get(n - 1) else {
+            self.set_status(format!("there is no heading {n}"));
+            return;
+        };
+
+        // the heading's row is however many rows the label and the text before it wrap to, a
+        // reply's label is on rows of its own
+        let width = {
+            let layout = self.layout.borrow();
+            layout.chat_area.width.saturating_sub(2 * layout.border) as usize
+        };
+        let rows = |text: &str| match self.wrap {
+            true => ui::wrap_text(text, width).len(),
+            false => text.split('\n').count(),
+        };
+        let mark = match message.bookmarked {
+            true => "★ ",
+            false => "",
+        };
+        let before = &text[..heading.offset];
+        let before = before.strip_suffix('\n').unwrap_or(before);
+        let row = rows(&format!("{mark}{}:", self.role_label(message)))
+            + match before.is_empty() {
+                true => 0,
+                false => rows(before),
+            };
+
+        self.select(Some(idx));
+        self.inner_scroll = row;
     }
 
-    //FIXME: fix this later
-    //app.messages.push(assistant_line);
+    /// Indexes of the bookmarked messages, oldest first
+    fn bookmarks(&self) -> impl Iterator<Item = usize> + '_ {
+        self.messages
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| m.bookmarked)
+            .map(|(idx, _)| idx)
+    }
 
-    Ok(())
-}
+    /// Fold or unfold the turn the selected message is part of, leaving its question selected
+    fn toggle_collapsed(&mut self) {
+        let Some(message) = self.selected.and_then(|idx| self.messages.get(idx)) else {
+            return;
+        };
+        let Some(turn) = message
+            .parent_id
+            .or((message.role == chat::MessageRoles::User).then_some(message.id))
+        else {
+            return;
+        };
 
-async fn batch_ollama_response<'a>(
-    app: &mut App,
-    client: Client,
-    req: chat::ChatRequest<'a>,
-) -> anyhow::Result<()> {
-    batch_ollama_response_inner(app, client, req).await
-}
+        if !self.collapsed.remove(&turn) {
+            self.collapsed.insert(turn);
+        }
+        let question = self.messages.iter().position(|m| m.id == turn);
+        self.select(question);
+    }
 
-fn batch_ollama_response_inner<'a>(
-    app: &'a mut App,
-    client: Client,
-    req: chat::ChatRequest<'a>,
-) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>> {
-    Box::pin(async move {
-        let start = Instant::now();
-        let resp = client
-            .post("http://localhost:11434/api/chat")
-            .json(&req)
-            .send()
-            .await?;
-        let elapsed = start.elapsed();
+    /// Whether a message is hidden inside a folded turn
+    fn is_folded(&self, message: &Message) -> bool {
+        message
+            .parent_id
+            .is_some_and(|parent| self.collapsed.contains(&parent))
+    }
 
-        let status = resp.status();
-        let headers = resp.headers().clone();
-        let body_bytes = resp.bytes().await?;
+    fn select(&mut self, selected: Option<usize>) {
+        self.selected = selected;
+        self.inner_scroll = 0;
+        self.layout.borrow_mut().inner_scroll_max = 0;
+    }
 
-        match serde_json::from_slice::<chat::ChatResponse>(&body_bytes) {
-            Ok(r) => {
-                match r.message.content.action {
-                    chat::Action::Chat => app.messages.push(r.message),
-                    chat::Action::Tool(assistant_tool) => {
-                        match assistant_tool {
-                            chat::AssistantTool::WikiSearch => {
-                                //HACK: fake it for now, until I figure out how to grab a web page and display it in a way the model understands
-                                let tool_args = r.message.content.arguments.clone();
-                                app.messages.push(r.message);
-
-                                let search_term = match tool_args.get("query") {
-                                    Some(v) => v.as_str(),
-                                    None => todo!(),
-                                };
-
-                                let tool_response = match search_term {
-                                    "American Crow" => {
-                                        let r = args_builder! {
-                                            "result" => include_str!("data/american_crow_wikipedia.md")
-                                        };
-                                        r
-                                    }
-                                    "Black Bear" => {
-                                        let r = args_builder! {
-                                            "result" => include_str!("data/black_bear_wikipedia.md")
-                                        };
-                                        r
-                                    }
-                                    _ => {
-                                        let r = args_builder! {
-                                            "result" => "Search failed to return any valid data"
-                                        };
-                                        r
-                                    }
-                                };
-
-                                let tool_message = Message::from((
-                                    chat::MessageRoles::Tool,
-                                    Action::Tool(chat::AssistantTool::WikiSearch),
-                                    tool_response,
-                                ));
-                                app.messages.push(tool_message);
-                                //FIXME: model could recurse forever
-                                batch_ollama_response(app, client.clone(), req).await?;
-                            }
-                            chat::AssistantTool::WebSearch => todo!(),
-                            chat::AssistantTool::GetDateTime => todo!(),
-                            chat::AssistantTool::GetDirectoryTree => todo!(),
-                            chat::AssistantTool::GetFileContents => todo!(),
-                            chat::AssistantTool::InvalidTool => todo!(),
-                        }
-                    }
+    fn handle_mouse(&mut self, mouse: MouseEvent) {
+        match mouse.kind {
+            MouseEventKind::Up(MouseButton::Left) => {}
+            MouseEventKind::ScrollUp => return self.scroll_chat(KeyCode::Up),
+            MouseEventKind::ScrollDown => return self.scroll_chat(KeyCode::Down),
+            _ => return,
+        }
+
+        let (input_hit, chat_hit) = {
+            let layout = self.layout.borrow();
+            (
+                layout.input_click(&self.prompt, mouse.column, mouse.row),
+                layout.chat_click(mouse.column, mouse.row),
+            )
+        };
+
+        if let Some(cursor) = input_hit {
+            self.cursor = cursor;
+        } else if let Some(idx) = chat_hit {
+            // clicking the selected message again lets go of it
+            self.select((self.selected != Some(idx)).then_some(idx));
+        }
+    }
+
+    /// Put pasted text in the prompt at the cursor, newlines and all, without sending anything
+    fn handle_paste(&mut self, text: &str) {
+        if self.popup.is_some() || self.search.is_some() || self.quit_pending {
+            return;
+        }
+        // terminals hand pasted line breaks over as carriage returns more often than not
+        let text = text.replace("\r\n", "\n").replace('\r', "\n");
+        self.prompt.insert_str(self.cursor, &text);
+        self.cursor += text.len();
+    }
+
+    /// Scroll the chat pane back through the history, reaching the bottom follows new messages again
+    fn scroll_chat(&mut self, code: KeyCode) {
+        let (view, current) = {
+            let layout = self.layout.borrow();
+            (layout.chat_view, layout.chat_scroll)
+        };
+        let Some(offset) = view.scroll_key(current, code) else {
+            return;
+        };
+        // scrolling takes over from a selection that was holding the view in place
+        self.select(None);
+        self.scrollback = (offset < view.max_offset).then_some(offset);
+    }
+
+    fn handle_key(&mut self, code: KeyCode) {
+        match code {
+            KeyCode::Char(c) => {
+                self.prompt.insert(self.cursor, c);
+                self.cursor += c.len_utf8();
+            }
+            KeyCode::Backspace => {
+                if let Some(idx) = input::prev_boundary(&self.prompt, self.cursor) {
+                    self.prompt.replace_range(idx..self.cursor, "");
+                    self.cursor = idx;
                 }
             }
-            Err(e) => {
-                println!("Failed to parse JSON: {}", e);
-                println!("Status: {}", status);
-                println!("Headers: {:#?}", headers);
-                // Try to print the body as text for debugging
-                if let Ok(body_text) = std::str::from_utf8(&body_bytes) {
-                    println!("Body text: {}", body_text);
-                } else {
-                    println!("Body was not valid UTF-8");
+            KeyCode::Left => {
+                if let Some(idx) = input::prev_boundary(&self.prompt, self.cursor) {
+                    self.cursor = idx;
                 }
             }
-        }
+            KeyCode::Right => {
+                if let Some(idx) = input::next_boundary(&self.prompt, self.cursor) {
+                    self.cursor = idx;
+                }
+            }
+            KeyCode::Delete => {
+                if let Some(idx) = input::next_boundary(&self.prompt, self.cursor) {
+                    self.prompt.replace_range(self.cursor..idx, "");
+                }
+            }
+            KeyCode::Home => self.cursor = input::line_start(&self.prompt, self.cursor),
+            KeyCode::End => self.cursor = input::line_end(&self.prompt, self.cursor),
+            KeyCode::Enter => {
+                if !self.prompt.is_empty() && self.history.last() != Some(&self.prompt) {
+                    self.history.push(self.prompt.clone());
+                }
 
-        app.waiting = false;
-        Ok(())
-    })
+                if let Some(command) = chat::parse_command(&self.prompt) {
+                    self.prompt.clear();
+                    self.cursor = 0;
+                    self.run_command(command);
+                    return;
+                }
+
+                if self.budget_exhausted() {
+                    // keep what they typed so it can be sent after a reset
+                    self.set_status(format!(
+                        "token budget of {} used up, /budget reset to keep going",
+                        self.config.token_budget.unwrap_or_default()
+                    ));
+                    return;
+                }
+
+                if self.config.confirm_paid
+                    && !self.paid_confirmed
+                    && self.config.is_paid(&self.host)
+                {
+                    // the line stays put, a second Enter sends it
+                    self.paid_confirmed = true;
+                    self.set_status(format!(
+                        "{} at {} is a paid backend, press Enter again to send",
+                        self.config.backend.as_str(),
+                        self.host
+                    ));
+                    return;
+                }
+
+                if self.waiting {
+                    match self.config.busy_enter {
+                        config::BusyEnter::Ignore => {
+                            self.set_status("still waiting on a reply");
+                        }
+                        config::BusyEnter::Queue => {
+                            self.queued.push_back(std::mem::take(&mut self.prompt));
+                            self.cursor = 0;
+                        }
+                    }
+                    return;
+                }
+
+                let line = std::mem::take(&mut self.prompt);
+                self.cursor = 0;
+                self.submit(line);
+            }
+            // a request in this tab gets cancelled before this, one in a background tab may still be going
+            KeyCode::Esc => self.request_quit(),
+            _ => {}
+        }
+    }
+
+    /// Quit, asking first while any tab still has a reply on its way
+    fn request_quit(&mut self) {
+        match self.any_waiting() && self.config.confirm_quit {
+            true => {
+                self.quit_pending = true;
+                self.set_status("a request is in progress, quit anyway? (y/n)");
+            }
+            false => self.should_quit = true,
+        }
+    }
+
+    /// Start a new turn with `line` as the user's message
+    fn submit(&mut self, line: String) {
+        let message_args = args_builder! {
+            "response" => line,
+        };
+        // a new turn gets a fresh set of corrections, and only this turn's nudge
+        self.undone.clear();
+        self.reset_turn();
+        self.system.turn = self.next_turn_note.take();
+
+        self.push_message(chat::Message::new(
+            chat::MessageRoles::User,
+            chat::Action::Chat,
+            message_args,
+        ));
+
+        self.send_chat();
+    }
+
+    /// Per-turn bookkeeping back to where a turn starts from
+    fn reset_turn(&mut self) {
+        self.corrections.clear();
+        self.corrective_retries = 0;
+        self.tool_hops = 0;
+        self.reinforced = false;
+        self.turn_started = Instant::now();
+    }
+
+    /// Drop everything after the last user message and send the conversation up to it again
+    fn retry(&mut self) {
+        let Some(last) = self
+            .messages
+            .iter()
+            .rposition(|m| m.role == chat::MessageRoles::User)
+        else {
+            self.set_status("nothing to retry");
+            return;
+        };
+
+        let dropped = self.messages.len() - (last + 1);
+        self.messages.truncate(last + 1);
+        if self.selected.is_some_and(|idx| idx > last) {
+            self.select(None);
+        }
+        self.reset_turn();
+        self.set_status(format!("retrying, dropped {dropped} messages"));
+        self.send_chat();
+    }
+
+    fn run_command(&mut self, command: chat::Command) {
+        match command {
+            chat::Command::Dump(path) => {
+                let status = match self.dump_exchange(&path) {
+                    Ok(()) => format!("wrote last exchange to {}", path.display()),
+                    Err(e) => format!("dump failed: {e}"),
+                };
+                self.set_status(status);
+            }
+            chat::Command::Save(path) => {
+                let status = match self.conversation().save(&path) {
+                    Ok(()) => format!("saved conversation to {}", path.display()),
+                    Err(e) => format!("save failed: {e}"),
+                };
+                self.set_status(status);
+            }
+            chat::Command::Export(path) => {
+                let status = match self
+                    .conversation()
+                    .export(&path, &self.config.assistant_name)
+                {
+                    Ok(()) => format!("exported the conversation to {}", path.display()),
+                    Err(e) => format!("export failed: {e}"),
+                };
+                self.set_status(status);
+            }
+            chat::Command::Load(_) if self.waiting => {
+                self.set_status("wait for the reply, or cancel it, first");
+            }
+            chat::Command::Load(path) => {
+                let status = match self.load(&path) {
+                    Ok(count) => format!("loaded {count} messages from {}", path.display()),
+                    Err(e) => format!("load failed: {e}"),
+                };
+                self.set_status(status);
+            }
+            chat::Command::Tree { off: true } => {
+                self.system.project = None;
+                self.set_status("project tree dropped from the context");
+            }
+            chat::Command::Tree { off: false } => {
+                let status = match self.refresh_project_tree() {
+                    Ok(()) => "project tree added to the context".to_string(),
+                    Err(e) => format!("unable to list the project: {e}"),
+                };
+                self.set_status(status);
+            }
+            chat::Command::Host(None) => self.set_status(format!("host is {}", self.host)),
+            chat::Command::Host(Some(host)) => self.switch_host(host),
+            chat::Command::Budget { reset } => {
+                if reset {
+                    self.tokens_used = 0;
+                }
+                let status = match self.budget_remaining() {
+                    Some(left) => format!("{left} of {} tokens left", self.tokens_used + left),
+                    None => format!("{} tokens used, no budget set", self.tokens_used),
+                };
+                self.set_status(status);
+            }
+            chat::Command::Context => {
+                self.popup = Some(ui::Popup::new("Context", self.context_preview()));
+            }
+            chat::Command::Cache { clear } => {
+                let stats = {
+                    let mut cache = self.render_cache.borrow_mut();
+                    if clear {
+                        cache.clear();
+                    }
+                    cache.stats()
+                };
+                self.set_status(format!("render cache, {stats}"));
+            }
+            chat::Command::Undo | chat::Command::Redo if self.waiting => {
+                self.set_status("wait for the reply, or cancel it, first");
+            }
+            chat::Command::Undo => {
+                let start = self
+                    .messages
+                    .iter()
+                    .rposition(|m| m.role == chat::MessageRoles::User);
+                match start {
+                    Some(start) => {
+                        let turn = self.messages.split_off(start);
+                        self.set_status(format!("took back {} messages", turn.len()));
+                        self.undone.push(turn);
+                        if self.selected.is_some_and(|idx| idx >= start) {
+                            self.select(None);
+                        }
+                    }
+                    None => self.set_status("nothing to undo"),
+                }
+            }
+            chat::Command::Redo => match self.undone.pop() {
+                Some(turn) => {
+                    self.set_status(format!("brought back {} messages", turn.len()));
+                    self.messages.extend(turn);
+                }
+                None => self.set_status("nothing to redo"),
+            },
+            chat::Command::Bookmarks(None) => {
+                let list = self
+                    .bookmarks()
+                    .enumerate()
+                    .map(|(n, idx)| {
+                        let m = &self.messages[idx];
+                        format!("{}) {}: {}", n + 1, self.role_label(m), m.render_text())
+                    })
+                    .collect::<Vec<_>>();
+                match list.is_empty() {
+                    true => self.set_status("no bookmarks, select a message and press Ctrl+B"),
+                    false => {
+                        let body =
+                            format!("/bookmarks <number> jumps to one\n\n{}", list.join("\n\n"));
+                        self.popup = Some(ui::Popup::new("Bookmarks", body));
+                    }
+                }
+            }
+            chat::Command::Outline(n) => self.outline(n),
+            chat::Command::Bookmarks(Some(n)) => {
+                let found = self.bookmarks().nth(n - 1);
+                match found {
+                    // selecting a message is what scrolls it into view
+                    Some(idx) => self.select(Some(idx)),
+                    None => self.set_status(format!("there is no bookmark {n}")),
+                }
+            }
+            chat::Command::Copy(None) => match self.last_reply() {
+                Some(idx) => self.copy_message(idx),
+                None => self.set_status("no reply to copy yet"),
+            },
+            chat::Command::Copy(Some(n)) => match n <= self.messages.len() {
+                true => self.copy_message(n - 1),
+                false => self.set_status(format!("there is no message {n}")),
+            },
+            chat::Command::Show(model) => {
+                let model = model.unwrap_or_else(|| self.config.model.clone());
+                self.set_status(format!("fetching details for {model}..."));
+                let request = self.take_request_id();
+                let _ = self.tx_cmd.send(Cmd::Show {
+                    epoch: self.epoch,
+                    request,
+                    host: self.host.clone(),
+                    model,
+                });
+            }
+            chat::Command::Mode(None) => self.set_status(format!("in {} mode", self.mode)),
+            chat::Command::Mode(Some(mode)) => {
+                self.mode = mode;
+                let status = match self.get_system_prompt() {
+                    Ok(prompt) => {
+                        self.system.base = prompt;
+                        format!("switched to {mode} mode")
+                    }
+                    Err(e) => format!("switched to {mode} mode, but the system prompt failed: {e}"),
+                };
+                self.set_status(status);
+            }
+            chat::Command::ImportMerge(path) => {
+                let status = match self.import_merge(&path) {
+                    Ok(status) => status,
+                    Err(e) => format!("import failed: {e}"),
+                };
+                self.set_status(status);
+            }
+            chat::Command::Note(note) => {
+                self.set_status(match &note {
+                    Some(_) => "system note set for this conversation".to_string(),
+                    None => "system note cleared".to_string(),
+                });
+                self.system.conversation = note;
+            }
+            chat::Command::Nudge(note) => {
+                self.set_status(match &note {
+                    Some(_) => "system note set for the next turn".to_string(),
+                    None => "next turn's system note cleared".to_string(),
+                });
+                self.next_turn_note = note;
+            }
+            chat::Command::Clear | chat::Command::Retry if self.waiting => {
+                self.set_status("wait for the reply, or cancel it, first");
+            }
+            chat::Command::Clear => {
+                self.messages.clear();
+                self.undone.clear();
+                self.collapsed.clear();
+                self.select(None);
+                self.scrollback = None;
+                self.set_status("conversation cleared");
+            }
+            chat::Command::Retry => self.retry(),
+            chat::Command::Model(None) => {
+                self.set_status(format!("model is {}", self.config.model))
+            }
+            chat::Command::Model(Some(model)) => {
+                self.config.model = model;
+                // the context size belongs to the old model, the probe fetches the new one's
+                self.model_context = None;
+                self.probe_host();
+                self.set_status(format!("switched model to {}", self.config.model));
+            }
+            chat::Command::System(Some(prompt)) => {
+                self.system.base = prompt;
+                self.set_status("system prompt replaced for this session");
+            }
+            chat::Command::System(None) => {
+                let status = match self.get_system_prompt() {
+                    Ok(prompt) => {
+                        self.system.base = prompt;
+                        "system prompt back to the built-in one".to_string()
+                    }
+                    Err(e) => format!("unable to build the system prompt: {e}"),
+                };
+                self.set_status(status);
+            }
+            chat::Command::Tools(None) => {
+                let body = chat::AssistantTool::ALL
+                    .iter()
+                    .map(|tool| {
+                        let state = match self.disabled_tools.contains(tool) {
+                            true => "disabled",
+                            false => "enabled",
+                        };
+                        format!("{tool}: {state}")
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                let body = format!("/tools enable|disable <name> turns one on or off\n\n{body}");
+                self.popup = Some(ui::Popup::new("Tools", body));
+            }
+            chat::Command::Tools(Some((on, tool))) => {
+                let status = match self.set_tool_enabled(tool, on) {
+                    Ok(()) if on => format!("{tool} enabled"),
+                    Ok(()) => format!("{tool} disabled"),
+                    Err(e) => format!("unable to build the system prompt: {e}"),
+                };
+                self.set_status(status);
+            }
+            chat::Command::Set(None) => self.set_status(self.options.to_string()),
+            chat::Command::Set(Some((field, value))) => {
+                let status = match self.options.set(&field, &value) {
+                    Ok(()) if value != "none" && self.config.backend.drops_option(&field) => {
+                        format!(
+                            "{field} set to {value}, but the {} backend has no such option and ignores it",
+                            self.config.backend.as_str()
+                        )
+                    }
+                    Ok(()) => format!("{field} set to {value}"),
+                    Err(e) => e,
+                };
+                self.set_status(status);
+            }
+            chat::Command::Prune if self.waiting => {
+                self.set_status("wait for the reply, or cancel it, first");
+            }
+            chat::Command::Prune => {
+                let status = match self.context_limit() {
+                    Some(limit) => {
+                        let budget = (limit as f64 * CONTEXT_WARN_RATIO) as u64;
+                        let dropped = self.prune(budget);
+                        let estimate = self.estimate_tokens(&self.chat_request());
+                        format!(
+                            "dropped {dropped} messages, the prompt is about {estimate}/{limit} tokens"
+                        )
+                    }
+                    None => "the context window isn't known yet, set --context-size".to_string(),
+                };
+                self.set_status(status);
+            }
+            chat::Command::Quit => self.should_quit = true,
+            chat::Command::Invalid(reason) => self.set_status(reason),
+        }
+    }
+
+    /// The conversation as /save writes it, errors are only ever shown so they are left out
+    fn conversation(&self) -> chat::Conversation {
+        chat::Conversation {
+            system: Some(self.system.base.clone()),
+            messages: self
+                .messages
+                .iter()
+                .filter(|m| m.has_known_role())
+                .cloned()
+                .collect(),
+            totals: self.totals,
+        }
+    }
+
+    /// Swap the conversation for a saved one, nothing changes if it can't be read
+    fn load(&mut self, path: &std::path::Path) -> anyhow::Result<usize> {
+        let conversation = chat::Conversation::load(path)?;
+        self.messages.clear();
+        self.undone.clear();
+        self.collapsed.clear();
+        self.select(None);
+        self.scrollback = None;
+
+        let count = conversation.messages.len();
+        for message in conversation.messages {
+            self.push_message(message);
+        }
+        self.totals = conversation.totals;
+        Ok(count)
+    }
+
+    /// Write the conversation to `--session`, without the system prompt as that is rebuilt
+    /// at every start anyway
+    fn save_session(&self) -> anyhow::Result<()> {
+        let Some(path) = &self.args.session else {
+            return Ok(());
+        };
+        let conversation = chat::Conversation {
+            system: None,
+            ..self.conversation()
+        };
+        conversation.save(path)
+    }
+
+    /// Append the messages of a saved conversation, each gets a fresh id so none collide
+    fn import_merge(&mut self, path: &std::path::Path) -> anyhow::Result<String> {
+        let conversation = chat::Conversation::load(path)?;
+        let count = conversation.messages.len();
+        let differs = conversation
+            .system
+            .is_some_and(|system| system != self.system.base);
+
+        // a taken back turn would come back after the merged messages, out of order
+        self.undone.clear();
+        for message in conversation.messages {
+            self.push_message(message);
+        }
+        self.totals += conversation.totals;
+
+        let mut status = format!("merged {count} messages from {}", path.display());
+        if differs {
+            status.push_str(", warning: it was held under a different system prompt");
+        }
+        Ok(status)
+    }
+
+    fn refresh_project_tree(&mut self) -> Result<(), String> {
+        let tree = tool::dir_tree(&self.sandbox, PROJECT_TREE_DEPTH, PROJECT_TREE_ENTRIES)?;
+        self.system.project = Some(tree);
+        Ok(())
+    }
+
+    fn switch_host(&mut self, host: String) {
+        // whatever was in flight belonged to the old host
+        self.abort_in_flight();
+        self.host = host;
+        self.paid_confirmed = false;
+        self.probe_host();
+        self.set_status(format!("switched host to {}", self.host));
+    }
+
+    /// Give up on a turn that has gone on too long, even one still making progress. What has
+    /// streamed in so far is kept as the answer.
+    fn check_turn_deadline(&mut self, now: Instant) {
+        let Some(limit) = self.config.turn_timeout_secs else {
+            return;
+        };
+        if !self.waiting || now.duration_since(self.turn_started) < Duration::from_secs(limit) {
+            return;
+        }
+
+        let partial = std::mem::take(&mut self.streaming)
+            .into_values()
+            .collect::<Vec<_>>()
+            .join("");
+        self.abort_in_flight();
+        if !partial.is_empty() {
+            self.push_raw_reply(partial);
+        }
+        self.set_status(format!("turn timed out after {limit}s, kept what arrived"));
+        self.dirty = true;
+    }
+
+    /// Stop the current request, including any generation still running server side
+    fn cancel(&mut self) {
+        self.abort_in_flight();
+        // marks where the answer would have gone, like any error it is never sent or saved
+        self.push_error("cancelled".to_string());
+        self.set_status("request cancelled");
+    }
+
+    fn abort_in_flight(&mut self) {
+        let _ = self.tx_cmd.send(Cmd::Cancel { epoch: self.epoch });
+        self.epoch = self.take_epoch();
+        self.waiting = false;
+        self.streaming.clear();
+        self.requests.clear();
+        self.early_replies.clear();
+        self.queued.clear();
+        self.pending_tool_result = None;
+        self.corrections.clear();
+        // nothing more is coming for whatever was still on its way
+        for message in &mut self.messages {
+            if message.status == chat::SendStatus::Pending {
+                message.status = chat::SendStatus::Failed;
+            }
+        }
+    }
+
+    /// Turn a tool on or off and rebuild the system prompt of every tab still on the built-in one,
+    /// a prompt replaced with /system is left as it is
+    fn set_tool_enabled(&mut self, tool: chat::AssistantTool, on: bool) -> anyhow::Result<()> {
+        // which tabs are on the built-in prompt has to be known before it changes
+        let built_in = (0..self.tabs.len())
+            .map(|idx| self.in_tab(idx, |app| Ok(app.get_system_prompt()? == app.system.base)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        match on {
+            true => self.disabled_tools.remove(&tool),
+            false => self.disabled_tools.insert(tool),
+        };
+        for (idx, _) in built_in
+            .iter()
+            .enumerate()
+            .filter(|(_, built_in)| **built_in)
+        {
+            self.in_tab(idx, |app| {
+                app.system.base = app.get_system_prompt()?;
+                anyhow::Ok(())
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Run `f` with the tab in slot `idx` live, swapped back out again afterwards
+    fn in_tab<T>(&mut self, idx: usize, f: impl FnOnce(&mut Self) -> T) -> T {
+        if idx == self.active_tab {
+            return f(self);
+        }
+        self.swap_tab(idx);
+        let result = f(self);
+        self.swap_tab(idx);
+        result
+    }
+
+    fn take_epoch(&mut self) -> u64 {
+        self.last_epoch += 1;
+        self.last_epoch
+    }
+
+    /// Trade the live conversation for the one parked in tab slot `idx`
+    fn swap_tab(&mut self, idx: usize) {
+        macro_rules! swap {
+            ($live:expr, $tab:expr; $($field:ident),*) => {
+                $(std::mem::swap(&mut $live.$field, &mut $tab.$field);)*
+            };
+        }
+        let tab = &mut self.tabs[idx];
+        swap!(self, tab; system, mode, next_turn_note, prompt, cursor, messages, undone, streaming, waiting, turn_started, queued, requests, early_replies, reply_slot, corrections, corrective_retries, tool_hops, reinforced, totals, pending_tool_result, epoch, h_scroll, collapsed, selected, inner_scroll, scrollback, last_sent_chars);
+    }
+
+    fn switch_tab(&mut self, idx: usize) {
+        if idx == self.active_tab || idx >= self.tabs.len() {
+            return;
+        }
+        // the live tab goes back to its slot and takes the stand-in out, which goes to `idx`
+        self.swap_tab(self.active_tab);
+        self.swap_tab(idx);
+        self.active_tab = idx;
+        self.layout.borrow_mut().inner_scroll_max = 0;
+    }
+
+    /// Open an empty conversation next to the others and switch to it
+    fn new_tab(&mut self) {
+        let system = chat::SystemLayers {
+            base: self
+                .get_system_prompt()
+                .unwrap_or_else(|_| self.system.base.clone()),
+            project: self.system.project.clone(),
+            language: self.system.language.clone(),
+            ..Default::default()
+        };
+        let epoch = self.take_epoch();
+        self.tabs.push(Tab::new(system, self.mode, epoch));
+        self.switch_tab(self.tabs.len() - 1);
+        self.set_status(format!("opened tab {}", self.active_tab + 1));
+    }
+
+    /// Close the active tab, dropping whatever it still had in flight
+    fn close_tab(&mut self) {
+        if self.tabs.len() == 1 {
+            self.set_status("this is the only tab");
+            return;
+        }
+        let _ = self.tx_cmd.send(Cmd::Cancel { epoch: self.epoch });
+        let closing = self.active_tab;
+        let next = match closing + 1 < self.tabs.len() {
+            true => closing + 1,
+            false => closing - 1,
+        };
+        self.switch_tab(next);
+        self.tabs.remove(closing);
+        if next > closing {
+            self.active_tab -= 1;
+        }
+        self.set_status(format!("closed tab {}", closing + 1));
+    }
+
+    /// Whether this tab or any in the background is waiting on a reply
+    fn any_waiting(&self) -> bool {
+        self.waiting
+            || self
+                .tabs
+                .iter()
+                .enumerate()
+                .any(|(idx, tab)| idx != self.active_tab && tab.waiting)
+    }
+
+    /// Name of the tab in slot `idx` for the tab bar, after its first question
+    fn tab_title(&self, idx: usize) -> String {
+        let (messages, waiting) = match idx == self.active_tab {
+            true => (&self.messages, self.waiting),
+            false => (&self.tabs[idx].messages, self.tabs[idx].waiting),
+        };
+        let first = messages
+            .iter()
+            .find(|m| m.role == chat::MessageRoles::User)
+            .map(|m| m.render_text().lines().next().unwrap_or("").to_string());
+        let mut title = match first {
+            Some(text) if text.chars().count() > TAB_TITLE_WIDTH => {
+                let cut: String = text.chars().take(TAB_TITLE_WIDTH - 1).collect();
+                format!("{cut}…")
+            }
+            Some(text) => text,
+            None => "new chat".to_string(),
+        };
+        if waiting {
+            title.push_str(" *");
+        }
+        format!("{} {title}", idx + 1)
+    }
+
+    fn probe_host(&mut self) {
+        self.connection = ConnectionState::Checking;
+        let request = self.take_request_id();
+        let _ = self.tx_cmd.send(Cmd::Probe {
+            epoch: self.epoch,
+            request,
+            host: self.host.clone(),
+            model: self.config.model.clone(),
+        });
+    }
+
+    /// Tokens left before sending is blocked, `None` if there is no budget
+    fn budget_remaining(&self) -> Option<u64> {
+        self.config
+            .token_budget
+            .map(|budget| budget.saturating_sub(self.tokens_used))
+    }
+
+    fn budget_exhausted(&self) -> bool {
+        self.budget_remaining() == Some(0)
+    }
+
+    fn record_usage(&mut self, usage: chat::Usage) {
+        self.tokens_used += usage.completion_tokens;
+        self.totals.add(usage);
+        self.last_prompt_tokens = usage.prompt_tokens.or(self.last_prompt_tokens);
+        if let (Some(tokens), Some(chars)) = (usage.prompt_tokens, self.last_sent_chars.take())
+            && tokens > 0
+        {
+            self.chars_per_token = (chars as f64 / tokens as f64).clamp(1.0, 8.0);
+        }
+        // a cached prompt reports no count, the last one known still holds
+        self.last_usage = Some(chat::Usage {
+            prompt_tokens: self.last_prompt_tokens,
+            ..usage
+        });
+
+        // Ollama quietly cuts the front off prompts that don't fit, so say so before that happens
+        let limit = self.context_limit();
+        if let (Some(prompt), Some(limit)) = (usage.prompt_tokens, limit)
+            && prompt as f64 >= limit as f64 * CONTEXT_WARN_RATIO
+        {
+            self.set_status(format!(
+                "context near capacity ({prompt}/{limit} tokens), consider /clear"
+            ));
+        }
+    }
+
+    /// Context window prompts have to fit, `None` until the model's is known
+    fn context_limit(&self) -> Option<u64> {
+        self.config.context_size.or(self.model_context)
+    }
+
+    /// Rough token count of a request before it is sent, calibrated by the host's own counts
+    fn estimate_tokens(&self, req: &chat::ChatRequest<'_>) -> u64 {
+        (chat::prompt_chars(&req.messages) as f64 / self.chars_per_token).ceil() as u64
+    }
+
+    /// Drop the oldest turns until the next prompt is estimated at `budget` tokens or less. The
+    /// latest turn always stays, returns how many messages went.
+    fn prune(&mut self, budget: u64) -> usize {
+        let user = chat::MessageRoles::User;
+        let mut dropped = 0;
+        while self.estimate_tokens(&self.chat_request()) > budget {
+            // the oldest turn ends where the second user message starts
+            let Some(end) = self
+                .messages
+                .iter()
+                .enumerate()
+                .filter(|(_, m)| m.role == user)
+                .nth(1)
+                .map(|(idx, _)| idx)
+            else {
+                break;
+            };
+            self.messages.drain(..end);
+            dropped += end;
+        }
+        if let Some(idx) = self.selected {
+            self.select(idx.checked_sub(dropped));
+        }
+        dropped
+    }
+
+    /// Bytes sent and received by the last request and the prompt tokens it cost, shown with
+    /// `--nerd-stats` so it is plain when tool output is bloating the context
+    fn nerd_stats(&self) -> Option<String> {
+        if !self.args.nerd_stats {
+            return None;
+        }
+        let exchange = self.last_exchange.as_ref()?;
+        let mut stats = format!(
+            "sent {}, received {}",
+            ui::human_bytes(exchange.request_body.len()),
+            ui::human_bytes(exchange.response_body.len())
+        );
+        if let Some(usage) = &self.last_usage {
+            stats.push_str(&format!(", {}", usage.stats()));
+        }
+        Some(stats)
+    }
+
+    /// Token count of the conversation, with what it cost if the backend has a price
+    fn totals_text(&self) -> Option<String> {
+        let total = self.totals.total();
+        if total == 0 {
+            return None;
+        }
+        Some(match &self.config.token_price {
+            Some(price) => format!("{total} tokens (~${:.4})", price.cost(&self.totals)),
+            None => format!("{total} tokens"),
+        })
+    }
+
+    fn dump_exchange(&self, path: &std::path::Path) -> anyhow::Result<()> {
+        let exchange = self
+            .last_exchange
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("nothing has been sent yet"))?;
+
+        std::fs::write(path, exchange.render(&self.config.secrets()))?;
+        Ok(())
+    }
+
+    fn take_request_id(&mut self) -> u64 {
+        self.next_request_id += 1;
+        self.next_request_id
+    }
+
+    /// Index the next message will be inserted at
+    fn insertion_point(&self) -> usize {
+        self.reply_slot.unwrap_or(self.messages.len())
+    }
+
+    /// Add a message to the conversation, at the reply slot if one is being filled
+    fn push_message(&mut self, mut message: Message) {
+        self.next_message_id += 1;
+        message.id = self.next_message_id;
+
+        let idx = self.insertion_point();
+        // everything that isn't the user's belongs to the turn of the user message before it
+        let user = chat::MessageRoles::User;
+        message.parent_id = match message.role == user {
+            true => None,
+            false => self.messages[..idx]
+                .iter()
+                .rfind(|m| m.role == user)
+                .map(|m| m.id),
+        };
+        self.messages.insert(idx, message);
+
+        if let Some(slot) = &mut self.reply_slot {
+            *slot += 1;
+        }
+        // keep the same message selected when one lands above it
+        if let Some(selected) = &mut self.selected
+            && *selected >= idx
+        {
+            *selected += 1;
+        }
+    }
+
+    /// Build a request from the conversation up to the insertion point and hand it to the workers
+    fn send_chat(&mut self) {
+        let mut req = self.chat_request();
+
+        if let Some(limit) = self.context_limit() {
+            let budget = (limit as f64 * CONTEXT_WARN_RATIO) as u64;
+            let estimate = self.estimate_tokens(&req);
+            // only between turns, mid-turn the messages being answered must stay where they are
+            let can_prune =
+                self.config.auto_prune && self.reply_slot.is_none() && self.requests.is_empty();
+            if estimate > budget && can_prune {
+                let dropped = self.prune(budget);
+                req = self.chat_request();
+                self.set_status(format!(
+                    "dropped the {dropped} oldest messages to fit the context window"
+                ));
+            } else if estimate > budget {
+                self.set_status(format!(
+                    "prompt is about {estimate}/{limit} tokens, /prune drops the oldest turns"
+                ));
+            }
+        }
+
+        self.last_sent_chars = Some(chat::prompt_chars(&req.messages));
+        self.run_request(RequestKind::Chat, req);
+    }
+
+    /// The request the next turn would send, from the conversation up to the insertion point
+    fn chat_request(&self) -> chat::ChatRequest<'static> {
+        let mut prompts: Vec<_> = self.system.prompts().collect();
+        let error = chat::MessageRoles::Error;
+        let history = self.messages[..self.insertion_point()]
+            .iter()
+            .filter(|m| m.role != error);
+        match self.mode {
+            chat::Mode::Agent => prompts.extend(history.map(|msg| chat::Prompt::from(msg.clone()))),
+            // plain chat has no packets, only the text is sent
+            chat::Mode::Chat => prompts.extend(history.map(|msg| chat::Prompt {
+                role: Cow::Owned(msg.role.to_string()),
+                content: Cow::Owned(msg.render_text().into_owned()),
+            })),
+        }
+        prompts.extend(self.corrections.iter().cloned());
+
+        engine::chat_request(
+            &self.config.model,
+            self.mode,
+            self.args.stream,
+            self.options.clone(),
+            prompts,
+        )
+    }
+
+    /// Everything `chat_request` would send, laid out for reading
+    fn context_preview(&self) -> String {
+        let req = self.chat_request();
+        let mut preview = format!(
+            "model: {}, {} messages, format: {}\n",
+            req.model,
+            req.messages.len(),
+            req.format.unwrap_or("none")
+        );
+        for prompt in &req.messages {
+            preview.push_str(&format!("\n--- {} ---\n{}\n", prompt.role, prompt.content));
+        }
+        preview
+    }
+
+    fn run_request(&mut self, kind: RequestKind, req: chat::ChatRequest<'static>) {
+        self.waiting = true;
+
+        let request = self.take_request_id();
+        let parent = self.messages[..self.insertion_point()].last().map(|m| m.id);
+        self.requests.insert(request, parent);
+        self.set_send_status(parent, chat::SendStatus::Pending);
+
+        // the worker only goes away when we are shutting down
+        let _ = self.tx_cmd.send(Cmd::RunChat {
+            epoch: self.epoch,
+            request,
+            host: self.host.clone(),
+            kind,
+            req,
+        });
+    }
+
+    fn handle_msg(&mut self, envelope: Envelope) {
+        if envelope.epoch != self.epoch {
+            // a reply for a background tab is handled with that tab swapped in for the time
+            let owner = self.tabs.iter().position(|tab| tab.epoch == envelope.epoch);
+            if let Some(idx) = owner
+                && idx != self.active_tab
+            {
+                self.in_tab(idx, |app| app.handle_msg_live(envelope));
+                self.dirty = true;
+            }
+            // anything else is from a host we've since switched away from, or was cancelled
+            return;
+        }
+        self.handle_msg_live(envelope);
+    }
+
+    /// Handle `first` and whatever else is already queued behind it. Every message is handled,
+    /// only the redraw they ask for is shared, so a flood of deltas costs one frame.
+    fn handle_msgs(&mut self, first: Envelope, rx: &mut mpsc::UnboundedReceiver<Envelope>) {
+        self.handle_msg(first);
+        while let Ok(msg) = rx.try_recv() {
+            self.handle_msg(msg);
+        }
+    }
+
+    /// Handle a message for the conversation that is live right now
+    fn handle_msg_live(&mut self, envelope: Envelope) {
+        let request = envelope.request;
+        match envelope.msg {
+            Msg::Exchange(exchange) => self.last_exchange = Some(exchange),
+            msg @ (Msg::HttpDone(..) | Msg::StreamDone(_) | Msg::ToolDone(..)) => {
+                self.complete(request, msg)
+            }
+            Msg::StreamDelta(delta) => self.streaming.entry(request).or_default().push_str(&delta),
+            Msg::Usage(usage) => self.record_usage(usage),
+            Msg::Retrying(attempt, max) => {
+                self.set_status(format!("host didn't answer, retrying ({attempt}/{max})..."))
+            }
+            Msg::StreamStalled(true) => self.set_status("stream stalled..."),
+            Msg::StreamStalled(false) => self.status = None,
+            Msg::Probe(Ok(version)) => self.connection = ConnectionState::Connected(version),
+            Msg::Probe(Err(e)) => {
+                log!(Warn, "host {} unreachable: {e}", self.host);
+                self.connection = ConnectionState::Unreachable(e);
+            }
+            Msg::Show(model, Ok(show)) => {
+                self.status = None;
+                self.popup = Some(ui::Popup::new(model.clone(), show.render(&model)));
+            }
+            Msg::Show(model, Err(e)) => self.set_status(format!("unable to show {model}: {e}")),
+            Msg::ModelContext(num_ctx) => {
+                self.model_context = Some(num_ctx.unwrap_or(DEFAULT_NUM_CTX))
+            }
+        }
+        self.dirty = true;
+    }
+
+    /// Put finished replies in place in the order their requests were sent, whatever order they
+    /// actually finish in
+    fn complete(&mut self, request: u64, msg: Msg) {
+        if let Some((&first, _)) = self.requests.first_key_value()
+            && first != request
+        {
+            self.early_replies.insert(request, msg);
+            return;
+        }
+
+        self.commit_reply(request, msg);
+        while let Some((&next, _)) = self.requests.first_key_value()
+            && let Some(msg) = self.early_replies.remove(&next)
+        {
+            self.commit_reply(next, msg);
+        }
+        self.waiting = !self.requests.is_empty();
+
+        if !self.waiting
+            && !self.budget_exhausted()
+            && let Some(line) = self.queued.pop_front()
+        {
+            self.submit(line);
+        }
+    }
+
+    /// Handle one finished reply, anything it adds lands right after the message it answers
+    fn commit_reply(&mut self, request: u64, msg: Msg) {
+        let parent = self.requests.remove(&request).flatten();
+        let delivered = matches!(
+            msg,
+            Msg::HttpDone(_, Ok(_)) | Msg::StreamDone(Ok(StreamEnd::Finished))
+        );
+        self.set_send_status(
+            parent,
+            match delivered {
+                true => chat::SendStatus::Sent,
+                false => chat::SendStatus::Failed,
+            },
+        );
+        self.reply_slot = parent
+            .and_then(|id| self.messages.iter().position(|m| m.id == id))
+            .map(|idx| idx + 1);
+
+        match msg {
+            Msg::HttpDone(RequestKind::Chat, result) => self.handle_http_done(result),
+            Msg::HttpDone(RequestKind::Summarize(tool), result) => {
+                self.handle_summary_done(tool, result)
+            }
+            Msg::StreamDone(result) => self.handle_stream_done(request, result),
+            Msg::ToolDone(tool, result) => self.finish_tool(tool, tool_result(tool, result)),
+            _ => {}
+        }
+        self.reply_slot = None;
+    }
+
+    /// Mark whether the user message with `id` got through, other messages aren't tracked
+    fn set_send_status(&mut self, id: Option<u64>, status: chat::SendStatus) {
+        let user = chat::MessageRoles::User;
+        if let Some(message) = self
+            .messages
+            .iter_mut()
+            .find(|m| Some(m.id) == id && m.role == user)
+        {
+            message.status = status;
+        }
+    }
+
+    /// What went wrong with a request, a timeout says how long it was waited on
+    fn request_error(&self, what: &str, e: &reqwest::Error) -> String {
+        if !e.is_timeout() {
+            return format!("{what} failed: {e}");
+        }
+        match &self.last_exchange {
+            Some(exchange) => format!(
+                "{what} timed out after {:.1}s, nothing heard from the host for {}s",
+                exchange.elapsed.as_secs_f64(),
+                self.config.request_timeout_secs
+            ),
+            None => format!(
+                "{what} timed out, nothing heard from the host for {}s",
+                self.config.request_timeout_secs
+            ),
+        }
+    }
+
+    fn handle_http_done(&mut self, result: Result<bytes::Bytes, reqwest::Error>) {
+        let body_bytes = match result {
+            Ok(b) => b,
+            Err(e) => {
+                self.push_error(self.request_error("request", &e));
+                return;
+            }
+        };
+
+        match self.config.backend.parse_reply(&body_bytes) {
+            Ok(r) => {
+                if let Some(usage) = r.usage() {
+                    self.record_usage(usage);
+                }
+                self.handle_reply_content(r.message.content)
+            }
+            Err(e) => {
+                // the start of the body is usually enough to tell what came back instead
+                let body = match std::str::from_utf8(&body_bytes) {
+                    Ok(text) => tool::truncate_result(text, ERROR_BODY_LIMIT).into_owned(),
+                    Err(_) => "body was not valid UTF-8".to_string(),
+                };
+                self.push_error(format!("unable to parse the reply ({e}): {body}"));
+            }
+        }
+    }
+
+    /// Put an error in the chat where it can't be missed, it is never sent to the model
+    fn push_error(&mut self, text: String) {
+        log!(Error, "{text}");
+        self.push_message(Message::new(
+            chat::MessageRoles::Error,
+            Action::Chat,
+            args_builder! {
+                "response" => text,
+            },
+        ));
+    }
+
+    fn handle_summary_done(
+        &mut self,
+        tool: chat::AssistantTool,
+        result: Result<bytes::Bytes, reqwest::Error>,
+    ) {
+        let summary = result
+            .map_err(anyhow::Error::from)
+            .and_then(|body| self.config.backend.parse_reply(&body))
+            .map(|r| {
+                if let Some(usage) = r.usage() {
+                    self.record_usage(usage);
+                }
+                r.message.content
+            });
+
+        match summary {
+            Ok(summary) if !summary.trim().is_empty() => {
+                self.push_tool_result(tool, format!("[summarized] {}", summary.trim()));
+                self.send_chat();
+            }
+            _ => {
+                self.set_status("tool result summary failed, truncating instead");
+                if let Some(result) = self.pending_tool_result.take() {
+                    let truncated = tool::truncate_result(&result, self.config.tool_result_limit);
+                    self.push_tool_result(tool, truncated.into_owned());
+                    self.send_chat();
+                }
+            }
+        }
+        self.pending_tool_result = None;
+    }
+
+    /// Hand a tool's result back to the model, shrinking it first if it is over the limit
+    fn finish_tool(&mut self, tool: chat::AssistantTool, result: String) {
+        log!(Debug, "tool {tool} returned: {result}");
+        let limit = self.config.tool_result_limit;
+        if result.chars().count() <= limit {
+            self.push_tool_result(tool, result);
+            self.send_chat();
+            return;
+        }
+
+        match self.config.tool_result_overflow {
+            config::ToolOverflow::Truncate => {
+                let truncated = tool::truncate_result(&result, limit).into_owned();
+                self.push_tool_result(tool, truncated);
+                self.send_chat();
+            }
+            config::ToolOverflow::Summarize => {
+                let question = self
+                    .messages
+                    .iter()
+                    .rev()
+                    .find(|m| m.role == chat::MessageRoles::User)
+                    .and_then(|m| m.content.response())
+                    .map(str::to_string)
+                    .unwrap_or_default();
+
+                let model = match &self.config.assist_models.summarize {
+                    Some(model) => model,
+                    None => &self.config.model,
+                };
+                let req = tool::summary_request(model, &result, &question, limit);
+                self.pending_tool_result = Some(result);
+                self.set_status(format!("summarizing {tool} result..."));
+                self.run_request(RequestKind::Summarize(tool), req);
+            }
+        }
+    }
+
+    fn push_tool_result(&mut self, tool: chat::AssistantTool, result: String) {
+        self.push_message(Message::from((
+            chat::MessageRoles::Tool,
+            Action::Tool(tool),
+            args_builder! {
+                "result" => result,
+            },
+        )));
+    }
+
+    fn handle_stream_done(&mut self, request: u64, result: Result<StreamEnd, reqwest::Error>) {
+        let content = self.streaming.remove(&request).unwrap_or_default();
+
+        match result {
+            Err(e) => self.push_error(self.request_error("stream", &e)),
+            Ok(StreamEnd::TimedOut) => {
+                self.set_status("stream stalled out, kept what arrived");
+                // there is no more coming, so keep the partial as is rather than ask for a fix
+                let packet = chat::validate_envelope(&content)
+                    .ok()
+                    .or_else(|| chat::salvage_envelope(&content));
+                match packet {
+                    Some(packet) => self.handle_reply(Message {
+                        id: 0,
+                        role: chat::MessageRoles::Assistant,
+                        content: packet,
+                        bookmarked: false,
+                        parent_id: None,
+                        status: chat::SendStatus::Sent,
+                    }),
+                    None if content.is_empty() => {}
+                    None => self.push_raw_reply(content),
+                }
+                return;
+            }
+            Ok(StreamEnd::Finished) => {}
+        }
+        if content.is_empty() {
+            return;
+        }
+
+        // partial JSON never parses, so the packet is only decoded once the whole reply is in
+        self.handle_reply_content(content);
+    }
+
+    /// Check a finished reply follows the action contract, asking the model to fix it if it doesn't
+    fn handle_reply_content(&mut self, content: String) {
+        if self.mode == chat::Mode::Chat {
+            self.push_raw_reply(content);
+            return;
+        }
+
+        match chat::validate_envelope(&content) {
+            Ok(packet) => {
+                self.corrections.clear();
+                self.handle_reply(Message {
+                    id: 0,
+                    role: chat::MessageRoles::Assistant,
+                    content: packet,
+                    bookmarked: false,
+                    parent_id: None,
+                    status: chat::SendStatus::Sent,
+                });
+            }
+            Err(_) if let Some(packet) = chat::salvage_envelope(&content) => {
+                // the model misbehaved, but what it meant is there, so no round trip to fix it
+                log!(Warn, "reply had prose around its JSON: {content}");
+                self.corrections.clear();
+                self.set_status("reply had prose around its JSON, used the object inside");
+                self.handle_reply(Message {
+                    id: 0,
+                    role: chat::MessageRoles::Assistant,
+                    content: packet,
+                    bookmarked: false,
+                    parent_id: None,
+                    status: chat::SendStatus::Sent,
+                });
+            }
+            Err(_)
+                if self.config.reinforce_json && !self.reinforced && chat::is_prose(&content) =>
+            {
+                // doesn't count against the corrections, those still follow if this doesn't work
+                log!(Warn, "reply wasn't JSON: {content}");
+                self.reinforced = true;
+                self.set_status("reply wasn't JSON, asking again more firmly");
+                self.corrections.push(chat::Prompt {
+                    role: Cow::Owned(chat::MessageRoles::Assistant.to_string()),
+                    content: Cow::Owned(content),
+                });
+                self.corrections.push(chat::Prompt {
+                    role: Cow::Owned(chat::MessageRoles::System.to_string()),
+                    content: Cow::Borrowed(chat::REINFORCE_PROMPT),
+                });
+                self.send_chat();
+            }
+            Err(problem) if self.corrective_retries < MAX_CORRECTIONS => {
+                log!(Warn, "reply broke the format ({problem}): {content}");
+                self.corrective_retries += 1;
+                self.set_status(format!(
+                    "reply broke the format ({problem}), asking again ({}/{MAX_CORRECTIONS})",
+                    self.corrective_retries
+                ));
+                self.corrections.push(chat::Prompt {
+                    role: Cow::Owned(chat::MessageRoles::Assistant.to_string()),
+                    content: Cow::Owned(content),
+                });
+                self.corrections.push(chat::Prompt {
+                    role: Cow::Owned(chat::MessageRoles::System.to_string()),
+                    content: Cow::Owned(chat::correction_prompt(&problem)),
+                });
+                self.send_chat();
+            }
+            Err(problem) => {
+                // out of retries, show whatever the model said rather than nothing
+                log!(
+                    Warn,
+                    "reply still broke the format ({problem}), shown as plain text"
+                );
+                self.corrections.clear();
+                self.set_status(format!("reply still broke the format: {problem}"));
+                self.push_raw_reply(content);
+            }
+        }
+    }
+
+    /// Show a reply that isn't a valid packet as plain chat text
+    fn push_raw_reply(&mut self, content: String) {
+        self.push_message(Message::new(
+            chat::MessageRoles::Assistant,
+            Action::Chat,
+            args_builder! {
+                "response" => content,
+            },
+        ));
+    }
+
+    fn handle_reply(&mut self, message: Message) {
+        let chat::Action::Tool(assistant_tool) = message.content.action else {
+            self.push_message(message);
+            return;
+        };
+
+        // the call is shown as it came, the result follows it as a tool message
+        let args = message.content.arguments.clone();
+        self.push_message(message);
+
+        if self.tool_hops > MAX_TOOL_HOPS {
+            // it was already told to answer without tools, asking again won't go any better
+            self.push_error(format!(
+                "model kept calling tools after {MAX_TOOL_HOPS}, stopped the turn"
+            ));
+            self.set_status(format!(
+                "model kept calling tools, stopped after {MAX_TOOL_HOPS}"
+            ));
+            return;
+        }
+        if self.tool_hops == MAX_TOOL_HOPS {
+            self.tool_hops += 1;
+            self.push_tool_result(
+                assistant_tool,
+                format!(
+                    "error: stopped after {MAX_TOOL_HOPS} tool calls in one turn, no more tools \
+                    this turn. Answer now with what you have, as a chat action."
+                ),
+            );
+            self.set_status(format!(
+                "model kept calling tools, asked it to answer after {MAX_TOOL_HOPS}"
+            ));
+            self.send_chat();
+            return;
+        }
+        self.tool_hops += 1;
+        log!(Info, "tool {assistant_tool} called with {args:?}");
+
+        if self.disabled_tools.contains(&assistant_tool) {
+            self.finish_tool(
+                assistant_tool,
+                format!("error: the {assistant_tool} tool is disabled, answer without it"),
+            );
+            return;
+        }
+
+        if assistant_tool == chat::AssistantTool::WebSearch && self.config.search_url.is_some() {
+            match tool::check_args(assistant_tool, &args) {
+                Ok(()) => self.run_search(args["query"].clone()),
+                Err(e) => self.finish_tool(assistant_tool, tool_result(assistant_tool, Err(e))),
+            }
+            return;
+        }
+
+        let result = tool::execute(&self.sandbox, &self.config, assistant_tool, &args);
+        self.finish_tool(assistant_tool, tool_result(assistant_tool, result));
+    }
+
+    /// Hand a `web_search` to the worker, its result comes back in order like a reply would
+    fn run_search(&mut self, query: String) {
+        self.waiting = true;
+        self.set_status(format!("searching the web for {query:?}..."));
+
+        let request = self.take_request_id();
+        let parent = self.messages[..self.insertion_point()].last().map(|m| m.id);
+        self.requests.insert(request, parent);
+
+        let _ = self.tx_cmd.send(Cmd::Search {
+            epoch: self.epoch,
+            request,
+            query,
+        });
+    }
+
+    /// Index of the newest assistant chat reply
+    fn last_reply(&self) -> Option<usize> {
+        self.messages.iter().rposition(|m| {
+            m.role == chat::MessageRoles::Assistant && m.content.action == Action::Chat
+        })
+    }
+
+    /// Put a message's text, not its packet, on the clipboard
+    fn copy_message(&mut self, idx: usize) {
+        let text = self.messages[idx].render_text().into_owned();
+        let status = match ui::copy_to_clipboard(&text) {
+            Ok(()) => format!("copied {} characters", text.chars().count()),
+            Err(e) => format!("copy failed: {e}"),
+        };
+        self.set_status(status);
+    }
+
+    /// Put a line in the status bar, stamped with the time so it can be matched up afterwards
+    fn set_status(&mut self, text: impl Into<String>) {
+        self.status = Some(Status {
+            text: text.into(),
+            at: chrono::Local::now(),
+        });
+    }
+
+    /// Take the status line down once it has been up for `STATUS_TTL`. Progress notes stay while
+    /// a request is in flight, and so does the quit question until it is answered.
+    fn expire_status(&mut self, now: chrono::DateTime<chrono::Local>) {
+        let expired = self.status.as_ref().is_some_and(|status| {
+            (now - status.at)
+                .to_std()
+                .is_ok_and(|age| age >= STATUS_TTL)
+        });
+        if expired && !self.waiting && !self.quit_pending {
+            self.status = None;
+            self.dirty = true;
+        }
+    }
+
+    /// Tick fast while anything is changing on screen, slowly when there is nothing to draw
+    fn tick_rate(&self) -> Duration {
+        match self.dirty || self.waiting || self.busy.is_busy() || self.caret_animating() {
+            true => BUSY_TICK,
+            false => IDLE_TICK,
+        }
+    }
+
+    fn caret_animating(&self) -> bool {
+        !self.streaming.is_empty()
+            && self.config.stream_cursor_blink_ms > 0
+            && self.config.stream_cursor != config::StreamCursor::None
+    }
+
+    /// Label shown in front of a message in the chat pane, the assistant goes by its configured name
+    fn role_label<'a>(&'a self, message: &'a Message) -> &'a str {
+        match message.role {
+            chat::MessageRoles::Assistant => &self.config.assistant_name,
+            ref role => role.as_str(),
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    // parse arguments
+    let args = match Args::try_parse() {
+        Ok(args) => args,
+        Err(e) => {
+            // with stdout gone (`oxiai --help | head -0`) there is nowhere to say anything, the
+            // exit code still tells what happened
+            let _ = e.print();
+            // --help and --version come through here too, only they are a success
+            let code = match e.kind() {
+                clap::error::ErrorKind::DisplayHelp | clap::error::ErrorKind::DisplayVersion => 0,
+                _ => 2,
+            };
+            std::process::exit(code);
+        }
+    };
+
+    if let Some(path) = &args.log_file
+        && let Err(e) = logging::init(path, args.log_level)
+    {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+
+    if let Some(CliCommand::Doctor) = args.command {
+        std::process::exit(
+            doctor::run(
+                args.config.as_deref(),
+                args.host.as_deref(),
+                args.model.as_deref(),
+            )
+            .await,
+        );
+    }
+
+    if args.config.is_none() && !args.no_wizard && wizard::needed() {
+        let stdin = std::io::stdin();
+        if let Err(e) = wizard::run(&mut stdin.lock(), &mut std::io::stdout()).await {
+            eprintln!("setup wizard failed, carrying on with the defaults: {e}");
+        }
+    }
+
+    let mut config = match config::Config::load(args.config.as_deref()) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+    if let Some(model) = &args.model {
+        config.model = model.clone();
+    }
+    if let Some(host) = &args.host {
+        config.host = host.clone();
+    }
+    if let Some(backend) = args.backend {
+        config.backend = backend;
+    }
+    if let Some(workdir) = &args.workdir {
+        config.workdir = Some(workdir.clone());
+    }
+    if let Some(retries) = args.max_retries {
+        config.max_retries = retries;
+    }
+    if let Some(size) = args.context_size {
+        config.context_size = Some(size);
+    }
+    if args.auto_prune {
+        config.auto_prune = true;
+    }
+    if let Some(secs) = args.timeout {
+        config.request_timeout_secs = secs;
+    }
+    if let Some(url) = &args.search_url {
+        config.search_url = Some(url.clone());
+    }
+    if let Some(key) = &args.search_key {
+        config.search_key = Some(key.clone());
+    }
+    if let Some(script) = &args.mock {
+        match mock::start(script).await {
+            Ok(host) => {
+                config.host = host;
+                config.backend = backend::Backend::Ollama;
+            }
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+    }
+    // a bad host in the config gets the same treatment as a bad --host
+    if let Err(e) = parse_host(&config.host) {
+        use clap::CommandFactory;
+        Args::command()
+            .error(
+                clap::error::ErrorKind::ValueValidation,
+                format!("config host `{}`: {e}", config.host),
+            )
+            .exit();
+    }
+
+    let workdir = match &config.workdir {
+        Some(dir) => dir.clone(),
+        None => std::env::current_dir()?,
+    };
+    let sandbox = match tool::Sandbox::new(&workdir) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!(
+                "unable to use {} as the working directory: {e}",
+                workdir.display()
+            );
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(CliCommand::Serve { stdio }) = args.command {
+        if !stdio {
+            eprintln!("serve needs a transport, try `oxiai serve --stdio`");
+            std::process::exit(2);
+        }
+        return serve::run(config, sandbox).await;
+    }
+
+    if let Some(CliCommand::Replay {
+        conversation,
+        out,
+        compare,
+    }) = &args.command
+    {
+        return replay::run(&config, conversation, out.as_deref(), *compare).await;
+    }
+
+    // a prompt file that can't be used is not worth refusing to start over
+    let mut warnings = Vec::new();
+    let mut section = |path: Option<&std::path::Path>, default: &str, json: bool| {
+        let Some(path) = path else {
+            return default.to_string();
+        };
+        match read_prompt_section(path, json) {
+            Ok(text) => text,
+            Err(e) => {
+                let warning = format!("{e}, using the built-in one");
+                eprintln!("warning: {warning}");
+                warnings.push(warning);
+                default.to_string()
+            }
+        }
+    };
+    let header_prompt = section(args.system_file.as_deref(), HEADER_PROMPT, false);
+    let tools_list = section(args.tools_file.as_deref(), TOOLS_LIST, true);
+
+    let (tx_cmd, rx_cmd) = mpsc::unbounded_channel();
+    let (tx_msg, mut rx_msg) = mpsc::unbounded_channel();
+    let engine = engine::Engine::new(&config)?;
+    let search = search::Provider::from_config(engine.client(), &config);
+    let busy = BusyLot::default();
+    tokio::spawn(run_workers(rx_cmd, tx_msg, engine, search, busy.clone()));
+
+    let mut terminal = match OxiTerminal::setup() {
+        Ok(terminal) => terminal,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+
+    let mut app = AppState::new(
+        args,
+        config,
+        sandbox,
+        header_prompt,
+        tools_list,
+        busy,
+        tx_cmd,
+    )?;
+    // the file tools can read anything under the root, so make sure it is the one intended
+    app.set_status(format!(
+        "file tools are confined to {}",
+        app.sandbox.root().display()
+    ));
+    if let Some(warning) = warnings.pop() {
+        app.set_status(warning);
+    }
+    if app.args.project_tree
+        && let Err(e) = app.refresh_project_tree()
+    {
+        app.set_status(format!("unable to list the project: {e}"));
+    }
+    if let Some(path) = app.args.session.clone()
+        && path.exists()
+        && let Err(e) = app.load(&path)
+    {
+        // starting over beats not starting, but the file is left alone in case it can be saved
+        app.args.session = None;
+        app.set_status(format!(
+            "session not restored, {e}, it won't be overwritten on quit"
+        ));
+    }
+    app.probe_host();
+
+    let mut events = EventStream::new();
+    let mut tick_rate = app.tick_rate();
+    let mut ticker = new_ticker(tick_rate);
+
+    while !app.should_quit {
+        tokio::select! {
+            _ = ticker.tick() => {
+                app.check_turn_deadline(Instant::now());
+                app.expire_status(chrono::Local::now());
+                // a blinking caret or the spinner changes with nothing else happening
+                if app.dirty || app.busy.is_busy() || app.caret_animating() {
+                    terminal.draw(|f| ui::chat_ui(f, &app))?;
+                    ui::write_links(terminal.backend_mut(), &app.layout.borrow().links)?;
+                    app.dirty = false;
+                }
+            }
+            Some(event) = events.next() => app.handle_input(event?),
+            Some(msg) = rx_msg.recv() => app.handle_msgs(msg, &mut rx_msg),
+        }
+
+        let wanted = app.tick_rate();
+        if wanted != tick_rate {
+            tick_rate = wanted;
+            ticker = new_ticker(tick_rate);
+        }
+    }
+
+    // hand the terminal back first so anything printed below lands on the normal screen
+    drop(terminal);
+
+    if let Err(e) = app.save_session() {
+        eprintln!("unable to save the session: {e}");
+    }
+    if let Some(path) = &app.args.export_on_quit
+        && let Err(e) = app.conversation().export(path, &app.config.assistant_name)
+    {
+        eprintln!("unable to export the conversation: {e}");
+    }
+    Ok(())
+}
+
+/// The terminal while the UI has it. Dropping it hands the terminal back, so an error or a panic
+/// on the way out doesn't leave raw mode and the alternate screen behind.
+struct OxiTerminal(Terminal<CrosstermBackend<std::io::Stdout>>);
+
+impl OxiTerminal {
+    fn setup() -> anyhow::Result<Self> {
+        use std::io::IsTerminal;
+
+        if !std::io::stdout().is_terminal() {
+            anyhow::bail!(
+                "stdout is not a terminal, the chat UI needs one (`oxiai serve --stdio` works without)"
+            );
+        }
+        let terminal = Terminal::new(CrosstermBackend::new(std::io::stdout()))?;
+        enable_raw_mode()?;
+        // from here on a failure still gets cleaned up, by the drop
+        let mut terminal = OxiTerminal(terminal);
+        crossterm::execute!(
+            terminal.backend_mut(),
+            EnterAlternateScreen,
+            EnableMouseCapture,
+            EnableBracketedPaste
+        )?;
+        Ok(terminal)
+    }
+}
+
+impl std::ops::Deref for OxiTerminal {
+    type Target = Terminal<CrosstermBackend<std::io::Stdout>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl std::ops::DerefMut for OxiTerminal {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl Drop for OxiTerminal {
+    fn drop(&mut self) {
+        // best effort, there is nowhere left to report a failure to
+        let _ = disable_raw_mode();
+        let _ = crossterm::execute!(
+            self.0.backend_mut(),
+            LeaveAlternateScreen,
+            DisableMouseCapture,
+            DisableBracketedPaste
+        );
+        let _ = self.0.show_cursor();
+    }
+}
+
+/// Ticker whose first tick is one period out, so switching rates never draws twice in a row
+fn new_ticker(period: Duration) -> tokio::time::Interval {
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    // if we fall behind just skip the frames, the state they would have shown is already stale
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    ticker
+}
+
+async fn run_workers(
+    mut rx_cmd: mpsc::UnboundedReceiver<Cmd>,
+    tx_msg: mpsc::UnboundedSender<Envelope>,
+    engine: engine::Engine,
+    search: Option<search::Provider>,
+    busy: BusyLot,
+) {
+    let engine = std::sync::Arc::new(engine);
+    let search = search.map(std::sync::Arc::new);
+    // each with the epoch it was started under, so cancelling one tab leaves the others be
+    let mut in_flight: Vec<(u64, tokio::task::AbortHandle)> = Vec::new();
+
+    while let Some(cmd) = rx_cmd.recv().await {
+        in_flight.retain(|(_, h)| !h.is_finished());
+
+        match cmd {
+            Cmd::RunChat {
+                epoch,
+                request,
+                host,
+                kind,
+                req,
+            } => {
+                let ticket = busy.park();
+                let call = chat_call(
+                    engine.clone(),
+                    host,
+                    kind,
+                    req,
+                    Outbox::new(epoch, request, &tx_msg),
+                );
+                let handle = tokio::spawn(async move {
+                    let _ticket = ticket;
+                    call.await
+                });
+                in_flight.push((epoch, handle.abort_handle()));
+            }
+            Cmd::Probe {
+                epoch,
+                request,
+                host,
+                model,
+            } => {
+                tokio::spawn(probe_host(
+                    engine.clone(),
+                    host,
+                    model,
+                    Outbox::new(epoch, request, &tx_msg),
+                ));
+            }
+            Cmd::Show {
+                epoch,
+                request,
+                host,
+                model,
+            } => {
+                tokio::spawn(show_model(
+                    engine.clone(),
+                    host,
+                    model,
+                    Outbox::new(epoch, request, &tx_msg),
+                ));
+            }
+            Cmd::Search {
+                epoch,
+                request,
+                query,
+            } => {
+                let outbox = Outbox::new(epoch, request, &tx_msg);
+                let search = search.clone();
+                let ticket = busy.park();
+                let handle = tokio::spawn(async move {
+                    let _ticket = ticket;
+                    let result = match search {
+                        Some(search) => search.run(&query).await,
+                        None => Err("web_search has no search provider set up".to_string()),
+                    };
+                    outbox.send(Msg::ToolDone(chat::AssistantTool::WebSearch, result));
+                });
+                in_flight.push((epoch, handle.abort_handle()));
+            }
+            Cmd::Cancel { epoch } => in_flight.retain(|(started, handle)| {
+                if *started == epoch {
+                    handle.abort();
+                }
+                *started != epoch
+            }),
+        }
+    }
+}
+
+/// Count of worker tasks in flight, each holds a `Ticket` for as long as it runs. An aborted
+/// task drops its ticket along with everything else, so the count can't be left stuck.
+#[derive(Clone, Default)]
+struct BusyLot(std::sync::Arc<std::sync::atomic::AtomicUsize>);
+
+impl BusyLot {
+    fn park(&self) -> Ticket {
+        self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        Ticket(self.clone())
+    }
+
+    fn is_busy(&self) -> bool {
+        self.0.load(std::sync::atomic::Ordering::Relaxed) > 0
+    }
+}
+
+struct Ticket(BusyLot);
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        self.0.0.fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+/// Sending half of the msg queue for one request, tags everything with the request's epoch and id
+struct Outbox {
+    epoch: u64,
+    request: u64,
+    tx_msg: mpsc::UnboundedSender<Envelope>,
+}
+
+impl Outbox {
+    fn new(epoch: u64, request: u64, tx_msg: &mpsc::UnboundedSender<Envelope>) -> Self {
+        Self {
+            epoch,
+            request,
+            tx_msg: tx_msg.clone(),
+        }
+    }
+
+    fn send(&self, msg: Msg) {
+        // the UI only drops its receiver when we are shutting down
+        let _ = self.tx_msg.send(Envelope {
+            epoch: self.epoch,
+            request: self.request,
+            msg,
+        });
+    }
+}
+
+impl engine::Events for Outbox {
+    fn emit(&self, event: engine::Event) {
+        self.send(match event {
+            engine::Event::Exchange(exchange) => Msg::Exchange(exchange),
+            engine::Event::Delta(delta) => Msg::StreamDelta(delta),
+            engine::Event::Usage(usage) => Msg::Usage(usage),
+            engine::Event::Retrying(attempt, max) => Msg::Retrying(attempt, max),
+            engine::Event::Stalled(stalled) => Msg::StreamStalled(stalled),
+        });
+    }
+}
+
+async fn probe_host(
+    engine: std::sync::Arc<engine::Engine>,
+    host: String,
+    model: String,
+    outbox: Outbox,
+) {
+    let result = engine.probe(&host).await;
+    let reachable = result.is_ok();
+    outbox.send(Msg::Probe(result.map_err(|e| e.to_string())));
+
+    // a model the host doesn't have gets no context size, the first request will say why
+    if reachable
+        && engine.backend() == backend::Backend::Ollama
+        && let Ok(show) = chat::fetch_show(engine.client(), &host, &model, PROBE_TIMEOUT).await
+    {
+        outbox.send(Msg::ModelContext(show.num_ctx()));
+    }
+}
+
+async fn show_model(
+    engine: std::sync::Arc<engine::Engine>,
+    host: String,
+    model: String,
+    outbox: Outbox,
+) {
+    let result = chat::fetch_show(engine.client(), &host, &model, PROBE_TIMEOUT).await;
+    outbox.send(Msg::Show(model, result.map_err(|e| e.to_string())));
+}
+
+async fn chat_call(
+    engine: std::sync::Arc<engine::Engine>,
+    host: String,
+    kind: RequestKind,
+    req: chat::ChatRequest<'static>,
+    outbox: Outbox,
+) {
+    let done = match engine.chat(&host, &req, &outbox).await {
+        engine::Done::Whole(result) => Msg::HttpDone(kind, result),
+        engine::Done::Streamed(result) => Msg::StreamDone(result),
+    };
+    outbox.send(done);
+}
+
+/// What goes back to the model for a tool run, a failure is still an answer it can work with
+fn tool_result(tool: chat::AssistantTool, result: Result<String, String>) -> String {
+    result.unwrap_or_else(|e| {
+        log!(Warn, "tool {tool} failed: {e}");
+        format!("error: {e}")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A session on `config` with no workers behind it, what it asks of them lands in the receiver
+    pub(crate) fn app_with(config: config::Config) -> (AppState, mpsc::UnboundedReceiver<Cmd>) {
+        let (tx_cmd, rx_cmd) = mpsc::unbounded_channel();
+        let args = Args::try_parse_from(["oxiai"]).unwrap();
+        let sandbox = tool::Sandbox::new(&std::env::temp_dir()).unwrap();
+        let app = AppState::new(
+            args,
+            config,
+            sandbox,
+            HEADER_PROMPT.to_string(),
+            TOOLS_LIST.to_string(),
+            BusyLot::default(),
+            tx_cmd,
+        )
+        .unwrap();
+        (app, rx_cmd)
+    }
+
+    /// Type `line` into the input box and press Enter
+    fn enter(app: &mut AppState, line: &str) {
+        app.prompt = line.to_string();
+        app.cursor = line.len();
+        app.handle_key(KeyCode::Enter);
+    }
+
+    /// Chat requests handed to the workers since the last call, as (epoch, request id, request)
+    fn chats(rx: &mut mpsc::UnboundedReceiver<Cmd>) -> Vec<(u64, u64, chat::ChatRequest<'static>)> {
+        let mut sent = Vec::new();
+        while let Ok(cmd) = rx.try_recv() {
+            if let Cmd::RunChat {
+                epoch,
+                request,
+                req,
+                ..
+            } = cmd
+            {
+                sent.push((epoch, request, req));
+            }
+        }
+        sent
+    }
+
+    /// A whole reply with `content` arriving for `request`, as the backend sends it
+    fn reply(app: &mut AppState, epoch: u64, request: u64, content: &str) {
+        let body = match app.config.backend {
+            backend::Backend::Ollama => serde_json::json!({
+                "message": { "role": "assistant", "content": content },
+                "done": true,
+            }),
+            backend::Backend::OpenAi => serde_json::json!({
+                "choices": [{ "message": { "role": "assistant", "content": content } }],
+            }),
+        };
+        app.handle_msg(Envelope {
+            epoch,
+            request,
+            msg: Msg::HttpDone(RequestKind::Chat, Ok(body.to_string().into())),
+        });
+    }
+
+    fn status(app: &AppState) -> &str {
+        app.status.as_ref().map_or("", |s| s.text.as_str())
+    }
+
+    #[test]
+    fn a_spent_budget_blocks_sending_until_reset() {
+        let (mut app, mut rx) = app_with(config::Config {
+            token_budget: Some(100),
+            ..Default::default()
+        });
+        app.record_usage(chat::Usage {
+            completion_tokens: 120,
+            ..Default::default()
+        });
+
+        enter(&mut app, "still there?");
+        assert!(chats(&mut rx).is_empty());
+        assert_eq!(app.prompt, "still there?");
+        assert!(status(&app).starts_with("token budget of 100 used up"));
+
+        enter(&mut app, "/budget reset");
+        assert_eq!(status(&app), "100 of 100 tokens left");
+        enter(&mut app, "still there?");
+        assert_eq!(chats(&mut rx).len(), 1);
+    }
+
+    /// A chat action packet replying with `text`
+    fn packet(text: &str) -> String {
+        serde_json::json!({ "action": "chat", "arguments": { "response": text } }).to_string()
+    }
+
+    #[test]
+    fn only_the_first_send_to_a_paid_backend_asks() {
+        let (mut app, mut rx) = app_with(config::Config {
+            backend: backend::Backend::OpenAi,
+            ..Default::default()
+        });
+
+        enter(&mut app, "hello");
+        assert!(chats(&mut rx).is_empty());
+        assert!(status(&app).contains("is a paid backend, press Enter again"));
+
+        app.handle_key(KeyCode::Enter);
+        let sent = chats(&mut rx);
+        assert_eq!(sent.len(), 1);
+        let (epoch, request, _) = &sent[0];
+        reply(&mut app, *epoch, *request, &packet("hi"));
+        assert!(!app.waiting);
+
+        enter(&mut app, "and again");
+        assert_eq!(chats(&mut rx).len(), 1);
+    }
+
+    #[test]
+    fn enter_while_waiting_never_sends_twice() {
+        let (mut app, mut rx) = app_with(config::Config::default());
+        enter(&mut app, "first");
+        enter(&mut app, "second");
+        assert_eq!(chats(&mut rx).len(), 1);
+        assert_eq!(app.prompt, "second");
+        assert_eq!(status(&app), "still waiting on a reply");
+    }
+
+    #[test]
+    fn enter_while_waiting_can_queue_the_line_instead() {
+        let (mut app, mut rx) = app_with(config::Config {
+            busy_enter: config::BusyEnter::Queue,
+            ..Default::default()
+        });
+        enter(&mut app, "first");
+        enter(&mut app, "second");
+        let sent = chats(&mut rx);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(app.queued, ["second"]);
+
+        // the queued line only goes out once the reply to the first is in
+        let (epoch, request, _) = &sent[0];
+        reply(&mut app, *epoch, *request, &packet("done"));
+        assert_eq!(chats(&mut rx).len(), 1);
+        assert!(app.queued.is_empty());
+    }
+
+    /// Role and text of the last prompt in a request
+    fn last_prompt(req: &chat::ChatRequest<'_>) -> (String, String) {
+        let prompt = req.messages.last().unwrap();
+        (prompt.role.to_string(), prompt.content.to_string())
+    }
+
+    #[test]
+    fn a_reply_that_breaks_the_format_is_corrected_up_to_the_cap() {
+        let (mut app, mut rx) = app_with(config::Config::default());
+        let broken = r#"{"action":"dance","arguments":{}}"#;
+        enter(&mut app, "hi");
+        let mut pending = chats(&mut rx).pop().unwrap();
+
+        for attempt in 1..=MAX_CORRECTIONS {
+            reply(&mut app, pending.0, pending.1, broken);
+            let mut sent = chats(&mut rx);
+            assert_eq!(sent.len(), 1, "one re-prompt per broken reply");
+            pending = sent.pop().unwrap();
+            let (role, content) = last_prompt(&pending.2);
+            assert_eq!(role, "system");
+            assert!(content.starts_with("Your last reply was rejected"));
+            assert_eq!(app.corrective_retries, attempt);
+        }
+
+        reply(&mut app, pending.0, pending.1, broken);
+        assert!(chats(&mut rx).is_empty());
+        assert!(!app.waiting);
+        assert!(status(&app).starts_with("reply still broke the format"));
+        let last = app.messages.last().unwrap();
+        assert_eq!(last.role, chat::MessageRoles::Assistant);
+        assert_eq!(last.render_text(), broken);
+    }
+
+    #[test]
+    fn a_prose_reply_is_reinforced_once_per_turn() {
+        let (mut app, mut rx) = app_with(config::Config::default());
+        let prose = "Bears mostly eat berries.";
+        enter(&mut app, "what do bears eat?");
+        let (epoch, request, _) = chats(&mut rx).pop().unwrap();
+
+        reply(&mut app, epoch, request, prose);
+        let mut sent = chats(&mut rx);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(last_prompt(&sent[0].2).1, chat::REINFORCE_PROMPT);
+        assert!(app.reinforced);
+
+        // the second time around it is an ordinary correction
+        let (epoch, request, _) = sent.pop().unwrap();
+        reply(&mut app, epoch, request, prose);
+        let mut sent = chats(&mut rx);
+        assert_eq!(sent.len(), 1);
+        assert!(
+            last_prompt(&sent[0].2)
+                .1
+                .starts_with("Your last reply was rejected")
+        );
+        let reinforcements = sent[0]
+            .2
+            .messages
+            .iter()
+            .filter(|p| p.content == chat::REINFORCE_PROMPT)
+            .count();
+        assert_eq!(reinforcements, 1);
+
+        // a new turn gets its own
+        let (epoch, request, _) = sent.pop().unwrap();
+        reply(&mut app, epoch, request, &packet("berries"));
+        enter(&mut app, "and fish?");
+        assert!(!app.reinforced);
+    }
+
+    /// Workers on `host` streaming replies, and the ends to talk to them through
+    fn workers(
+        host: &str,
+    ) -> (
+        mpsc::UnboundedSender<Cmd>,
+        mpsc::UnboundedReceiver<Envelope>,
+        BusyLot,
+    ) {
+        let config = config::Config {
+            host: host.to_string(),
+            ..Default::default()
+        };
+        let (tx_cmd, rx_cmd) = mpsc::unbounded_channel();
+        let (tx_msg, rx_msg) = mpsc::unbounded_channel();
+        let busy = BusyLot::default();
+        let engine = engine::Engine::new(&config).unwrap();
+        tokio::spawn(run_workers(rx_cmd, tx_msg, engine, None, busy.clone()));
+        (tx_cmd, rx_msg, busy)
+    }
+
+    fn stream_chat(host: &str) -> Cmd {
+        Cmd::RunChat {
+            epoch: 1,
+            request: 1,
+            host: host.to_string(),
+            kind: RequestKind::Chat,
+            req: engine::chat_request("mock", chat::Mode::Chat, true, Default::default(), vec![]),
+        }
+    }
+
+    #[tokio::test]
+    async fn cancelling_stops_the_stream() {
+        let chunks: Vec<_> = (0..20).map(|n| format!("{n} ")).collect();
+        let script = serde_json::json!([{ "reply": "", "chunks": chunks, "delay_ms": 50 }]);
+        let host = mock_host("cancel", script).await;
+
+        let (tx_cmd, mut rx_msg, busy) = workers(&host);
+        tx_cmd.send(stream_chat(&host)).unwrap();
+        loop {
+            let envelope = rx_msg.recv().await.unwrap();
+            if matches!(envelope.msg, Msg::StreamDelta(_)) {
+                break;
+            }
+        }
+        tx_cmd.send(Cmd::Cancel { epoch: 1 }).unwrap();
+
+        // the rest of the reply would have come in well within this
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        while let Ok(envelope) = rx_msg.try_recv() {
+            // a delta already on its way when the cancel went out may still be in the queue
+            assert!(!matches!(
+                envelope.msg,
+                Msg::StreamDone(_) | Msg::Exchange(_)
+            ));
+        }
+        assert!(!busy.is_busy());
+        let quiet = tokio::time::timeout(Duration::from_millis(300), rx_msg.recv()).await;
+        assert!(quiet.is_err());
+    }
+
+    #[tokio::test]
+    async fn cancelling_hangs_up_on_the_host() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // a host that starts a stream and then never finishes it
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = format!("http://{}", listener.local_addr().unwrap());
+        let (tx_cmd, mut rx_msg, _busy) = workers(&host);
+        tx_cmd.send(stream_chat(&host)).unwrap();
+
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0; 64 * 1024];
+        let _ = socket.read(&mut buf).await.unwrap();
+        let line = "{\"message\":{\"role\":\"assistant\",\"content\":\"hi\"},\"done\":false}\n";
+        let head = "HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\n\
+                    Transfer-Encoding: chunked\r\n\r\n";
+        let chunk = format!("{head}{:x}\r\n{line}\r\n", line.len());
+        socket.write_all(chunk.as_bytes()).await.unwrap();
+
+        loop {
+            let envelope = rx_msg.recv().await.unwrap();
+            if matches!(envelope.msg, Msg::StreamDelta(_)) {
+                break;
+            }
+        }
+        tx_cmd.send(Cmd::Cancel { epoch: 1 }).unwrap();
+
+        // dropping the stream drops the connection, which is what stops Ollama generating
+        let read = tokio::time::timeout(Duration::from_secs(5), socket.read(&mut buf)).await;
+        assert!(
+            matches!(read, Ok(Ok(0)) | Ok(Err(_))),
+            "the connection stayed open"
+        );
+    }
+
+    #[test]
+    fn switching_host_moves_requests_and_drops_old_replies() {
+        let (mut app, mut rx) = app_with(config::Config::default());
+        enter(&mut app, "what do bears eat?");
+        let (old_epoch, old_request, _) = chats(&mut rx).pop().unwrap();
+
+        enter(&mut app, "/host http://127.0.0.1:1");
+        let mut cancelled = false;
+        while let Ok(cmd) = rx.try_recv() {
+            cancelled |= matches!(cmd, Cmd::Cancel { epoch } if epoch == old_epoch);
+        }
+        assert!(cancelled);
+        assert_ne!(app.epoch, old_epoch);
+        assert!(!app.waiting);
+
+        // the old host answering late changes nothing
+        let before = app.messages.len();
+        reply(&mut app, old_epoch, old_request, &packet("berries"));
+        assert_eq!(app.messages.len(), before);
+        assert!(!app.waiting);
+
+        enter(&mut app, "and fish?");
+        let mut hosts = Vec::new();
+        while let Ok(cmd) = rx.try_recv() {
+            if let Cmd::RunChat { epoch, host, .. } = cmd {
+                assert_eq!(epoch, app.epoch);
+                hosts.push(host);
+            }
+        }
+        assert_eq!(hosts, ["http://127.0.0.1:1"]);
+    }
+
+    /// What each message in the conversation says, in order
+    fn transcript(app: &AppState) -> Vec<&str> {
+        app.messages
+            .iter()
+            .map(|m| {
+                m.content
+                    .arguments
+                    .get("response")
+                    .map_or("", String::as_str)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn replies_land_in_send_order_whatever_order_they_finish_in() {
+        let (mut app, mut rx) = app_with(config::Config::default());
+        enter(&mut app, "first");
+        // a second turn sent before the first is answered
+        app.submit("second".to_string());
+        let sent = chats(&mut rx);
+        assert_eq!(sent.len(), 2);
+        let (epoch, first, _) = sent[0];
+        let (_, second, _) = sent[1];
+
+        reply(&mut app, epoch, second, &packet("second answer"));
+        // held back until the one before it is in
+        assert_eq!(transcript(&app), ["first", "second"]);
+        assert!(app.waiting);
+
+        reply(&mut app, epoch, first, &packet("first answer"));
+        assert_eq!(
+            transcript(&app),
+            ["first", "first answer", "second", "second answer"]
+        );
+        assert!(!app.waiting);
+        assert!(app.early_replies.is_empty());
+    }
+
+    /// A mock host answering from `script`
+    async fn mock_host(name: &str, script: serde_json::Value) -> String {
+        let path = std::env::temp_dir().join(format!("oxiai-{name}-{}", std::process::id()));
+        std::fs::write(&path, script.to_string()).unwrap();
+        let host = mock::start(&path).await.unwrap();
+        let _ = std::fs::remove_file(&path);
+        host
+    }
+
+    /// Pass what the session asks for to `tx_cmd` and what comes back into the session, until it
+    /// has nothing left in flight
+    async fn run_turn(
+        app: &mut AppState,
+        rx: &mut mpsc::UnboundedReceiver<Cmd>,
+        tx_cmd: &mpsc::UnboundedSender<Cmd>,
+        rx_msg: &mut mpsc::UnboundedReceiver<Envelope>,
+    ) {
+        loop {
+            while let Ok(cmd) = rx.try_recv() {
+                tx_cmd.send(cmd).unwrap();
+            }
+            if !app.waiting {
+                return;
+            }
+            let envelope = tokio::time::timeout(Duration::from_secs(5), rx_msg.recv())
+                .await
+                .expect("the turn never finished")
+                .unwrap();
+            app.handle_msg(envelope);
+        }
+    }
+
+    /// The screen as drawn at `width` x `height`, one string per row
+    fn screen(app: &AppState, width: u16, height: u16) -> Vec<String> {
+        let mut terminal =
+            ratatui::Terminal::new(ratatui::backend::TestBackend::new(width, height)).unwrap();
+        terminal.draw(|f| ui::chat_ui(f, app)).unwrap();
+        let buffer = terminal.backend().buffer();
+        (0..height)
+            .map(|y| (0..width).map(|x| buffer[(x, y)].symbol()).collect())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn a_scripted_tool_call_runs_and_is_answered() {
+        let script = serde_json::json!([
+            { "reply": { "action": "get_date_time", "arguments": {} } },
+            { "reply": { "action": "chat", "arguments": { "response": "It is late." } } },
+        ]);
+        let host = mock_host("tool-turn", script).await;
+        let (mut app, mut rx) = app_with(config::Config {
+            host: host.clone(),
+            ..Default::default()
+        });
+        let (tx_cmd, mut rx_msg, _busy) = workers(&host);
+
+        enter(&mut app, "/mode agent");
+        enter(&mut app, "what time is it?");
+        run_turn(&mut app, &mut rx, &tx_cmd, &mut rx_msg).await;
+
+        let roles: Vec<_> = app.messages.iter().map(|m| m.role.clone()).collect();
+        use chat::MessageRoles::*;
+        assert_eq!(roles, [User, Assistant, Tool, Assistant]);
+        assert_eq!(
+            app.messages[1].content.action,
+            chat::Action::Tool(chat::AssistantTool::GetDateTime)
+        );
+        assert!(
+            app.messages
+                .iter()
+                .all(|m| m.status != chat::SendStatus::Pending)
+        );
+
+        let screen = screen(&app, 100, 40).join("\n");
+        assert!(screen.contains("what time is it?"));
+        assert!(screen.contains("get_date_time"));
+        assert!(screen.contains("It is late."));
+    }
+
+    #[test]
+    fn setting_an_option_the_backend_drops_says_so() {
+        let (mut app, _rx) = app_with(config::Config {
+            backend: backend::Backend::OpenAi,
+            ..Default::default()
+        });
+        enter(&mut app, "/set top_k 40");
+        assert_eq!(app.options.top_k, Some(40));
+        assert!(status(&app).contains("ignores it"));
+        enter(&mut app, "/set temperature 0.5");
+        assert_eq!(status(&app), "temperature set to 0.5");
+    }
+
+    #[test]
+    fn cache_clear_empties_the_render_cache() {
+        let (mut app, _rx) = app_with(config::Config::default());
+        app.submit("hello".to_string());
+        screen(&app, 80, 24);
+
+        enter(&mut app, "/cache");
+        assert!(
+            status(&app).contains("chat: 1 messages"),
+            "{}",
+            status(&app)
+        );
+
+        enter(&mut app, "/cache clear");
+        assert_eq!(
+            status(&app),
+            "render cache, chat: 0 messages, 0.0% hits, 0 B; side panel: 0 messages, 0.0% hits, 0 B"
+        );
+        // and it fills back up on the next frame
+        screen(&app, 80, 24);
+        enter(&mut app, "/cache");
+        assert!(status(&app).contains("chat: 1 messages"));
+    }
+
+    #[test]
+    fn context_after_pruning_keeps_the_system_prompt_and_omits_dropped_turns() {
+        let (mut app, mut rx) = app_with(config::Config::default());
+        for turn in ["first", "second", "third"] {
+            enter(
+                &mut app,
+                &format!("{turn} question {}", "padding ".repeat(100)),
+            );
+            let (epoch, request, _) = chats(&mut rx).pop().unwrap();
+            reply(&mut app, epoch, request, &packet(&format!("{turn} answer")));
+        }
+
+        // room for everything but about half a turn, so only the oldest has to go
+        let full = app.estimate_tokens(&app.chat_request());
+        let turn = full - {
+            let mut without_one = app.chat_request();
+            without_one.messages.drain(1..3);
+            app.estimate_tokens(&without_one)
+        };
+        app.config.context_size =
+            Some(((full - turn / 2) as f64 / CONTEXT_WARN_RATIO).ceil() as u64);
+        enter(&mut app, "/prune");
+        assert!(
+            status(&app).starts_with("dropped 2 messages"),
+            "{}",
+            status(&app)
+        );
+
+        enter(&mut app, "/context");
+        let preview = &app.popup.as_ref().unwrap().body;
+        assert!(preview.contains(&app.system.base));
+        assert!(!preview.contains("first question"));
+        assert!(!preview.contains("first answer"));
+        assert!(preview.contains("second question"));
+        assert!(preview.contains("third answer"));
+    }
+
+    /// The message ids, checked to be unique and in conversation order on the way
+    fn ordered_ids(app: &AppState) -> Vec<u64> {
+        let ids: Vec<_> = app.messages.iter().map(|m| m.id).collect();
+        assert!(
+            ids.windows(2).all(|w| w[0] < w[1]),
+            "ids out of order: {ids:?}"
+        );
+        ids
+    }
+
+    #[test]
+    fn message_ids_stay_ordered_through_undo_import_and_load() {
+        let (mut app, mut rx) = app_with(config::Config::default());
+        for turn in ["first", "second"] {
+            enter(&mut app, turn);
+            let (epoch, request, _) = chats(&mut rx).pop().unwrap();
+            reply(&mut app, epoch, request, &packet("ok"));
+        }
+        assert_eq!(ordered_ids(&app), [1, 2, 3, 4]);
+
+        let path = std::env::temp_dir().join(format!("oxiai-ids-{}.json", std::process::id()));
+        enter(&mut app, &format!("/save {}", path.display()));
+
+        enter(&mut app, "/undo");
+        assert_eq!(ordered_ids(&app), [1, 2]);
+        enter(&mut app, "/redo");
+        assert_eq!(ordered_ids(&app), [1, 2, 3, 4]);
+
+        // what is merged in gets ids of its own, after everything already there
+        enter(&mut app, "/undo");
+        enter(&mut app, &format!("/import merge {}", path.display()));
+        assert_eq!(ordered_ids(&app), [1, 2, 5, 6, 7, 8]);
+        // the taken back turn would land after the merged one with older ids, so it is gone
+        enter(&mut app, "/redo");
+        assert_eq!(status(&app), "nothing to redo");
+
+        enter(&mut app, &format!("/load {}", path.display()));
+        assert_eq!(ordered_ids(&app), [9, 10, 11, 12]);
+        let _ = std::fs::remove_file(&path);
+
+        enter(&mut app, "third");
+        assert_eq!(ordered_ids(&app), [9, 10, 11, 12, 13]);
+    }
+
+    #[test]
+    fn only_agent_mode_sends_json_format() {
+        let (mut app, mut rx) = app_with(config::Config::default());
+        enter(&mut app, "/mode agent");
+        enter(&mut app, "hello");
+        let (epoch, request, req) = chats(&mut rx).pop().unwrap();
+        assert_eq!(req.format, Some("json"));
+        reply(&mut app, epoch, request, &packet("hi"));
+
+        enter(&mut app, "/mode chat");
+        enter(&mut app, "hello again");
+        let (_, _, req) = chats(&mut rx).pop().unwrap();
+        assert_eq!(req.format, None);
+        // and the packets already in the conversation go as plain text
+        assert_eq!(req.messages[req.messages.len() - 2].content, "hi");
+    }
+
+    #[test]
+    fn undo_takes_back_the_whole_turn_and_redo_restores_it() {
+        let (mut app, mut rx) = app_with(config::Config::default());
+        enter(&mut app, "/mode agent");
+        enter(&mut app, "hello");
+        let (epoch, request, _) = chats(&mut rx).pop().unwrap();
+        reply(&mut app, epoch, request, &packet("hi"));
+
+        // a turn with a tool call in it
+        enter(&mut app, "what time is it?");
+        let (epoch, request, _) = chats(&mut rx).pop().unwrap();
+        let call = serde_json::json!({ "action": "get_date_time", "arguments": {} });
+        reply(&mut app, epoch, request, &call.to_string());
+        let (epoch, request, _) = chats(&mut rx).pop().unwrap();
+        reply(&mut app, epoch, request, &packet("noon"));
+        let before: Vec<String> = transcript(&app).into_iter().map(String::from).collect();
+        let ids: Vec<_> = app.messages.iter().map(|m| m.id).collect();
+        assert_eq!(app.messages.len(), 6);
+
+        enter(&mut app, "/undo");
+        assert_eq!(status(&app), "took back 4 messages");
+        assert_eq!(transcript(&app), ["hello", "hi"]);
+
+        enter(&mut app, "/redo");
+        assert_eq!(transcript(&app), before);
+        assert_eq!(app.messages.iter().map(|m| m.id).collect::<Vec<_>>(), ids);
+        enter(&mut app, "/redo");
+        assert_eq!(status(&app), "nothing to redo");
+    }
+
+    #[test]
+    fn tree_adds_the_project_layer_and_tree_off_drops_it() {
+        let dir = std::env::temp_dir().join(format!("oxiai-tree-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::write(dir.join("src/lib.rs"), "").unwrap();
+        let (mut app, mut rx) = app_with(config::Config::default());
+        app.sandbox = tool::Sandbox::new(&dir).unwrap();
+        let has_tree = |req: &chat::ChatRequest<'_>| {
+            req.messages
+                .iter()
+                .any(|p| p.role == "system" && p.content.contains("lib.rs"))
+        };
+
+        enter(&mut app, "/tree");
+        enter(&mut app, "what is in here?");
+        let (epoch, request, req) = chats(&mut rx).pop().unwrap();
+        assert!(has_tree(&req));
+        reply(&mut app, epoch, request, &packet("a library"));
+
+        enter(&mut app, "/tree off");
+        enter(&mut app, "and now?");
+        let (_, _, req) = chats(&mut rx).pop().unwrap();
+        assert!(!has_tree(&req));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn a_user_message_is_pending_until_its_reply_settles_it() {
+        let (mut app, mut rx) = app_with(config::Config::default());
+        let last_status = |app: &AppState| {
+            app.messages
+                .iter()
+                .rfind(|m| m.role == chat::MessageRoles::User)
+                .unwrap()
+                .status
+        };
+
+        enter(&mut app, "answered");
+        assert_eq!(last_status(&app), chat::SendStatus::Pending);
+        let (epoch, request, _) = chats(&mut rx).pop().unwrap();
+        reply(&mut app, epoch, request, &packet("yes"));
+        assert_eq!(last_status(&app), chat::SendStatus::Sent);
+
+        enter(&mut app, "failed");
+        let (epoch, request, _) = chats(&mut rx).pop().unwrap();
+        let error = reqwest::Client::new().get("not a url").build().unwrap_err();
+        app.handle_msg(Envelope {
+            epoch,
+            request,
+            msg: Msg::HttpDone(RequestKind::Chat, Err(error)),
+        });
+        assert_eq!(last_status(&app), chat::SendStatus::Failed);
+
+        enter(&mut app, "cancelled");
+        assert_eq!(last_status(&app), chat::SendStatus::Pending);
+        app.cancel();
+        assert_eq!(last_status(&app), chat::SendStatus::Failed);
+        // the earlier ones keep what they got
+        let user: Vec<_> = app
+            .messages
+            .iter()
+            .filter(|m| m.role == chat::MessageRoles::User)
+            .map(|m| m.status)
+            .collect();
+        use chat::SendStatus::*;
+        assert_eq!(user, [Sent, Failed, Failed]);
+    }
+
+    fn press(app: &mut AppState, code: KeyCode, modifiers: KeyModifiers) {
+        app.handle_input(Event::Key(crossterm::event::KeyEvent::new(code, modifiers)));
+    }
+
+    #[test]
+    fn quitting_mid_request_asks_first() {
+        let (mut app, _rx) = app_with(config::Config::default());
+        press(&mut app, KeyCode::Char('q'), KeyModifiers::CONTROL);
+        // nothing in flight, nothing to ask about
+        assert!(app.should_quit);
+
+        let (mut app, _rx) = app_with(config::Config::default());
+        enter(&mut app, "hello");
+        press(&mut app, KeyCode::Char('q'), KeyModifiers::CONTROL);
+        assert!(!app.should_quit);
+        assert!(app.quit_pending);
+        assert_eq!(status(&app), "a request is in progress, quit anyway? (y/n)");
+
+        // any other key backs out of it
+        press(&mut app, KeyCode::Char('n'), KeyModifiers::NONE);
+        assert!(!app.should_quit && !app.quit_pending);
+        assert!(app.waiting);
+
+        press(&mut app, KeyCode::Char('q'), KeyModifiers::CONTROL);
+        assert!(app.quit_pending);
+        // the quit key a second time is a yes
+        press(&mut app, KeyCode::Char('q'), KeyModifiers::CONTROL);
+        assert!(app.should_quit);
+    }
+
+    #[test]
+    fn a_turn_past_its_deadline_is_cut_off_keeping_what_arrived() {
+        let (mut app, mut rx) = app_with(config::Config {
+            turn_timeout_secs: Some(30),
+            ..Default::default()
+        });
+        enter(&mut app, "tell me a long story");
+        let (epoch, request, _) = chats(&mut rx).pop().unwrap();
+        app.handle_msg(Envelope {
+            epoch,
+            request,
+            msg: Msg::StreamDelta("Once upon a time".to_string()),
+        });
+
+        app.check_turn_deadline(app.turn_started + Duration::from_secs(29));
+        assert!(app.waiting);
+
+        app.check_turn_deadline(app.turn_started + Duration::from_secs(30));
+        assert!(!app.waiting);
+        assert_eq!(status(&app), "turn timed out after 30s, kept what arrived");
+        assert!(
+            matches!(rx.try_recv(), Ok(Cmd::Cancel { epoch: cancelled }) if cancelled == epoch)
+        );
+        assert_eq!(
+            transcript(&app),
+            ["tell me a long story", "Once upon a time"]
+        );
+
+        // the rest of the reply turning up late changes nothing
+        app.handle_msg(Envelope {
+            epoch,
+            request,
+            msg: Msg::StreamDelta(", the end".to_string()),
+        });
+        assert!(app.streaming.is_empty());
+    }
+
+    #[test]
+    fn sideways_scrolling_with_wrap_off_stops_at_the_longest_line() {
+        let (mut app, mut rx) = app_with(config::Config::default());
+        enter(&mut app, &format!("a long line {}end", "x".repeat(200)));
+        let (epoch, request, _) = chats(&mut rx).pop().unwrap();
+        reply(&mut app, epoch, request, &packet("ok"));
+
+        press(&mut app, KeyCode::F(4), KeyModifiers::NONE);
+        assert!(!app.wrap);
+        press(&mut app, KeyCode::Up, KeyModifiers::ALT);
+        assert!(app.selected.is_some());
+        screen(&app, 80, 24);
+        let max = app.layout.borrow().h_scroll_max;
+        assert!(max > 0);
+
+        for _ in 0..100 {
+            press(&mut app, KeyCode::Right, KeyModifiers::NONE);
+        }
+        assert_eq!(app.h_scroll, max);
+        // and the end of the line is what is in view
+        assert!(screen(&app, 80, 24).iter().any(|row| row.contains("xxend")));
+
+        // wrapped lines have nothing to scroll to
+        press(&mut app, KeyCode::F(4), KeyModifiers::NONE);
+        assert_eq!(app.h_scroll, 0);
+        screen(&app, 80, 24);
+        assert_eq!(app.layout.borrow().h_scroll_max, 0);
+    }
+
+    /// What goes out to the terminal for the links of a frame drawn at 80x24
+    fn link_output(app: &AppState) -> String {
+        screen(app, 80, 24);
+        let mut out = Vec::new();
+        ui::write_links(&mut out, &app.layout.borrow().links).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn urls_in_the_chat_become_osc_8_links_unless_turned_off() {
+        for hyperlinks in [true, false] {
+            let (mut app, mut rx) = app_with(config::Config {
+                hyperlinks,
+                ..Default::default()
+            });
+            enter(&mut app, "where are the docs?");
+            let (epoch, request, _) = chats(&mut rx).pop().unwrap();
+            reply(
+                &mut app,
+                epoch,
+                request,
+                &packet("see https://example.com/docs for more"),
+            );
+
+            let out = link_output(&app);
+            let link =
+                "\x1b]8;;https://example.com/docs\x1b\\https://example.com/docs\x1b]8;;\x1b\\";
+            match hyperlinks {
+                true => assert!(out.contains(link), "{out:?}"),
+                false => assert_eq!(out, ""),
+            }
+        }
+    }
+
+    #[test]
+    fn the_streaming_reply_ends_in_the_configured_caret() {
+        use config::StreamCursor;
+        let cases = [
+            (StreamCursor::Block, "Once upon▍"),
+            (StreamCursor::Underscore, "Once upon_"),
+            (StreamCursor::Spinner, "Once upon|"),
+            (StreamCursor::None, "Once upon "),
+        ];
+        for (stream_cursor, expected) in cases {
+            let (mut app, mut rx) = app_with(config::Config {
+                stream_cursor,
+                // no blinking, so the caret is always in its first phase
+                stream_cursor_blink_ms: 0,
+                ..Default::default()
+            });
+            enter(&mut app, "tell me a story");
+            let (epoch, request, _) = chats(&mut rx).pop().unwrap();
+            app.handle_msg(Envelope {
+                epoch,
+                request,
+                msg: Msg::StreamDelta("Once upon".to_string()),
+            });
+
+            let screen = screen(&app, 80, 24);
+            assert!(
+                screen.iter().any(|row| row.contains(expected)),
+                "{stream_cursor:?}: {screen:#?}"
+            );
+        }
+    }
+
+    #[test]
+    fn the_cursor_sits_after_the_prompt_with_or_without_borders() {
+        for (minimal, text_column) in [(false, 4), (true, 3)] {
+            let (mut app, _rx) = app_with(config::Config::default());
+            app.minimal = minimal;
+            app.prompt = "abc".to_string();
+            app.cursor = 3;
+
+            let backend = ratatui::backend::TestBackend::new(40, 12);
+            let mut terminal = ratatui::Terminal::new(backend).unwrap();
+            terminal.draw(|f| ui::chat_ui(f, &app)).unwrap();
+            let cursor = terminal.get_cursor_position().unwrap();
+            let buffer = terminal.backend().buffer();
+            let prompt_row: Vec<_> = (0..40).map(|x| buffer[(x, cursor.y)].symbol()).collect();
+
+            // the margin, the border if there is one, then "> "
+            let typed = prompt_row[text_column - 2..text_column + 3].concat();
+            assert_eq!(typed, "> abc", "{prompt_row:?}");
+            assert_eq!(cursor.x as usize, text_column + 3, "minimal {minimal}");
+        }
+    }
+
+    #[test]
+    fn tool_activity_moves_to_the_side_panel_only_when_there_is_room() {
+        let (mut app, mut rx) = app_with(config::Config::default());
+        enter(&mut app, "/mode agent");
+        enter(&mut app, "what time is it?");
+        let (epoch, request, _) = chats(&mut rx).pop().unwrap();
+        let call = serde_json::json!({ "action": "get_date_time", "arguments": {} });
+        reply(&mut app, epoch, request, &call.to_string());
+        let (epoch, request, _) = chats(&mut rx).pop().unwrap();
+        reply(&mut app, epoch, request, &packet("about noon"));
+        app.side_panel = true;
+
+        // the tools get the right-hand column, the conversation the left
+        let wide = screen(&app, 200, 30);
+        assert!(wide[1].contains("Tools"), "{wide:#?}");
+        let column = |rows: &[String], text: &str| {
+            rows.iter()
+                .find_map(|row| row.find(text).map(|idx| row[..idx].chars().count()))
+                .unwrap_or_else(|| panic!("{text} not on screen"))
+        };
+        assert!(column(&wide, "get_date_time") > 100);
+        assert!(column(&wide, "about noon") < 100);
+
+        // too narrow for two, everything stays in the one column
+        let narrow = screen(&app, 80, 30);
+        assert!(
+            !narrow.iter().any(|row| row.contains("Tools")),
+            "{narrow:#?}"
+        );
+        assert!(column(&narrow, "get_date_time") < 40);
+        column(&narrow, "about noon");
+    }
+
+    #[test]
+    fn the_assistant_name_is_in_the_prompt_and_the_label() {
+        let (mut app, mut rx) = app_with(config::Config {
+            assistant_name: "Aria".to_string(),
+            show_greeting: false,
+            ..Default::default()
+        });
+        let prompt = app.get_system_prompt().unwrap();
+        assert!(prompt.contains("Aria"));
+        assert!(!prompt.contains("OxiAI"));
+
+        enter(&mut app, "who are you?");
+        let (epoch, request, req) = chats(&mut rx).pop().unwrap();
+        assert!(req.messages[0].content.contains("Aria"));
+        reply(&mut app, epoch, request, &packet("I'm Aria."));
+        assert!(screen(&app, 80, 24).iter().any(|row| row.contains("Aria:")));
+    }
+
+    #[test]
+    fn the_greeting_is_shown_but_never_sent() {
+        let (mut app, mut rx) = app_with(config::Config {
+            assistant_name: "Aria".to_string(),
+            greeting: "Hello from {assistant_name}!".to_string(),
+            ..Default::default()
+        });
+        assert!(
+            screen(&app, 80, 24)
+                .iter()
+                .any(|row| row.contains("Hello from Aria!"))
+        );
+        assert!(app.messages.is_empty());
+
+        enter(&mut app, "hi");
+        let (_, _, req) = chats(&mut rx).pop().unwrap();
+        assert!(
+            req.messages
+                .iter()
+                .all(|p| !p.content.contains("Hello from"))
+        );
+        // the conversation has started, it has done its job
+        assert!(
+            !screen(&app, 80, 24)
+                .iter()
+                .any(|row| row.contains("Hello from Aria!"))
+        );
+
+        let (app, _rx) = app_with(config::Config {
+            greeting: "Hello from {assistant_name}!".to_string(),
+            show_greeting: false,
+            ..Default::default()
+        });
+        assert!(
+            !screen(&app, 80, 24)
+                .iter()
+                .any(|row| row.contains("Hello from"))
+        );
+    }
+
+    #[tokio::test]
+    async fn a_flood_of_deltas_keeps_every_token_in_one_frame() {
+        let tokens: Vec<_> = (0..2_000).map(|n| format!("t{n} ")).collect();
+        let script = serde_json::json!([{ "reply": "", "chunks": tokens }]);
+        let host = mock_host("flood", script).await;
+        let (mut app, mut rx) = app_with(config::Config {
+            host: host.clone(),
+            ..Default::default()
+        });
+        let (tx_cmd, mut rx_msg, busy) = workers(&host);
+        app.args.stream = true;
+
+        enter(&mut app, "/mode chat");
+        enter(&mut app, "count for me");
+        let run = std::iter::from_fn(|| rx.try_recv().ok())
+            .find(|cmd| matches!(cmd, Cmd::RunChat { .. }))
+            .unwrap();
+        tx_cmd.send(run).unwrap();
+
+        // a UI that falls behind finds the whole reply queued up by the time it looks
+        let first = tokio::time::timeout(Duration::from_secs(5), rx_msg.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        while busy.is_busy() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        app.dirty = false;
+        app.handle_msgs(first, &mut rx_msg);
+
+        let mut frames = 0;
+        if app.dirty {
+            screen(&app, 80, 24);
+            frames += 1;
+        }
+        assert_eq!(frames, 1);
+        assert!(!app.waiting);
+        assert_eq!(
+            transcript(&app).last().unwrap().trim_end(),
+            tokens.concat().trim_end()
+        );
+    }
+
+    /// A session in agent mode whose tool results over 5 characters get summarized, with the
+    /// reply calling `get_date_time` in
+    fn oversized_tool_result(
+        summarize_model: Option<&str>,
+    ) -> (AppState, Vec<(RequestKind, chat::ChatRequest<'static>)>) {
+        let (mut app, mut rx) = app_with(config::Config {
+            tool_result_limit: 5,
+            tool_result_overflow: config::ToolOverflow::Summarize,
+            assist_models: config::AssistModels {
+                summarize: summarize_model.map(str::to_string),
+            },
+            ..Default::default()
+        });
+        enter(&mut app, "/mode agent");
+        enter(&mut app, "what time is it?");
+        let (epoch, request, _) = chats(&mut rx).pop().unwrap();
+        let call = serde_json::json!({ "action": "get_date_time", "arguments": {} });
+        reply(&mut app, epoch, request, &call.to_string());
+
+        let mut sent = Vec::new();
+        while let Ok(cmd) = rx.try_recv() {
+            if let Cmd::RunChat { kind, req, .. } = cmd {
+                sent.push((kind, req));
+            }
+        }
+        (app, sent)
+    }
+
+    #[test]
+    fn an_oversized_tool_result_is_sent_off_to_be_summarized() {
+        let (app, sent) = oversized_tool_result(None);
+        assert_eq!(sent.len(), 1);
+        let (kind, req) = &sent[0];
+        assert!(matches!(
+            kind,
+            RequestKind::Summarize(chat::AssistantTool::GetDateTime)
+        ));
+        assert!(!req.stream);
+        assert!(app.pending_tool_result.is_some());
+        // the tool result waits for the summary, the conversation doesn't have it yet
+        assert!(
+            app.messages
+                .iter()
+                .all(|m| m.role != chat::MessageRoles::Tool)
+        );
+    }
+
+    #[test]
+    fn summaries_go_to_the_assist_model_when_there_is_one() {
+        let (app, sent) = oversized_tool_result(Some("tiny:1b"));
+        assert_eq!(sent[0].1.model, "tiny:1b");
+        assert_ne!(app.config.model, "tiny:1b");
+
+        // without one the chat model does it
+        let (app, sent) = oversized_tool_result(None);
+        assert_eq!(sent[0].1.model, app.config.model);
+    }
+
+    #[test]
+    fn a_prompt_near_the_context_window_warns() {
+        let (mut app, _rx) = app_with(config::Config::default());
+        let usage = |prompt_tokens| chat::Usage {
+            completion_tokens: 10,
+            prompt_tokens: Some(prompt_tokens),
+            total_duration: None,
+            eval_duration: None,
+        };
+
+        // nothing to compare against until the window is known
+        app.record_usage(usage(7_000));
+        assert_eq!(status(&app), "");
+
+        app.model_context = Some(8_192);
+        app.record_usage(usage(6_500));
+        assert_eq!(status(&app), "");
+        app.record_usage(usage(6_554));
+        assert_eq!(
+            status(&app),
+            "context near capacity (6554/8192 tokens), consider /clear"
+        );
+
+        // a configured size wins over what the model says
+        app.status = None;
+        app.config.context_size = Some(16_384);
+        app.record_usage(usage(6_554));
+        assert_eq!(status(&app), "");
+    }
+
+    #[test]
+    fn the_ui_ticks_fast_only_while_something_is_going_on() {
+        let (mut app, mut rx) = app_with(config::Config::default());
+        app.dirty = false;
+        assert_eq!(app.tick_rate(), IDLE_TICK);
+
+        app.dirty = true;
+        assert_eq!(app.tick_rate(), BUSY_TICK);
+        app.dirty = false;
+
+        // a worker still at it, even with nothing of this tab's in flight
+        let ticket = app.busy.park();
+        assert_eq!(app.tick_rate(), BUSY_TICK);
+        drop(ticket);
+        assert_eq!(app.tick_rate(), IDLE_TICK);
+
+        enter(&mut app, "hello");
+        app.dirty = false;
+        assert_eq!(app.tick_rate(), BUSY_TICK);
+        let (epoch, request, _) = chats(&mut rx).pop().unwrap();
+        reply(&mut app, epoch, request, &packet("hi"));
+        app.dirty = false;
+        assert_eq!(app.tick_rate(), IDLE_TICK);
+    }
+
+    #[test]
+    fn nerd_stats_show_the_size_of_the_last_request() {
+        let (mut app, _rx) = app_with(config::Config::default());
+        let request = reqwest::Client::new()
+            .post("http://localhost:11434/api/chat")
+            .build()
+            .unwrap();
+        let mut exchange = chat::Exchange::new(&request, vec![b'x'; 2_048]);
+        exchange.response_body = vec![b'y'; 300];
+        app.last_exchange = Some(Box::new(exchange));
+
+        assert_eq!(app.nerd_stats(), None);
+        app.args.nerd_stats = true;
+        assert_eq!(app.nerd_stats().unwrap(), "sent 2.0 KiB, received 300 B");
+    }
+
+    #[tokio::test]
+    async fn a_tool_chain_belongs_to_its_question_and_folds_with_it() {
+        let script = serde_json::json!([
+            { "reply": { "action": "get_date_time", "arguments": {} } },
+            { "reply": { "action": "chat", "arguments": { "response": "It is late." } } },
+            { "reply": { "action": "chat", "arguments": { "response": "Go to bed." } } },
+        ]);
+        let host = mock_host("tool-parent", script).await;
+        let (mut app, mut rx) = app_with(config::Config {
+            host: host.clone(),
+            ..Default::default()
+        });
+        let (tx_cmd, mut rx_msg, _busy) = workers(&host);
+
+        enter(&mut app, "/mode agent");
+        enter(&mut app, "what time is it?");
+        run_turn(&mut app, &mut rx, &tx_cmd, &mut rx_msg).await;
+        enter(&mut app, "so what now?");
+        run_turn(&mut app, &mut rx, &tx_cmd, &mut rx_msg).await;
+
+        use chat::MessageRoles::*;
+        let roles: Vec<_> = app.messages.iter().map(|m| m.role.clone()).collect();
+        assert_eq!(roles, [User, Assistant, Tool, Assistant, User, Assistant]);
+        let (first, second) = (app.messages[0].id, app.messages[4].id);
+        let parents: Vec<_> = app.messages.iter().map(|m| m.parent_id).collect();
+        assert_eq!(
+            parents,
+            [
+                None,
+                Some(first),
+                Some(first),
+                Some(first),
+                None,
+                Some(second)
+            ]
+        );
+
+        // folding from inside the chain hides all of it under the question
+        app.select(Some(2));
+        press(&mut app, KeyCode::Char('o'), KeyModifiers::CONTROL);
+        assert_eq!(app.selected, Some(0));
+        let screen = screen(&app, 100, 40).join("\n");
+        assert!(screen.contains("what time is it?"));
+        assert!(screen.contains("3 more in this turn"));
+        assert!(!screen.contains("get_date_time"));
+        assert!(!screen.contains("It is late."));
+        assert!(screen.contains("so what now?"));
+        assert!(screen.contains("Go to bed."));
+    }
 }
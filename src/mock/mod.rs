@@ -0,0 +1,330 @@
+//! `--mock`: a stand-in Ollama host on a local port that answers from a script file, so the UI and
+//! the tool round trips can be driven without a model. It speaks HTTP like the real thing, so
+//! everything from sending to streaming to error handling runs the same code it would otherwise.
+//!
+//! The script is a JSON array with one entry per `/api/chat` request, taken in order:
+//!
+//! ```json
+//! [
+//!   {"reply": {"action": "wiki_search", "arguments": {"query": "Black Bear"}}},
+//!   {"reply": "Black bears are omnivores.", "chunks": ["Black bears ", "are omnivores."], "delay_ms": 200},
+//!   {"error": {"status": 500, "message": "model runner crashed"}}
+//! ]
+//! ```
+//!
+//! A `reply` that isn't a string is sent as its JSON text, which is how action packets are written.
+//! Streamed replies go out in `chunks` if given, word by word if not, `delay_ms` apart. Requests
+//! after the last entry are answered with an error.
+
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+/// One scripted answer to a chat request
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct Turn {
+    reply: Value,
+    chunks: Option<Vec<String>>,
+    delay_ms: u64,
+    error: Option<ScriptedError>,
+}
+
+/// An HTTP error the host answers with instead of a reply
+#[derive(Deserialize, Debug)]
+struct ScriptedError {
+    status: u16,
+    message: String,
+}
+
+impl Turn {
+    fn reply_text(&self) -> String {
+        match &self.reply {
+            Value::String(text) => text.clone(),
+            Value::Null => String::new(),
+            other => other.to_string(),
+        }
+    }
+
+    /// The reply in the pieces it is streamed in
+    fn pieces(&self) -> Vec<String> {
+        if let Some(chunks) = &self.chunks {
+            return chunks.clone();
+        }
+        self.reply_text()
+            .split_inclusive(' ')
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+struct Script {
+    turns: Vec<Turn>,
+    next: usize,
+}
+
+/// Read `path` and start answering from it, returns the host url to point the client at
+pub async fn start(path: &Path) -> anyhow::Result<String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("unable to read the mock script {}: {e}", path.display()))?;
+    let turns: Vec<Turn> = serde_json::from_str(&text)
+        .map_err(|e| anyhow::anyhow!("{} is not a valid mock script: {e}", path.display()))?;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let host = format!("http://{}", listener.local_addr()?);
+    let script = Arc::new(Mutex::new(Script { turns, next: 0 }));
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(serve(stream, script.clone()));
+        }
+    });
+    Ok(host)
+}
+
+/// Answer one request, every connection is closed after it so there is no keep-alive to handle
+async fn serve(stream: TcpStream, script: Arc<Mutex<Script>>) {
+    let mut stream = BufReader::new(stream);
+    let Ok((method, path, body)) = read_request(&mut stream).await else {
+        return;
+    };
+    let stream = stream.get_mut();
+
+    // a write that fails means the client hung up, which is its business
+    let _ = match (method.as_str(), path.as_str()) {
+        ("GET", "/api/version") => respond(stream, 200, &json!({ "version": "mock" })).await,
+        ("GET", "/api/tags") => {
+            respond(stream, 200, &json!({ "models": [{ "name": "mock" }] })).await
+        }
+        ("POST", "/api/show") => respond(stream, 200, &json!({ "parameters": "" })).await,
+        ("POST", "/api/chat") => {
+            let turn = {
+                let mut script = script
+                    .lock()
+                    .expect("the mock script lock is never poisoned");
+                let n = script.next;
+                script.next += 1;
+                (n < script.turns.len()).then(|| std::mem::take(&mut script.turns[n]))
+            };
+            chat(stream, turn, &body).await
+        }
+        _ => respond(stream, 404, &json!({ "error": "not found" })).await,
+    };
+}
+
+async fn chat(stream: &mut TcpStream, turn: Option<Turn>, body: &[u8]) -> std::io::Result<()> {
+    let Some(turn) = turn else {
+        let error = json!({ "error": "the mock script has no more turns" });
+        return respond(stream, 500, &error).await;
+    };
+    if let Some(error) = &turn.error {
+        return respond(stream, error.status, &json!({ "error": error.message })).await;
+    }
+
+    let streamed = serde_json::from_slice::<Value>(body)
+        .ok()
+        .and_then(|req| req.get("stream").and_then(Value::as_bool))
+        .unwrap_or(false);
+    if !streamed {
+        return respond(stream, 200, &chunk(&turn.reply_text(), true)).await;
+    }
+
+    stream
+        .write_all(
+            b"HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\n\
+              Transfer-Encoding: chunked\r\nConnection: close\r\n\r\n",
+        )
+        .await?;
+    let pieces = turn.pieces();
+    for (n, piece) in pieces.iter().enumerate() {
+        if n > 0 && turn.delay_ms > 0 {
+            tokio::time::sleep(Duration::from_millis(turn.delay_ms)).await;
+        }
+        write_chunk(stream, &chunk(piece, false)).await?;
+    }
+    write_chunk(stream, &chunk("", true)).await?;
+    stream.write_all(b"0\r\n\r\n").await?;
+    stream.flush().await
+}
+
+/// A reply, or a piece of one, as Ollama shapes it. Token counts go on the final one like the
+/// real host's, a token per word is close enough for the stats.
+fn chunk(content: &str, done: bool) -> Value {
+    let mut chunk = json!({
+        "message": { "role": "assistant", "content": content },
+        "done": done,
+    });
+    if done {
+        chunk["eval_count"] = json!(content.split_whitespace().count().max(1));
+    }
+    chunk
+}
+
+/// One line of a chunked NDJSON body
+async fn write_chunk(stream: &mut TcpStream, value: &Value) -> std::io::Result<()> {
+    let line = format!("{value}\n");
+    let framed = format!("{:x}\r\n{line}\r\n", line.len());
+    stream.write_all(framed.as_bytes()).await?;
+    stream.flush().await
+}
+
+async fn respond(stream: &mut TcpStream, status: u16, body: &Value) -> std::io::Result<()> {
+    let body = body.to_string();
+    let head = format!(
+        "HTTP/1.1 {status} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        reason(status),
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.flush().await
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        429 => "Too Many Requests",
+        503 => "Service Unavailable",
+        _ if status >= 500 => "Internal Server Error",
+        _ => "Error",
+    }
+}
+
+/// Method, path and body of the request on `stream`, reqwest always sends a `Content-Length`
+async fn read_request(
+    stream: &mut BufReader<TcpStream>,
+) -> std::io::Result<(String, String, Vec<u8>)> {
+    let mut request_line = String::new();
+    stream.read_line(&mut request_line).await?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+
+    let mut length = 0;
+    loop {
+        let mut header = String::new();
+        if stream.read_line(&mut header).await? == 0 || header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':')
+            && name.trim().eq_ignore_ascii_case("content-length")
+        {
+            length = value.trim().parse().unwrap_or(0);
+        }
+    }
+
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body).await?;
+    Ok((method, path, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{Backend, ChatBackend};
+    use crate::chat;
+
+    /// Start a mock host on `script`, the file is only read at startup so it is gone again after
+    async fn host(name: &str, script: &str) -> anyhow::Result<String> {
+        let path = std::env::temp_dir().join(format!("oxiai-mock-{name}-{}", std::process::id()));
+        std::fs::write(&path, script).unwrap();
+        let host = start(&path).await;
+        let _ = std::fs::remove_file(&path);
+        host
+    }
+
+    async fn post_chat(host: &str, stream: bool) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(Backend::Ollama.chat_url(host))
+            .json(&json!({ "model": "mock", "messages": [], "stream": stream }))
+            .send()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_bad_script_is_refused() {
+        let err = host("bad", r#"[{"reply": "hi", "colour": "red"}]"#)
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("is not a valid mock script"),
+            "{err}"
+        );
+        let missing = start(Path::new("/nonexistent/oxiai-mock.json"))
+            .await
+            .unwrap_err();
+        assert!(
+            missing
+                .to_string()
+                .contains("unable to read the mock script")
+        );
+    }
+
+    #[tokio::test]
+    async fn answers_the_probes() {
+        let host = host("probes", "[]").await.unwrap();
+        let client = reqwest::Client::new();
+        let version = chat::fetch_version(&client, &host, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(version, "mock");
+        let show = chat::fetch_show(&client, &host, "mock", Duration::from_secs(5)).await;
+        assert!(show.is_ok());
+    }
+
+    #[tokio::test]
+    async fn a_whole_reply_carries_the_action_packet() {
+        let script = r#"[{"reply": {"action": "chat", "arguments": {"response": "hi there"}}}]"#;
+        let host = host("whole", script).await.unwrap();
+        let resp = post_chat(&host, false).await;
+        assert_eq!(resp.status(), 200);
+
+        let reply = Backend::Ollama
+            .parse_reply(&resp.bytes().await.unwrap())
+            .unwrap();
+        assert!(reply.done);
+        let packet = chat::validate_envelope(&reply.message.content).unwrap();
+        assert_eq!(packet.response(), Some("hi there"));
+    }
+
+    #[tokio::test]
+    async fn a_streamed_reply_comes_in_its_chunks() {
+        let script = r#"[{"reply": "Black bears are omnivores.", "chunks": ["Black bears ", "are omnivores."]}]"#;
+        let host = host("stream", script).await.unwrap();
+        let body = post_chat(&host, true).await.bytes().await.unwrap();
+
+        let mut lines = chat::LineSplitter::default();
+        let chunks: Vec<_> = lines
+            .push(&body)
+            .iter()
+            .filter_map(|line| Backend::Ollama.parse_stream_line(line))
+            .collect();
+        let text: Vec<_> = chunks.iter().map(|c| c.message.content.as_str()).collect();
+        assert_eq!(text, ["Black bears ", "are omnivores.", ""]);
+        assert!(chunks.last().unwrap().done);
+        assert!(chunks.last().unwrap().usage().is_some());
+    }
+
+    #[tokio::test]
+    async fn scripted_errors_and_running_out_are_http_errors() {
+        let script = r#"[{"error": {"status": 503, "message": "model runner crashed"}}]"#;
+        let host = host("errors", script).await.unwrap();
+
+        let resp = post_chat(&host, true).await;
+        assert_eq!(resp.status(), 503);
+        assert!(resp.text().await.unwrap().contains("model runner crashed"));
+
+        let resp = post_chat(&host, false).await;
+        assert_eq!(resp.status(), 500);
+        assert!(resp.text().await.unwrap().contains("no more turns"));
+    }
+}
@@ -0,0 +1,217 @@
+//! Send a saved conversation's user turns to a model again, for comparing how models (or
+//! versions of one) answer the same prompts.
+
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+
+use unicode_width::UnicodeWidthStr;
+
+use crate::backend::ChatBackend;
+use crate::{PLAIN_PROMPT, chat, config, ui};
+
+/// Widest a `--compare` column gets, wider terminals just get more margin
+const COMPARE_WIDTH: usize = 60;
+
+/// Re-run every user turn of `path` in order against `config.model`, the saved answers are only
+/// kept for `compare`. The fresh transcript goes to `out`, or next to the original. Each turn
+/// sees the fresh answers to the turns before it, not the saved ones.
+pub async fn run(
+    config: &config::Config,
+    path: &Path,
+    out: Option<&Path>,
+    compare: bool,
+) -> anyhow::Result<()> {
+    let saved = chat::Conversation::load(path)?;
+    let client = reqwest::Client::new();
+
+    let system = saved
+        .system
+        .clone()
+        .unwrap_or_else(|| PLAIN_PROMPT.replace("{assistant_name}", &config.assistant_name));
+    let mut history = vec![prompt(chat::MessageRoles::System, system.clone())];
+    let mut replayed = chat::Conversation {
+        system: Some(system),
+        messages: Vec::new(),
+        totals: chat::TokenTotals::default(),
+    };
+
+    let turns = user_turns(&saved);
+    for (n, (question, answer)) in turns.iter().enumerate() {
+        eprintln!("turn {}/{}", n + 1, turns.len());
+        history.push(prompt(chat::MessageRoles::User, question.clone()));
+
+        let req = chat::ChatRequest {
+            model: Cow::Owned(config.model.clone()),
+            messages: history.clone(),
+            stream: false,
+            format: None,
+            stop: vec![],
+            options: None,
+        };
+        let reply = send(&client, config, &req).await?;
+        if let Some(usage) = reply.usage() {
+            replayed.totals.add(usage);
+        }
+        let fresh = reply.message.content;
+
+        if compare {
+            print_compare(n + 1, question, answer.as_deref().unwrap_or(""), &fresh);
+        }
+
+        history.push(prompt(chat::MessageRoles::Assistant, fresh.clone()));
+        replayed.messages.push(chat::Message::new(
+            chat::MessageRoles::User,
+            chat::Action::Chat,
+            crate::args_builder! { "response" => question.clone() },
+        ));
+        // a conversation held under the agent rules gets JSON back, anything else plain text
+        let packet = chat::validate_envelope(&fresh).unwrap_or_else(|_| {
+            chat::ActionPacket::new(
+                chat::Action::Chat,
+                crate::args_builder! { "response" => fresh },
+            )
+        });
+        replayed.messages.push(chat::Message::new(
+            chat::MessageRoles::Assistant,
+            packet.action,
+            packet.arguments,
+        ));
+    }
+
+    let out = out.map_or_else(|| replay_path(path, &config.model), Path::to_path_buf);
+    replayed.save(&out)?;
+    eprintln!("wrote {} turns to {}", turns.len(), out.display());
+    Ok(())
+}
+
+/// Each user message's text with the text of the first assistant chat reply after it, if any
+fn user_turns(conversation: &chat::Conversation) -> Vec<(String, Option<String>)> {
+    let mut turns: Vec<(String, Option<String>)> = Vec::new();
+    for message in &conversation.messages {
+        match message.role {
+            chat::MessageRoles::User => turns.push((message.render_text().into_owned(), None)),
+            chat::MessageRoles::Assistant if message.content.action == chat::Action::Chat => {
+                if let Some((_, answer @ None)) = turns.last_mut() {
+                    *answer = Some(message.render_text().into_owned());
+                }
+            }
+            _ => {}
+        }
+    }
+    turns
+}
+
+async fn send(
+    client: &reqwest::Client,
+    config: &config::Config,
+    req: &chat::ChatRequest<'_>,
+) -> anyhow::Result<chat::StreamChunk> {
+    let mut builder = client
+        .post(config.backend.chat_url(&config.host))
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(config.backend.body(req));
+    if let Some(key) = &config.api_key {
+        builder = builder.bearer_auth(key);
+    }
+    let resp = builder.send().await?.error_for_status()?;
+    config.backend.parse_reply(&resp.bytes().await?)
+}
+
+fn prompt(role: chat::MessageRoles, content: String) -> chat::Prompt<'static> {
+    chat::Prompt {
+        role: Cow::Owned(role.to_string()),
+        content: Cow::Owned(content),
+    }
+}
+
+/// `chat.json` replayed with `llama3:8b` becomes `chat.llama3-8b.json`
+fn replay_path(path: &Path, model: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let model = model.replace([':', '/', '\\'], "-");
+    path.with_file_name(format!("{stem}.{model}.json"))
+}
+
+/// The saved and the fresh answer next to each other, under the question
+fn print_compare(turn: usize, question: &str, saved: &str, fresh: &str) {
+    println!("== turn {turn}: {question}");
+    let left = ui::wrap_text(saved, COMPARE_WIDTH);
+    let right = ui::wrap_text(fresh, COMPARE_WIDTH);
+    println!("{:<COMPARE_WIDTH$} | fresh", "saved");
+    for row in 0..left.len().max(right.len()) {
+        let l = left.get(row).map_or("", String::as_str);
+        let r = right.get(row).map_or("", String::as_str);
+        // pad by display width, not bytes, so wide characters keep the columns straight
+        let pad = COMPARE_WIDTH.saturating_sub(l.width());
+        println!("{l}{} | {r}", " ".repeat(pad));
+    }
+    println!();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: chat::MessageRoles, text: &str) -> chat::Message {
+        chat::Message::new(
+            role,
+            chat::Action::Chat,
+            crate::args_builder! { "response" => text.to_string() },
+        )
+    }
+
+    #[tokio::test]
+    async fn every_user_turn_is_sent_once_in_order() {
+        use chat::MessageRoles::{Assistant, User};
+
+        let dir = std::env::temp_dir().join(format!("oxiai-replay-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("script.json");
+        // one answer per request, a request too many would get an error
+        std::fs::write(
+            &script,
+            r#"[{"reply": "fresh one"}, {"reply": "fresh two"}, {"reply": "fresh three"}]"#,
+        )
+        .unwrap();
+        let config = config::Config {
+            host: crate::mock::start(&script).await.unwrap(),
+            model: "mock:1b".to_string(),
+            ..Default::default()
+        };
+
+        let saved = dir.join("chat.json");
+        chat::Conversation {
+            system: None,
+            messages: vec![
+                message(User, "one?"),
+                message(Assistant, "saved one"),
+                message(User, "two?"),
+                message(Assistant, "saved two"),
+                message(User, "three?"),
+            ],
+            totals: chat::TokenTotals::default(),
+        }
+        .save(&saved)
+        .unwrap();
+
+        run(&config, &saved, None, false).await.unwrap();
+        let replayed = chat::Conversation::load(&dir.join("chat.mock-1b.json")).unwrap();
+        let texts: Vec<_> = replayed
+            .messages
+            .iter()
+            .map(|m| m.render_text().into_owned())
+            .collect();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(
+            texts,
+            [
+                "one?",
+                "fresh one",
+                "two?",
+                "fresh two",
+                "three?",
+                "fresh three"
+            ]
+        );
+    }
+}
@@ -0,0 +1,200 @@
+//! Backends for the `web_search` tool. Only the url and key come from the config, which API
+//! they belong to is worked out from the url.
+
+use std::time::Duration;
+
+use reqwest::Client;
+use serde::Deserialize;
+
+use crate::config;
+
+/// Longest a search may take before the model is told it failed
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(15);
+
+pub struct SearchResult {
+    pub title: String,
+    pub url: String,
+    pub snippet: String,
+}
+
+pub trait SearchProvider {
+    async fn search(&self, query: &str) -> anyhow::Result<Vec<SearchResult>>;
+}
+
+/// A SearxNG instance with its JSON output format turned on
+pub struct Searxng {
+    client: Client,
+    url: String,
+}
+
+#[derive(Deserialize)]
+struct SearxngResponse {
+    #[serde(default)]
+    results: Vec<SearxngResult>,
+}
+
+#[derive(Deserialize)]
+struct SearxngResult {
+    title: String,
+    url: String,
+    #[serde(default)]
+    content: String,
+}
+
+impl SearchProvider for Searxng {
+    async fn search(&self, query: &str) -> anyhow::Result<Vec<SearchResult>> {
+        let resp = self
+            .client
+            .get(format!("{}/search", self.url))
+            .query(&[("q", query), ("format", "json")])
+            .timeout(SEARCH_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<SearxngResponse>()
+            .await?;
+
+        Ok(resp
+            .results
+            .into_iter()
+            .map(|r| SearchResult {
+                title: r.title,
+                url: r.url,
+                snippet: r.content,
+            })
+            .collect())
+    }
+}
+
+/// Brave's web search API, it needs a subscription token as the key
+pub struct Brave {
+    client: Client,
+    url: String,
+    key: String,
+    count: usize,
+}
+
+#[derive(Deserialize)]
+struct BraveResponse {
+    web: Option<BraveWeb>,
+}
+
+#[derive(Deserialize)]
+struct BraveWeb {
+    #[serde(default)]
+    results: Vec<BraveResult>,
+}
+
+#[derive(Deserialize)]
+struct BraveResult {
+    title: String,
+    url: String,
+    #[serde(default)]
+    description: String,
+}
+
+impl SearchProvider for Brave {
+    async fn search(&self, query: &str) -> anyhow::Result<Vec<SearchResult>> {
+        let resp = self
+            .client
+            .get(&self.url)
+            .query(&[("q", query), ("count", &self.count.to_string())])
+            .header("X-Subscription-Token", &self.key)
+            .header("Accept", "application/json")
+            .timeout(SEARCH_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<BraveResponse>()
+            .await?;
+
+        Ok(resp
+            .web
+            .map(|web| web.results)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|r| SearchResult {
+                title: r.title,
+                url: r.url,
+                snippet: r.description,
+            })
+            .collect())
+    }
+}
+
+/// Whichever provider the config names, with how many results the model gets
+pub struct Provider {
+    backend: Backend,
+    limit: usize,
+}
+
+enum Backend {
+    Searxng(Searxng),
+    Brave(Brave),
+}
+
+impl Provider {
+    /// `None` when no `search_url` is set, `web_search` then tells the model it isn't available
+    pub fn from_config(client: &Client, config: &config::Config) -> Option<Self> {
+        let url = config
+            .search_url
+            .as_deref()?
+            .trim_end_matches('/')
+            .to_string();
+        let limit = config.search_results;
+
+        let backend = match (url.contains("search.brave.com"), &config.search_key) {
+            (true, Some(key)) => Backend::Brave(Brave {
+                client: client.clone(),
+                url,
+                key: key.clone(),
+                count: limit,
+            }),
+            // without a key Brave would only ever answer 401, so it is not worth trying
+            (true, None) => return None,
+            (false, _) => Backend::Searxng(Searxng {
+                client: client.clone(),
+                url,
+            }),
+        };
+        Some(Self { backend, limit })
+    }
+
+    /// The results as the tool result the model gets back, failures included
+    pub async fn run(&self, query: &str) -> Result<String, String> {
+        let results = self
+            .search(query)
+            .await
+            .map_err(|e| format!("search for {query:?} failed: {e}"))?;
+        Ok(format_results(
+            query,
+            &results[..results.len().min(self.limit)],
+        ))
+    }
+}
+
+impl SearchProvider for Provider {
+    async fn search(&self, query: &str) -> anyhow::Result<Vec<SearchResult>> {
+        match &self.backend {
+            Backend::Searxng(p) => p.search(query).await,
+            Backend::Brave(p) => p.search(query).await,
+        }
+    }
+}
+
+/// One numbered block per result: title, url, then the snippet
+fn format_results(query: &str, results: &[SearchResult]) -> String {
+    if results.is_empty() {
+        return format!("no results for {query:?}, try different search terms");
+    }
+
+    let mut out = String::new();
+    for (n, r) in results.iter().enumerate() {
+        out.push_str(&format!("{}. {}\n   {}\n", n + 1, r.title.trim(), r.url));
+        let snippet = r.snippet.trim();
+        if !snippet.is_empty() {
+            out.push_str(&format!("   {snippet}\n"));
+        }
+    }
+    out
+}
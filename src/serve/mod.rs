@@ -0,0 +1,375 @@
+//! Headless JSON-RPC 2.0 over stdin/stdout, one JSON message per line, for editor plugins.
+//!
+//! Methods:
+//! - `send` `{"content": string}`: adds a user message to the session's conversation and asks
+//!   the model. Each piece of the reply is sent as a `delta` notification
+//!   `{"id": <request id>, "content": string}` before the final result `{"content": string}`.
+//! - `list_models` `{}`: the models the host has, as `{"models": [string]}`.
+//! - `run_tool` `{"name": string, "arguments": {string: string}}`: runs one of the local tools,
+//!   answered with `{"result": string}`.
+//!
+//! Requests are handled one at a time, in the order they arrive. Replies are plain text, serve
+//! mode holds the conversation in chat mode and doesn't use the JSON action protocol. Turns go
+//! through the same `Engine` as the chat UI's, retries and the stall watchdog included.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::mpsc;
+
+use crate::engine::{self, Engine};
+use crate::{PLAIN_PROMPT, PROBE_TIMEOUT, chat, config, tool};
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Deserialize)]
+struct SendParams {
+    content: String,
+}
+
+#[derive(Deserialize)]
+struct RunToolParams {
+    name: String,
+    #[serde(default)]
+    arguments: HashMap<String, String>,
+}
+
+/// A failed request, sent back as a JSON-RPC error object
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+struct Session<W> {
+    engine: Engine,
+    config: config::Config,
+    sandbox: tool::Sandbox,
+    /// Sent ahead of the conversation with every turn, the same layers the chat UI sends
+    system: chat::SystemLayers,
+    /// The conversation so far, without the system prompt
+    history: Vec<chat::Prompt<'static>>,
+    out: W,
+}
+
+/// Serve requests from stdin until it is closed
+pub async fn run(config: config::Config, sandbox: tool::Sandbox) -> anyhow::Result<()> {
+    let stdin = BufReader::new(tokio::io::stdin());
+    serve(config, sandbox, stdin, tokio::io::stdout()).await
+}
+
+/// Answer the requests read from `input` on `out` until `input` runs out
+async fn serve(
+    config: config::Config,
+    sandbox: tool::Sandbox,
+    input: impl AsyncBufRead + Unpin,
+    out: impl AsyncWrite + Unpin,
+) -> anyhow::Result<()> {
+    let system = chat::SystemLayers {
+        base: PLAIN_PROMPT.replace("{assistant_name}", &config.assistant_name),
+        language: config.response_language.clone(),
+        ..Default::default()
+    };
+    let mut session = Session {
+        engine: Engine::new(&config)?,
+        config,
+        sandbox,
+        system,
+        history: Vec::new(),
+        out,
+    };
+
+    let mut lines = input.lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let request = match serde_json::from_str::<Request>(&line) {
+            Ok(r) => r,
+            Err(e) => {
+                let error = RpcError::new(PARSE_ERROR, e.to_string());
+                session.respond(Value::Null, Err(error)).await?;
+                continue;
+            }
+        };
+
+        let result = session.handle(&request).await;
+        session.respond(request.id, result).await?;
+    }
+
+    Ok(())
+}
+
+impl<W: AsyncWrite + Unpin> Session<W> {
+    async fn handle(&mut self, request: &Request) -> Result<Value, RpcError> {
+        match request.method.as_str() {
+            "send" => {
+                let params: SendParams = params(&request.params)?;
+                self.send(&request.id, params.content).await
+            }
+            "list_models" => {
+                let host = &self.config.host;
+                let models = chat::fetch_tags(self.engine.client(), host, PROBE_TIMEOUT)
+                    .await
+                    .map_err(|e| {
+                        RpcError::new(SERVER_ERROR, format!("unable to list models: {e}"))
+                    })?;
+                Ok(json!({ "models": models }))
+            }
+            "run_tool" => {
+                let params: RunToolParams = params(&request.params)?;
+                self.run_tool(params)
+            }
+            method => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("unknown method: {method}"),
+            )),
+        }
+    }
+
+    async fn send(&mut self, id: &Value, content: String) -> Result<Value, RpcError> {
+        self.history.push(chat::Prompt {
+            role: Cow::Owned(chat::MessageRoles::User.to_string()),
+            content: Cow::Owned(content),
+        });
+
+        let mut messages: Vec<_> = self.system.prompts().collect();
+        messages.extend(self.history.iter().cloned());
+        let req = engine::chat_request(
+            &self.config.model,
+            chat::Mode::Chat,
+            true,
+            chat::ChatOptions::default(),
+            messages,
+        );
+
+        let reply = match self.stream_reply(id, &req).await {
+            Ok(reply) => reply,
+            Err(e) => {
+                // the turn never happened, don't leave the question dangling in the history
+                self.history.pop();
+                return Err(RpcError::new(SERVER_ERROR, e.to_string()));
+            }
+        };
+
+        self.history.push(chat::Prompt {
+            role: Cow::Owned(chat::MessageRoles::Assistant.to_string()),
+            content: Cow::Owned(reply.clone()),
+        });
+        Ok(json!({ "content": reply }))
+    }
+
+    /// Run the turn, sending each piece of the reply on as a `delta` while it comes in
+    async fn stream_reply(
+        &mut self,
+        id: &Value,
+        req: &chat::ChatRequest<'static>,
+    ) -> anyhow::Result<String> {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let call = self.engine.chat(&self.config.host, req, &tx);
+        tokio::pin!(call);
+
+        let mut reply = String::new();
+        let mut exchange = None;
+        let done = loop {
+            let event = tokio::select! {
+                done = &mut call => break done,
+                Some(event) = rx.recv() => event,
+            };
+            forward(&mut self.out, id, event, &mut reply, &mut exchange).await?;
+        };
+        // the last of it can still be queued when the call returns
+        while let Ok(event) = rx.try_recv() {
+            forward(&mut self.out, id, event, &mut reply, &mut exchange).await?;
+        }
+
+        match done {
+            engine::Done::Streamed(Err(e)) | engine::Done::Whole(Err(e)) => return Err(e.into()),
+            // a stream that stalled out keeps what arrived, as it does in the chat UI
+            engine::Done::Streamed(Ok(_)) | engine::Done::Whole(Ok(_)) => {}
+        }
+        if let Some(exchange) = exchange
+            && let Some(status) = exchange.status.as_deref().filter(|s| !s.starts_with('2'))
+        {
+            let body = String::from_utf8_lossy(&exchange.response_body);
+            anyhow::bail!("the host answered {status}: {}", body.trim());
+        }
+        Ok(reply)
+    }
+
+    fn run_tool(&self, params: RunToolParams) -> Result<Value, RpcError> {
+        let result = match chat::AssistantTool::from_name(&params.name) {
+            Some(tool) => tool::execute(&self.sandbox, &self.config, tool, &params.arguments),
+            None => {
+                return Err(RpcError::new(
+                    INVALID_PARAMS,
+                    format!("unknown tool: {}", params.name),
+                ));
+            }
+        };
+
+        result
+            .map(|result| json!({ "result": result }))
+            .map_err(|e| RpcError::new(SERVER_ERROR, e))
+    }
+
+    async fn respond(&mut self, id: Value, result: Result<Value, RpcError>) -> std::io::Result<()> {
+        let message = match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(e) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": e.code, "message": e.message },
+            }),
+        };
+        write_line(&mut self.out, &message).await
+    }
+}
+
+/// Pass one event of a turn on to the plugin, deltas are all it hears about
+async fn forward(
+    out: &mut (impl AsyncWrite + Unpin),
+    id: &Value,
+    event: engine::Event,
+    reply: &mut String,
+    exchange: &mut Option<Box<chat::Exchange>>,
+) -> std::io::Result<()> {
+    match event {
+        engine::Event::Delta(content) => {
+            reply.push_str(&content);
+            notify(out, "delta", json!({ "id": id, "content": content })).await?;
+        }
+        engine::Event::Exchange(e) => *exchange = Some(e),
+        engine::Event::Usage(_) | engine::Event::Retrying(..) | engine::Event::Stalled(_) => {}
+    }
+    Ok(())
+}
+
+async fn notify(
+    out: &mut (impl AsyncWrite + Unpin),
+    method: &str,
+    params: Value,
+) -> std::io::Result<()> {
+    let message = json!({ "jsonrpc": "2.0", "method": method, "params": params });
+    write_line(out, &message).await
+}
+
+async fn write_line(out: &mut (impl AsyncWrite + Unpin), message: &Value) -> std::io::Result<()> {
+    let mut line = message.to_string();
+    line.push('\n');
+    out.write_all(line.as_bytes()).await?;
+    // plugins read line by line, so every message has to go out as soon as it is written
+    out.flush().await
+}
+
+fn params<T: serde::de::DeserializeOwned>(params: &Value) -> Result<T, RpcError> {
+    serde_json::from_value(params.clone()).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pipe `requests` through a session on a mock host answering from `script`, one JSON
+    /// message per line written back
+    async fn pipe(name: &str, script: &str, requests: &str) -> Vec<Value> {
+        let path = std::env::temp_dir().join(format!("oxiai-serve-{name}-{}", std::process::id()));
+        std::fs::write(&path, script).unwrap();
+        let host = crate::mock::start(&path).await.unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let config = config::Config {
+            host,
+            ..Default::default()
+        };
+        let sandbox = tool::Sandbox::new(&std::env::temp_dir()).unwrap();
+        let mut out = Vec::new();
+        serve(config, sandbox, requests.as_bytes(), &mut out)
+            .await
+            .unwrap();
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn send_streams_deltas_then_the_reply() {
+        let script = r#"[{"reply": "Black bears climb.", "chunks": ["Black bears ", "climb."]}]"#;
+        let request =
+            r#"{"jsonrpc": "2.0", "id": 7, "method": "send", "params": {"content": "bears?"}}"#;
+        let out = pipe("send", script, &format!("{request}\n")).await;
+
+        let (result, notes) = out.split_last().unwrap();
+        let deltas: Vec<_> = notes
+            .iter()
+            .map(|note| {
+                assert_eq!(note["method"], "delta");
+                assert_eq!(note["params"]["id"], 7);
+                note["params"]["content"].as_str().unwrap()
+            })
+            .collect();
+        assert_eq!(deltas, ["Black bears ", "climb.", ""]);
+        assert_eq!(result["id"], 7);
+        assert_eq!(result["result"]["content"], "Black bears climb.");
+    }
+
+    #[tokio::test]
+    async fn a_failed_turn_is_an_error_and_the_next_still_goes() {
+        let script = r#"[
+            {"error": {"status": 500, "message": "model runner crashed"}},
+            {"reply": "second try"}
+        ]"#;
+        let requests = concat!(
+            r#"{"jsonrpc": "2.0", "id": 1, "method": "send", "params": {"content": "one"}}"#,
+            "\n",
+            r#"{"jsonrpc": "2.0", "id": 2, "method": "send", "params": {"content": "two"}}"#,
+            "\n",
+        );
+        let out = pipe("error", script, requests).await;
+
+        assert_eq!(out[0]["id"], 1);
+        assert_eq!(out[0]["error"]["code"], SERVER_ERROR);
+        let message = out[0]["error"]["message"].as_str().unwrap();
+        assert!(message.contains("model runner crashed"), "{message}");
+        assert_eq!(out.last().unwrap()["result"]["content"], "second try");
+    }
+
+    #[tokio::test]
+    async fn unknown_methods_and_bad_json_get_errors() {
+        let out = pipe(
+            "methods",
+            "[]",
+            "{\"id\": 1, \"method\": \"fly\"}\nnot json\n",
+        )
+        .await;
+        assert_eq!(out[0]["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(out[1]["id"], Value::Null);
+        assert_eq!(out[1]["error"]["code"], PARSE_ERROR);
+    }
+}
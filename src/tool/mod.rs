@@ -0,0 +1,800 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+
+use crate::{chat, config};
+
+/// How far down `get_dir_tree` looks when the model doesn't say, and the most it may ask for
+const TREE_DEPTH: usize = 3;
+const TREE_MAX_DEPTH: usize = 8;
+/// Lines a `get_dir_tree` listing stops at, the rest are only counted
+const TREE_ENTRIES: usize = 200;
+
+const SUMMARIZE_PROMPT: &str = r#"You condense tool output for another assistant. Summarize the text below in plain prose, at most {limit} characters. Keep every name, number, date and fact that could help answer: "{question}". Reply with the summary only."#;
+
+/// Cut `text` down to at most `limit` characters, noting how much was dropped so the model
+/// knows it isn't seeing everything
+pub fn truncate_result(text: &str, limit: usize) -> Cow<'_, str> {
+    match text.char_indices().nth(limit) {
+        None => Cow::Borrowed(text),
+        Some((end, _)) => {
+            let dropped = text[end..].chars().count();
+            Cow::Owned(format!(
+                "{}\n[... truncated {dropped} characters ...]",
+                &text[..end]
+            ))
+        }
+    }
+}
+
+/// A one-off request asking `model` to shrink an oversized tool result, sent without the json
+/// format constraint so the reply is just the summary text
+pub fn summary_request(
+    model: &str,
+    result: &str,
+    question: &str,
+    limit: usize,
+) -> chat::ChatRequest<'static> {
+    let system_prompt = SUMMARIZE_PROMPT
+        .replace("{limit}", &limit.to_string())
+        .replace("{question}", question);
+
+    chat::ChatRequest {
+        model: Cow::Owned(model.to_string()),
+        messages: vec![
+            chat::Prompt {
+                role: Cow::Borrowed("system"),
+                content: Cow::Owned(system_prompt),
+            },
+            chat::Prompt {
+                role: Cow::Borrowed("user"),
+                content: Cow::Owned(result.to_string()),
+            },
+        ],
+        stream: false,
+        format: None,
+        stop: vec![],
+        options: None,
+    }
+}
+
+/// The argument names a tool reads, the ones in `required` it can't run without
+struct ArgSpec {
+    required: &'static [&'static str],
+    optional: &'static [&'static str],
+}
+
+fn arg_spec(tool: chat::AssistantTool) -> ArgSpec {
+    let (required, optional): (&[&str], &[&str]) = match tool {
+        chat::AssistantTool::WikiSearch | chat::AssistantTool::WebSearch => (&["query"], &[]),
+        chat::AssistantTool::GetDateTime => (&[], &["format", "timezone"]),
+        chat::AssistantTool::GetDirectoryTree => (&[], &["path", "depth"]),
+        chat::AssistantTool::GetFileContents => {
+            (&["path"], &["start", "end", "start_line", "end_line"])
+        }
+        chat::AssistantTool::InvalidTool => (&[], &[]),
+    };
+    ArgSpec { required, optional }
+}
+
+/// Check `args` against what `tool` takes before running it. A missing argument is an error the
+/// model gets back, ones the tool doesn't know are only logged, models add extras often enough.
+pub fn check_args(tool: chat::AssistantTool, args: &HashMap<String, String>) -> Result<(), String> {
+    let spec = arg_spec(tool);
+    if let Some(missing) = spec.required.iter().find(|name| !args.contains_key(**name)) {
+        return Err(format!("missing required argument: {missing}"));
+    }
+
+    let mut unknown = args
+        .keys()
+        .filter(|k| !spec.required.contains(&k.as_str()) && !spec.optional.contains(&k.as_str()))
+        .collect::<Vec<_>>();
+    if !unknown.is_empty() {
+        unknown.sort();
+        crate::log!(Warn, "{tool} ignored unknown arguments {unknown:?}");
+    }
+    Ok(())
+}
+
+/// Run one tool call, an `Err` is meant to go back to the model as the result so it can try again
+pub fn execute(
+    sandbox: &Sandbox,
+    config: &config::Config,
+    tool: chat::AssistantTool,
+    args: &HashMap<String, String>,
+) -> Result<String, String> {
+    check_args(tool, args)?;
+    match tool {
+        chat::AssistantTool::WikiSearch => wiki_search(args),
+        chat::AssistantTool::GetDateTime => get_date_time(args),
+        chat::AssistantTool::GetDirectoryTree => get_dir_tree(sandbox, args),
+        // it needs the network, so it is run by the worker when a provider is set up
+        chat::AssistantTool::WebSearch => Err("web_search has no search provider set up, \
+            answer from what you know and say so"
+            .to_string()),
+        chat::AssistantTool::GetFileContents => {
+            get_file_contents(sandbox, args, config.file_size_limit)
+        }
+        //TODO: the rest of the tools
+        tool => Err(format!("{tool} is not available yet")),
+    }
+}
+
+/// `wiki_search`: only knows the two pages bundled with the binary for now
+fn wiki_search(args: &HashMap<String, String>) -> Result<String, String> {
+    //HACK: fake it for now, until I figure out how to grab a web page and display it in a way the model understands
+    let query = args
+        .get("query")
+        .ok_or("missing required argument: query")?;
+    Ok(match query.as_str() {
+        "American Crow" => include_str!("../data/american_crow_wikipedia.md"),
+        "Black Bear" => include_str!("../data/black_bear_wikipedia.md"),
+        _ => "Search failed to return any valid data",
+    }
+    .to_string())
+}
+
+/// `get_date_time`: now, in local time unless `timezone` says otherwise, as RFC 3339 or in the
+/// strftime `format` given
+fn get_date_time(args: &HashMap<String, String>) -> Result<String, String> {
+    // no tz database to go on, so only the local zone, UTC and fixed offsets are known
+    let now = chrono::Local::now();
+    let now = match args.get("timezone").map(|tz| tz.trim()) {
+        None | Some("") => now.fixed_offset(),
+        Some(tz) if tz.eq_ignore_ascii_case("local") => now.fixed_offset(),
+        Some(tz) if tz.eq_ignore_ascii_case("utc") || tz == "Z" => {
+            now.with_timezone(&chrono::Utc).fixed_offset()
+        }
+        Some(tz) => {
+            let offset = tz.parse::<chrono::FixedOffset>().map_err(|_| {
+                format!("unknown timezone {tz}, use local, UTC or an offset like +02:00")
+            })?;
+            now.with_timezone(&offset)
+        }
+    };
+
+    match args.get("format") {
+        None => Ok(now.to_rfc3339_opts(chrono::SecondsFormat::Secs, false)),
+        Some(format) => {
+            let items = chrono::format::StrftimeItems::new(format)
+                .parse()
+                .map_err(|_| format!("invalid format {format:?}, expected a strftime pattern"))?;
+            Ok(now.format_with_items(items.into_iter()).to_string())
+        }
+    }
+}
+
+/// Directory the file tools are confined to, everything they touch must resolve inside it
+pub struct Sandbox {
+    root: PathBuf,
+}
+
+impl Sandbox {
+    pub fn new(root: &Path) -> std::io::Result<Self> {
+        Ok(Self {
+            root: root.canonicalize()?,
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Resolve a path the model gave us, relative paths are taken from the root. Canonicalizing
+    /// follows `..` and symlinks, so anything that ends up outside the root is refused.
+    ///
+    /// Drive letters and UNC shares are turned away before joining, `join` would otherwise swap
+    /// the root out for them (or, for `C:foo`, for whatever the current directory on C: is). The
+    /// final check compares canonical paths component by component, so on a case-insensitive
+    /// filesystem a differently cased root can only ever refuse a path, never let one out.
+    pub fn resolve(&self, path: &str) -> Result<PathBuf, String> {
+        if Path::new(path)
+            .components()
+            .any(|c| matches!(c, Component::Prefix(_)))
+        {
+            crate::log!(Warn, "sandbox refused {path:?}, it names a drive or share");
+            return Err(format!(
+                "{path} names a drive or network share, use a path inside the working directory"
+            ));
+        }
+
+        let candidate = self.root.join(path);
+        let resolved = candidate
+            .canonicalize()
+            .map_err(|e| format!("unable to open {path}: {e}"))?;
+
+        if !resolved.starts_with(&self.root) {
+            crate::log!(
+                Warn,
+                "sandbox refused {path:?}, it resolves to {} outside {}",
+                resolved.display(),
+                self.root.display()
+            );
+            return Err(format!(
+                "refused {path}, it is outside the working directory"
+            ));
+        }
+        Ok(resolved)
+    }
+}
+
+/// `get_file_contents`: the whole file, or with `start`/`end` (1-based, inclusive) just those
+/// lines, numbered so the model can refer back to them. `start_line`/`end_line` are taken too,
+/// models reach for those names often enough.
+pub fn get_file_contents(
+    sandbox: &Sandbox,
+    args: &HashMap<String, String>,
+    max_bytes: u64,
+) -> Result<String, String> {
+    let path = args.get("path").ok_or("missing required argument: path")?;
+    let resolved = sandbox.resolve(path)?;
+    let start = line_arg(args, "start")?.or(line_arg(args, "start_line")?);
+    let end = line_arg(args, "end")?.or(line_arg(args, "end_line")?);
+
+    if let (Some(start), Some(end)) = (start, end)
+        && start > end
+    {
+        return Err(format!(
+            "invalid range: start ({start}) is after end ({end})"
+        ));
+    }
+
+    let metadata =
+        std::fs::metadata(&resolved).map_err(|e| format!("unable to open {path}: {e}"))?;
+    if !metadata.is_file() {
+        return Err(format!("{path} is not a file"));
+    }
+
+    if start.is_none() && end.is_none() {
+        if metadata.len() > max_bytes {
+            return Err(format!(
+                "{path} is {} bytes, over the {max_bytes} byte limit, ask for a line range with start and end",
+                metadata.len()
+            ));
+        }
+        let bytes = std::fs::read(&resolved).map_err(|e| format!("unable to read {path}: {e}"))?;
+        return String::from_utf8(bytes).map_err(|_| format!("{path} is not valid UTF-8 text"));
+    }
+
+    read_line_range(&resolved, path, start.unwrap_or(1), end, max_bytes)
+}
+
+fn read_line_range(
+    resolved: &Path,
+    path: &str,
+    start: usize,
+    end: Option<usize>,
+    max_bytes: u64,
+) -> Result<String, String> {
+    use std::io::BufRead;
+
+    let file = std::fs::File::open(resolved).map_err(|e| format!("unable to open {path}: {e}"))?;
+    let reader = std::io::BufReader::new(file);
+
+    let mut lines = Vec::new();
+    for (idx, line) in reader.lines().enumerate() {
+        let number = idx + 1;
+        if end.is_some_and(|end| number > end) {
+            break;
+        }
+        let line = line.map_err(|e| match e.kind() {
+            std::io::ErrorKind::InvalidData => format!("{path} is not valid UTF-8 text"),
+            _ => format!("unable to read {path}: {e}"),
+        })?;
+        if number >= start {
+            lines.push((number, line));
+        }
+    }
+
+    // a range running off the end of the file is clamped to what is there
+    let Some(last) = lines.last().map(|(n, _)| *n) else {
+        return Err(format!("{path} has no lines from {start} onwards"));
+    };
+
+    let width = last.to_string().len();
+    let mut out = String::new();
+    for (number, line) in lines {
+        let numbered = format!("{number:>width$} | {line}\n");
+        if (out.len() + numbered.len()) as u64 > max_bytes {
+            out.push_str(&format!(
+                "[... stopped at line {number}, over the {max_bytes} byte limit ...]\n"
+            ));
+            break;
+        }
+        out.push_str(&numbered);
+    }
+    Ok(out)
+}
+
+/// Tool arguments always arrive as strings, line numbers need to be coerced
+fn line_arg(args: &HashMap<String, String>, key: &str) -> Result<Option<usize>, String> {
+    match args.get(key).map(|v| v.trim()) {
+        None | Some("") => Ok(None),
+        Some(v) => match v.parse::<usize>() {
+            Ok(0) => Err(format!(
+                "{key} must be 1 or more, lines are numbered from 1"
+            )),
+            Ok(n) => Ok(Some(n)),
+            Err(_) => Err(format!("{key} must be a line number, got `{v}`")),
+        },
+    }
+}
+
+/// `get_dir_tree`: the directory at `path` (the root if not given) as a tree, `depth` levels down
+fn get_dir_tree(sandbox: &Sandbox, args: &HashMap<String, String>) -> Result<String, String> {
+    let path = args.get("path").map_or(".", |p| p.as_str());
+    let depth = match args.get("depth").map(|v| v.trim()) {
+        None | Some("") => TREE_DEPTH,
+        Some(v) => match v.parse::<usize>() {
+            Ok(0) | Err(_) => return Err(format!("depth must be a number of levels, got `{v}`")),
+            Ok(n) => n.min(TREE_MAX_DEPTH),
+        },
+    };
+
+    let resolved = sandbox.resolve(path)?;
+    if !resolved.is_dir() {
+        return Err(format!("{path} is not a directory"));
+    }
+    let tree = dir_tree_at(sandbox, &resolved, depth, TREE_ENTRIES)?;
+    Ok(match tree.is_empty() {
+        true => format!("{path} is empty"),
+        false => tree,
+    })
+}
+
+/// The sandbox root as an indented tree, `depth` levels down and at most `max_entries` lines.
+/// `.git` and whatever the root's `.gitignore` lists are left out, symlinks are listed but never
+/// followed so nothing outside the root is walked.
+pub fn dir_tree(sandbox: &Sandbox, depth: usize, max_entries: usize) -> Result<String, String> {
+    dir_tree_at(sandbox, &sandbox.root, depth, max_entries)
+}
+
+/// `dir_tree` starting from `dir`, which must already be resolved inside the sandbox
+fn dir_tree_at(
+    sandbox: &Sandbox,
+    dir: &Path,
+    depth: usize,
+    max_entries: usize,
+) -> Result<String, String> {
+    let ignore = IgnoreRules::load(&sandbox.root);
+    // ignore rules are matched from the root, indentation from where the listing starts
+    let relative = dir.strip_prefix(&sandbox.root).unwrap_or(Path::new(""));
+    let mut lines = Vec::new();
+    let mut skipped = 0;
+    walk_tree(
+        dir,
+        relative,
+        0,
+        depth,
+        &ignore,
+        &mut lines,
+        &mut skipped,
+        max_entries,
+    )?;
+
+    let mut out = lines.join("\n");
+    if skipped > 0 {
+        out.push_str(&format!("\n[... {skipped} more entries ...]"));
+    }
+    Ok(out)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn walk_tree(
+    dir: &Path,
+    relative: &Path,
+    level: usize,
+    depth: usize,
+    ignore: &IgnoreRules,
+    lines: &mut Vec<String>,
+    skipped: &mut usize,
+    max_entries: usize,
+) -> Result<(), String> {
+    let mut entries = std::fs::read_dir(dir)
+        .map_err(|e| format!("unable to list {}: {e}", dir.display()))?
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let is_dir = entry.file_type().ok()?.is_dir();
+            Some((entry.file_name().to_string_lossy().into_owned(), is_dir))
+        })
+        .filter(|(name, is_dir)| name != ".git" && !ignore.ignores(&relative.join(name), *is_dir))
+        .collect::<Vec<_>>();
+    // directories first, each group alphabetical
+    entries.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+    let indent = "  ".repeat(level);
+    for (name, is_dir) in entries {
+        if lines.len() >= max_entries {
+            *skipped += 1;
+            continue;
+        }
+        match is_dir {
+            true => {
+                lines.push(format!("{indent}{name}/"));
+                if depth > 1 {
+                    walk_tree(
+                        &dir.join(&name),
+                        &relative.join(&name),
+                        level + 1,
+                        depth - 1,
+                        ignore,
+                        lines,
+                        skipped,
+                        max_entries,
+                    )?;
+                }
+            }
+            false => lines.push(format!("{indent}{name}")),
+        }
+    }
+    Ok(())
+}
+
+/// The plain patterns of a `.gitignore`: names, `*`/`?` globs, a leading `/` to anchor to the
+/// root and a trailing `/` for directories only. Negations and `**` are beyond it, such lines
+/// are skipped, so at worst something ignored gets listed.
+struct IgnoreRules {
+    patterns: Vec<(String, bool)>,
+}
+
+impl IgnoreRules {
+    fn load(root: &Path) -> Self {
+        let raw = std::fs::read_to_string(root.join(".gitignore")).unwrap_or_default();
+        let patterns = raw
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#') && !l.starts_with('!'))
+            .filter(|l| !l.contains("**"))
+            .map(|l| match l.strip_suffix('/') {
+                Some(dir) => (dir.to_string(), true),
+                None => (l.to_string(), false),
+            })
+            .collect();
+        Self { patterns }
+    }
+
+    fn ignores(&self, relative: &Path, is_dir: bool) -> bool {
+        let path = relative.to_string_lossy().replace('\\', "/");
+        let name = path.rsplit('/').next().unwrap_or(&path);
+
+        self.patterns.iter().any(|(pattern, dir_only)| {
+            if *dir_only && !is_dir {
+                return false;
+            }
+            match pattern.strip_prefix('/') {
+                Some(anchored) => wildcard(anchored, &path),
+                // a pattern with a slash in the middle is relative to the root too
+                None if pattern.contains('/') => wildcard(pattern, &path),
+                None => wildcard(pattern, name),
+            }
+        })
+    }
+}
+
+/// Glob match with `*` (any run within one path segment) and `?` (one character)
+fn wildcard(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some('?') if text[t] != '/' => {
+                p += 1;
+                t += 1;
+            }
+            Some(&c) if c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((bp, bt)) if text[bt] != '/' => {
+                    p = bp + 1;
+                    t = bt + 1;
+                    backtrack = Some((bp, bt + 1));
+                }
+                _ => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory under the system temp dir, removed again when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("oxiai-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// `outside/secret.txt` and `root/inside/file.txt`, with the sandbox on `root`
+    fn sandbox(name: &str) -> (TempDir, Sandbox) {
+        let dir = TempDir::new(name);
+        std::fs::create_dir_all(dir.0.join("outside")).unwrap();
+        std::fs::write(dir.0.join("outside/secret.txt"), "secret").unwrap();
+        std::fs::create_dir_all(dir.0.join("root/inside")).unwrap();
+        std::fs::write(dir.0.join("root/inside/file.txt"), "hello").unwrap();
+        let sandbox = Sandbox::new(&dir.0.join("root")).unwrap();
+        (dir, sandbox)
+    }
+
+    #[test]
+    fn resolve_allows_paths_inside_the_root() {
+        let (_dir, sandbox) = sandbox("inside");
+        let resolved = sandbox.resolve("inside/file.txt").unwrap();
+        assert_eq!(resolved, sandbox.root().join("inside/file.txt"));
+        // wandering out and back in again is still inside
+        assert!(sandbox.resolve("inside/../inside/./file.txt").is_ok());
+        assert!(sandbox.resolve(".").is_ok());
+    }
+
+    #[test]
+    fn resolve_refuses_parent_traversal() {
+        let (_dir, sandbox) = sandbox("traversal");
+        let err = sandbox.resolve("../outside/secret.txt").unwrap_err();
+        assert!(err.contains("outside the working directory"), "{err}");
+        assert!(sandbox.resolve("inside/../../outside/secret.txt").is_err());
+    }
+
+    #[test]
+    fn resolve_refuses_absolute_paths_outside_the_root() {
+        let (dir, sandbox) = sandbox("absolute");
+        let secret = dir.0.join("outside/secret.txt").canonicalize().unwrap();
+        let err = sandbox.resolve(secret.to_str().unwrap()).unwrap_err();
+        assert!(err.contains("outside the working directory"), "{err}");
+    }
+
+    #[test]
+    fn resolve_allows_absolute_paths_inside_the_root() {
+        let (_dir, sandbox) = sandbox("absolute-inside");
+        let file = sandbox.root().join("inside/file.txt");
+        assert_eq!(sandbox.resolve(file.to_str().unwrap()).unwrap(), file);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolve_refuses_symlinks_out_of_the_root() {
+        let (dir, sandbox) = sandbox("symlink");
+        std::os::unix::fs::symlink(dir.0.join("outside"), sandbox.root().join("escape")).unwrap();
+        let err = sandbox.resolve("escape/secret.txt").unwrap_err();
+        assert!(err.contains("outside the working directory"), "{err}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolve_follows_symlinks_that_stay_inside() {
+        let (_dir, sandbox) = sandbox("symlink-inside");
+        std::os::unix::fs::symlink(sandbox.root().join("inside"), sandbox.root().join("link"))
+            .unwrap();
+        let resolved = sandbox.resolve("link/file.txt").unwrap();
+        assert_eq!(resolved, sandbox.root().join("inside/file.txt"));
+    }
+
+    #[test]
+    fn resolve_reports_a_missing_leaf() {
+        let (_dir, sandbox) = sandbox("missing");
+        let err = sandbox.resolve("inside/nope.txt").unwrap_err();
+        assert!(err.starts_with("unable to open inside/nope.txt"), "{err}");
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn resolve_refuses_drive_and_share_prefixes() {
+        let (_dir, sandbox) = sandbox("prefix");
+        for path in [r"C:\Windows", r"C:foo", r"\\server\share\file"] {
+            let err = sandbox.resolve(path).unwrap_err();
+            assert!(err.contains("drive or network share"), "{path}: {err}");
+        }
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn resolve_takes_a_drive_letter_as_a_plain_name_off_windows() {
+        // there are no prefixes here, `C:foo` is just a file name that doesn't exist
+        let (_dir, sandbox) = sandbox("prefix");
+        let err = sandbox.resolve("C:foo").unwrap_err();
+        assert!(err.starts_with("unable to open"), "{err}");
+    }
+
+    fn args(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn check_args_reports_the_missing_argument() {
+        let err = check_args(
+            chat::AssistantTool::GetFileContents,
+            &args(&[("start", "1")]),
+        )
+        .unwrap_err();
+        assert_eq!(err, "missing required argument: path");
+        let err = check_args(chat::AssistantTool::WebSearch, &args(&[])).unwrap_err();
+        assert_eq!(err, "missing required argument: query");
+    }
+
+    #[test]
+    fn check_args_lets_optional_and_unknown_arguments_through() {
+        assert!(check_args(chat::AssistantTool::GetDateTime, &args(&[])).is_ok());
+        let given = args(&[("path", "a.txt"), ("end_line", "4"), ("verbose", "yes")]);
+        assert!(check_args(chat::AssistantTool::GetFileContents, &given).is_ok());
+    }
+
+    #[test]
+    fn every_tool_has_an_argument_spec() {
+        for tool in chat::AssistantTool::ALL {
+            let spec = arg_spec(tool);
+            // a name is either required or optional, never both
+            assert!(
+                spec.required
+                    .iter()
+                    .all(|name| !spec.optional.contains(name)),
+                "{tool}"
+            );
+        }
+    }
+
+    #[test]
+    fn execute_checks_arguments_before_running() {
+        let (_dir, sandbox) = sandbox("execute");
+        let config = config::Config::default();
+        let err = execute(
+            &sandbox,
+            &config,
+            chat::AssistantTool::GetFileContents,
+            &args(&[]),
+        )
+        .unwrap_err();
+        assert_eq!(err, "missing required argument: path");
+    }
+
+    #[test]
+    fn truncate_result_counts_what_it_drops() {
+        assert_eq!(truncate_result("short", 10), "short");
+        let cut = truncate_result("héllo wörld", 5);
+        assert_eq!(cut, "héllo\n[... truncated 6 characters ...]");
+    }
+
+    fn numbered_file(name: &str) -> (TempDir, Sandbox) {
+        let (dir, sandbox) = sandbox(name);
+        let text = (1..=12).map(|n| format!("line {n}\n")).collect::<String>();
+        std::fs::write(sandbox.root().join("lines.txt"), text).unwrap();
+        (dir, sandbox)
+    }
+
+    #[test]
+    fn get_file_contents_reads_a_whole_file() {
+        let (_dir, sandbox) = sandbox("whole");
+        let text = get_file_contents(&sandbox, &args(&[("path", "inside/file.txt")]), 1024);
+        assert_eq!(text.unwrap(), "hello");
+    }
+
+    #[test]
+    fn get_file_contents_numbers_a_line_range() {
+        let (_dir, sandbox) = numbered_file("range");
+        let given = args(&[("path", "lines.txt"), ("start", "9"), ("end", "10")]);
+        assert_eq!(
+            get_file_contents(&sandbox, &given, 1024).unwrap(),
+            " 9 | line 9\n10 | line 10\n"
+        );
+        // the names models tend to use work too, and a range past the end is clamped
+        let given = args(&[
+            ("path", "lines.txt"),
+            ("start_line", "12"),
+            ("end_line", "40"),
+        ]);
+        assert_eq!(
+            get_file_contents(&sandbox, &given, 1024).unwrap(),
+            "12 | line 12\n"
+        );
+    }
+
+    #[test]
+    fn get_file_contents_checks_the_range() {
+        let (_dir, sandbox) = numbered_file("bad-range");
+        let read = |pairs: &[(&str, &str)]| get_file_contents(&sandbox, &args(pairs), 1024);
+        assert!(read(&[("path", "lines.txt"), ("start", "0")]).is_err());
+        assert!(read(&[("path", "lines.txt"), ("start", "x")]).is_err());
+        assert!(read(&[("path", "lines.txt"), ("start", "5"), ("end", "2")]).is_err());
+        assert!(read(&[("path", "lines.txt"), ("start", "13")]).is_err());
+    }
+
+    #[test]
+    fn get_file_contents_keeps_to_the_size_limit() {
+        let (_dir, sandbox) = numbered_file("limit");
+        let err = get_file_contents(&sandbox, &args(&[("path", "lines.txt")]), 10).unwrap_err();
+        assert!(err.contains("over the 10 byte limit"), "{err}");
+
+        let given = args(&[("path", "lines.txt"), ("start", "1")]);
+        let text = get_file_contents(&sandbox, &given, 40).unwrap();
+        assert!(text.ends_with("over the 40 byte limit ...]\n"), "{text}");
+    }
+
+    #[test]
+    fn dir_tree_lists_directories_first_and_skips_ignored() {
+        let (_dir, sandbox) = sandbox("tree");
+        let root = sandbox.root();
+        std::fs::create_dir_all(root.join(".git")).unwrap();
+        std::fs::create_dir_all(root.join("target/debug")).unwrap();
+        std::fs::write(root.join(".gitignore"), "target/\n").unwrap();
+        std::fs::write(root.join("a.txt"), "").unwrap();
+
+        let tree = dir_tree(&sandbox, 3, 100).unwrap();
+        assert_eq!(tree, "inside/\n  file.txt\n.gitignore\na.txt");
+    }
+
+    #[test]
+    fn dir_tree_stops_at_depth_and_entry_limit() {
+        let (_dir, sandbox) = sandbox("tree-limits");
+        assert_eq!(dir_tree(&sandbox, 1, 100).unwrap(), "inside/");
+        for n in 0..5 {
+            std::fs::write(sandbox.root().join(format!("{n}.txt")), "").unwrap();
+        }
+        let tree = dir_tree(&sandbox, 1, 2).unwrap();
+        assert_eq!(tree, "inside/\n0.txt\n[... 4 more entries ...]");
+    }
+
+    #[test]
+    fn get_dir_tree_checks_its_depth() {
+        let (_dir, sandbox) = sandbox("tree-depth");
+        let err = get_dir_tree(&sandbox, &args(&[("depth", "0")])).unwrap_err();
+        assert_eq!(err, "depth must be a number of levels, got `0`");
+        let err = get_dir_tree(&sandbox, &args(&[("path", "inside/file.txt")])).unwrap_err();
+        assert_eq!(err, "inside/file.txt is not a directory");
+    }
+
+    #[test]
+    fn get_dir_tree_refuses_paths_outside_the_root() {
+        let (dir, sandbox) = sandbox("tree-outside");
+        let err = get_dir_tree(&sandbox, &args(&[("path", "../outside")])).unwrap_err();
+        assert!(err.contains("outside the working directory"), "{err}");
+        let outside = dir.0.join("outside").canonicalize().unwrap();
+        let err =
+            get_dir_tree(&sandbox, &args(&[("path", outside.to_str().unwrap())])).unwrap_err();
+        assert!(err.contains("outside the working directory"), "{err}");
+    }
+
+    #[test]
+    fn resolve_follows_the_case_rules_of_the_filesystem() {
+        let (dir, sandbox) = sandbox("case");
+        // which kind of filesystem the temp dir is on decides what a differently cased path means
+        let insensitive = dir.0.join("ROOT/INSIDE/FILE.TXT").exists();
+        match insensitive {
+            true => {
+                let resolved = sandbox.resolve("INSIDE/file.txt").unwrap();
+                assert!(resolved.starts_with(sandbox.root()));
+                // another spelling of the root is still the root, not a way out of it
+                let upper = Sandbox::new(&dir.0.join("ROOT")).unwrap();
+                assert!(upper.resolve("inside/file.txt").is_ok());
+                assert!(upper.resolve("../OUTSIDE/secret.txt").is_err());
+            }
+            false => {
+                let err = sandbox.resolve("INSIDE/file.txt").unwrap_err();
+                assert!(err.starts_with("unable to open"), "{err}");
+            }
+        }
+        assert!(sandbox.resolve("../OUTSIDE/secret.txt").is_err());
+    }
+}
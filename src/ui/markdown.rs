@@ -0,0 +1,106 @@
+//! Markdown styling for the rows of a chat reply. Kept apart from the rest of the ui so the
+//! benches can build it on its own.
+
+use ratatui::{
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+};
+
+/// One already wrapped row of a reply with its markdown styled: code fences and what is between
+/// them, headings, bullets, and `**bold**` and `` `code` `` spans. Styling works row by row, so a
+/// span wrapped onto two rows is left as written rather than guessed at.
+pub fn markdown_line<'a>(row: &'a str, in_code: &mut bool) -> Line<'a> {
+    let code = Style::default().fg(Color::Cyan);
+    let trimmed = row.trim_start();
+
+    if trimmed.starts_with("```") {
+        *in_code = !*in_code;
+        return Line::styled(row, Style::default().fg(Color::DarkGray));
+    }
+    if *in_code {
+        return Line::styled(row, code);
+    }
+    if trimmed.starts_with('#') {
+        return Line::styled(row, Style::default().add_modifier(Modifier::BOLD));
+    }
+
+    let mut spans = Vec::new();
+    let indent = &row[..row.len() - trimmed.len()];
+    let mut rest = match trimmed
+        .strip_prefix("- ")
+        .or_else(|| trimmed.strip_prefix("* "))
+    {
+        Some(item) => {
+            spans.push(Span::raw(indent));
+            spans.push(Span::raw("• "));
+            item
+        }
+        None => row,
+    };
+
+    while !rest.is_empty() {
+        let next = [
+            ("**", Style::default().add_modifier(Modifier::BOLD)),
+            ("`", code),
+        ]
+        .into_iter()
+        .filter_map(|(mark, style)| {
+            let start = rest.find(mark)?;
+            let len = rest[start + mark.len()..].find(mark)?;
+            Some((start, mark, len, style))
+        })
+        .min_by_key(|(start, ..)| *start);
+
+        let Some((start, mark, len, style)) = next else {
+            spans.push(Span::raw(rest));
+            break;
+        };
+        let inner = start + mark.len();
+        spans.push(Span::raw(&rest[..start]));
+        spans.push(Span::styled(&rest[inner..inner + len], style));
+        rest = &rest[inner + len + mark.len()..];
+    }
+    Line::from(spans)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(line: &Line<'_>) -> String {
+        line.spans.iter().map(|s| s.content.as_ref()).collect()
+    }
+
+    #[test]
+    fn fences_toggle_code_blocks() {
+        let mut in_code = false;
+        markdown_line("```rust", &mut in_code);
+        assert!(in_code);
+        let line = markdown_line("# not a heading in here", &mut in_code);
+        assert_eq!(line.style.fg, Some(Color::Cyan));
+        markdown_line("  ```", &mut in_code);
+        assert!(!in_code);
+    }
+
+    #[test]
+    fn headings_are_bold() {
+        let line = markdown_line("## Crows", &mut false);
+        assert!(line.style.add_modifier.contains(Modifier::BOLD));
+    }
+
+    #[test]
+    fn bullets_and_spans() {
+        let line = markdown_line("  - a **bold** and `code` item", &mut false);
+        assert_eq!(text(&line), "  • a bold and code item");
+        let bold = line.spans.iter().find(|s| s.content == "bold").unwrap();
+        assert!(bold.style.add_modifier.contains(Modifier::BOLD));
+        let code = line.spans.iter().find(|s| s.content == "code").unwrap();
+        assert_eq!(code.style.fg, Some(Color::Cyan));
+    }
+
+    #[test]
+    fn unclosed_marks_are_left_as_written() {
+        let line = markdown_line("2 ** 3 and a stray `tick", &mut false);
+        assert_eq!(text(&line), "2 ** 3 and a stray `tick");
+    }
+}
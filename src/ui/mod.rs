@@ -1,57 +1,1498 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+
+use crate::chat::{Action, Message, MessageRoles, SendStatus};
+
+use crossterm::event::KeyCode;
+
+mod markdown;
+
+use markdown::markdown_line;
 use ratatui::{
-    layout::{Constraint, Direction, Layout},
-    style::{Color, Style},
+    buffer::Buffer,
+    layout::{Constraint, Direction, Layout, Margin, Position, Rect},
+    style::{Color, Modifier, Style},
     text::{Line, Span},
-    widgets::{Block, Borders, Paragraph},
+    widgets::{
+        Block, Borders, Clear, Paragraph, Scrollbar, ScrollbarOrientation, ScrollbarState,
+        StatefulWidget, Widget,
+    },
 };
 
-pub fn chat_ui(f: &mut ratatui::Frame, app: &crate::App) {
+/// Columns the "> " in front of the prompt takes up
+const PROMPT_PREFIX_WIDTH: u16 = 2;
+/// Tallest the input box grows for a multi-line prompt, further lines scroll
+const INPUT_MAX_LINES: usize = 6;
+const SPINNER: [&str; 4] = ["|", "/", "-", "\\"];
+/// How long each frame of the busy spinner stays up, in milliseconds
+const BUSY_SPINNER_MS: u128 = 120;
+
+/// Narrowest chat pane the tool side panel is drawn next to, anything smaller stays one column
+const SIDE_PANEL_MIN_WIDTH: u16 = 100;
+
+/// Where things were drawn in the last frame
+#[derive(Default)]
+pub struct FrameLayout {
+    pub chat_area: Rect,
+    pub input_area: Rect,
+    /// Width of the pane borders, 0 in the minimal theme
+    pub border: u16,
+    /// First chat line shown at the top of the chat pane
+    pub chat_scroll: usize,
+    /// First prompt line shown in the input box
+    pub input_scroll: usize,
+    /// The chat pane's scroll window
+    pub chat_view: Viewport,
+    /// Which message each chat line belongs to, `None` for the greeting and the reply being streamed
+    pub line_owners: Vec<Option<usize>>,
+    /// Largest valid inner scroll for the selected message
+    pub inner_scroll_max: usize,
+    /// Largest valid sideways scroll of the chat pane, 0 while it wraps
+    pub h_scroll_max: usize,
+    /// The open popup's scroll window
+    pub popup_view: Viewport,
+    /// Urls on screen, drawn over as hyperlinks once the frame is out
+    pub links: Vec<Link>,
+}
+
+/// A url, or the piece of one on a single row, at the spot it was drawn
+pub struct Link {
+    pub position: Position,
+    pub text: String,
+    pub url: String,
+    pub fg: Color,
+    pub bg: Color,
+}
+
+/// Read-only text drawn over the chat, closed with Esc
+pub struct Popup {
+    pub title: String,
+    pub body: String,
+    pub scroll: usize,
+}
+
+impl Popup {
+    pub fn new(title: impl Into<String>, body: impl Into<String>) -> Self {
+        Self {
+            title: title.into(),
+            body: body.into(),
+            scroll: 0,
+        }
+    }
+}
+
+impl FrameLayout {
+    /// Columns between the left edge of the input area and the first character of the prompt
+    pub fn input_text_offset(&self) -> u16 {
+        self.border + PROMPT_PREFIX_WIDTH
+    }
+
+    /// Columns a row of the prompt gets, inside the border and past the prefix
+    pub fn input_width(&self) -> usize {
+        self.input_area
+            .width
+            .saturating_sub(2 * self.border + PROMPT_PREFIX_WIDTH) as usize
+    }
+
+    /// Cursor byte index for a click in the input box, `None` if the click was elsewhere
+    pub fn input_click(&self, prompt: &str, column: u16, row: u16) -> Option<usize> {
+        if !self.input_area.contains(Position::new(column, row)) {
+            return None;
+        }
+        let rows = input_rows(prompt, self.input_width());
+        let line = self.input_scroll + row.saturating_sub(self.input_area.y + self.border) as usize;
+        // a click below the last row lands on the last row
+        let range = rows.get(line).or(rows.last())?.clone();
+        let target = column.saturating_sub(self.input_area.x + self.input_text_offset()) as usize;
+        Some(range.start + column_to_cursor(&prompt[range], target))
+    }
+
+    /// Index of the message under a click in the chat pane
+    pub fn chat_click(&self, column: u16, row: u16) -> Option<usize> {
+        let inner = self.chat_area.inner(Margin::new(self.border, self.border));
+        if !inner.contains(Position::new(column, row)) {
+            return None;
+        }
+        let line = self.chat_scroll + (row - inner.y) as usize;
+        self.line_owners.get(line).copied().flatten()
+    }
+}
+
+/// Size of a `ScrollView`'s window onto its lines, as of the last frame it was drawn in
+#[derive(Default, Clone, Copy)]
+pub struct Viewport {
+    pub height: usize,
+    pub max_offset: usize,
+}
+
+impl Viewport {
+    fn new(len: usize, height: usize) -> Self {
+        Self {
+            height,
+            max_offset: len.saturating_sub(height),
+        }
+    }
+
+    /// Offset after a scrolling key, `None` if the key doesn't scroll
+    pub fn scroll_key(&self, offset: usize, code: KeyCode) -> Option<usize> {
+        let page = self.height.max(1);
+        let offset = match code {
+            KeyCode::Up => offset.saturating_sub(1),
+            KeyCode::Down => offset + 1,
+            KeyCode::PageUp => offset.saturating_sub(page),
+            KeyCode::PageDown => offset + page,
+            KeyCode::Home => 0,
+            KeyCode::End => self.max_offset,
+            _ => return None,
+        };
+        Some(offset.min(self.max_offset))
+    }
+}
+
+/// Already wrapped lines seen through a window that starts `offset` lines in. The offset is
+/// clamped so the window never runs past the end, and a scrollbar is drawn on the right border
+/// once there is more than fits (borderless blocks go without, there is no column to spare).
+pub struct ScrollView<'a> {
+    lines: Vec<Line<'a>>,
+    block: Block<'a>,
+    offset: usize,
+    /// Columns scrolled right, for lines that weren't wrapped to fit
+    h_offset: usize,
+}
+
+impl<'a> ScrollView<'a> {
+    pub fn new(lines: Vec<Line<'a>>, block: Block<'a>) -> Self {
+        Self {
+            lines,
+            block,
+            offset: 0,
+            h_offset: 0,
+        }
+    }
+
+    pub fn offset(mut self, offset: usize) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    pub fn h_offset(mut self, h_offset: usize) -> Self {
+        self.h_offset = h_offset;
+        self
+    }
+
+    /// Furthest right these lines can be scrolled in `area` before the longest runs out
+    pub fn h_offset_max(&self, area: Rect) -> usize {
+        let widest = self.lines.iter().map(Line::width).max().unwrap_or(0);
+        widest.saturating_sub(self.block.inner(area).width as usize)
+    }
+
+    /// The window these lines get when drawn in `area`
+    pub fn viewport(&self, area: Rect) -> Viewport {
+        Viewport::new(self.lines.len(), self.block.inner(area).height as usize)
+    }
+}
+
+impl Widget for ScrollView<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let view = self.viewport(area);
+        let offset = self.offset.min(view.max_offset);
+        let h_offset = self.h_offset.min(self.h_offset_max(area));
+        let bordered = self.block.inner(area).width < area.width;
+
+        Paragraph::new(ratatui::text::Text::from(self.lines))
+            .block(self.block)
+            .scroll((offset as u16, h_offset as u16))
+            .render(area, buf);
+
+        if bordered && view.max_offset > 0 {
+            let mut state = ScrollbarState::new(view.max_offset + 1)
+                .viewport_content_length(view.height)
+                .position(offset);
+            Scrollbar::new(ScrollbarOrientation::VerticalRight)
+                .begin_symbol(None)
+                .end_symbol(None)
+                .render(area.inner(Margin::new(0, 1)), buf, &mut state);
+        }
+    }
+}
+
+/// How many colors the terminal can show
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorDepth {
+    TrueColor,
+    Ansi256,
+    Ansi16,
+}
+
+/// The 16 ANSI colors as xterm draws them, in index order
+const ANSI16: [(Color, (u8, u8, u8)); 16] = [
+    (Color::Black, (0, 0, 0)),
+    (Color::Red, (205, 0, 0)),
+    (Color::Green, (0, 205, 0)),
+    (Color::Yellow, (205, 205, 0)),
+    (Color::Blue, (0, 0, 238)),
+    (Color::Magenta, (205, 0, 205)),
+    (Color::Cyan, (0, 205, 205)),
+    (Color::Gray, (229, 229, 229)),
+    (Color::DarkGray, (127, 127, 127)),
+    (Color::LightRed, (255, 0, 0)),
+    (Color::LightGreen, (0, 255, 0)),
+    (Color::LightYellow, (255, 255, 0)),
+    (Color::LightBlue, (92, 92, 255)),
+    (Color::LightMagenta, (255, 0, 255)),
+    (Color::LightCyan, (0, 255, 255)),
+    (Color::White, (255, 255, 255)),
+];
+
+/// Channel levels of the 6x6x6 cube in the 256 color palette
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+impl ColorDepth {
+    /// `COLORTERM` is the only reliable sign of truecolor, `TERM` of 256 colors, anything else
+    /// gets the 16 every terminal has
+    pub fn detect() -> Self {
+        let colorterm = std::env::var("COLORTERM").unwrap_or_default();
+        let term = std::env::var("TERM").unwrap_or_default();
+        if matches!(colorterm.as_str(), "truecolor" | "24bit") {
+            Self::TrueColor
+        } else if term.contains("256") {
+            Self::Ansi256
+        } else {
+            Self::Ansi16
+        }
+    }
+
+    /// The closest color this terminal can show, named colors are left to the terminal's own palette
+    pub fn fit(self, color: Color) -> Color {
+        match (self, color) {
+            (Self::TrueColor, _) => color,
+            (Self::Ansi256, Color::Rgb(r, g, b)) => Color::Indexed(nearest_256((r, g, b))),
+            (Self::Ansi16, Color::Rgb(r, g, b)) => nearest_16((r, g, b)),
+            (Self::Ansi16, Color::Indexed(idx)) => nearest_16(indexed_rgb(idx)),
+            _ => color,
+        }
+    }
+
+    pub fn fit_buffer(self, buf: &mut Buffer) {
+        if self == Self::TrueColor {
+            return;
+        }
+        for cell in &mut buf.content {
+            cell.fg = self.fit(cell.fg);
+            cell.bg = self.fit(cell.bg);
+        }
+    }
+}
+
+fn distance(a: (u8, u8, u8), b: (u8, u8, u8)) -> u32 {
+    let d = |x: u8, y: u8| (x as i32 - y as i32).pow(2) as u32;
+    d(a.0, b.0) + d(a.1, b.1) + d(a.2, b.2)
+}
+
+fn nearest_16(rgb: (u8, u8, u8)) -> Color {
+    ANSI16
+        .iter()
+        .min_by_key(|(_, ansi)| distance(rgb, *ansi))
+        .map_or(Color::Reset, |(color, _)| *color)
+}
+
+/// Index of the closest entry in the cube or the gray ramp, whichever is nearer
+fn nearest_256(rgb: (u8, u8, u8)) -> u8 {
+    let level = |v: u8| {
+        (0..CUBE_LEVELS.len())
+            .min_by_key(|&i| (CUBE_LEVELS[i] as i32 - v as i32).abs())
+            .unwrap_or(0)
+    };
+    let (r, g, b) = (level(rgb.0), level(rgb.1), level(rgb.2));
+    let cube = (16 + 36 * r + 6 * g + b) as u8;
+
+    let average = (rgb.0 as u32 + rgb.1 as u32 + rgb.2 as u32) / 3;
+    let gray = (232 + (average.saturating_sub(3) / 10).min(23)) as u8;
+
+    match distance(rgb, indexed_rgb(gray)) < distance(rgb, indexed_rgb(cube)) {
+        true => gray,
+        false => cube,
+    }
+}
+
+/// What xterm draws a 256 color palette index as
+fn indexed_rgb(idx: u8) -> (u8, u8, u8) {
+    match idx {
+        0..=15 => ANSI16[idx as usize].1,
+        16..=231 => {
+            let idx = idx - 16;
+            (
+                CUBE_LEVELS[(idx / 36) as usize],
+                CUBE_LEVELS[(idx / 6 % 6) as usize],
+                CUBE_LEVELS[(idx % 6) as usize],
+            )
+        }
+        _ => {
+            let v = 8 + 10 * (idx - 232);
+            (v, v, v)
+        }
+    }
+}
+
+/// A pane with a border and title, or a bare one in the minimal theme
+fn pane(title: &str, minimal: bool) -> Block<'_> {
+    match minimal {
+        true => Block::default(),
+        false => Block::default().borders(Borders::ALL).title(title),
+    }
+}
+
+/// Byte index in `prompt` of the character drawn at display column `target`, or the end of the
+/// prompt if the column is past it. Wide characters take up more than one column, and clusters
+/// like flags are treated as a single character so the cursor never lands inside one.
+pub fn column_to_cursor(prompt: &str, target: usize) -> usize {
+    use unicode_segmentation::UnicodeSegmentation;
+    use unicode_width::UnicodeWidthStr;
+
+    let mut col = 0;
+    for (idx, g) in prompt.grapheme_indices(true) {
+        let w = g.width();
+        if target < col + w {
+            return idx;
+        }
+        col += w;
+    }
+    prompt.len()
+}
+
+/// Byte ranges of the rows the prompt takes up in an input box `width` columns wide. Lines that
+/// don't fit are broken at the column rather than between words, so text doesn't jump about as
+/// it is typed. A line that fills its last row exactly gets an empty row after it, so the cursor
+/// at its end has somewhere to go that isn't the border.
+pub fn input_rows(prompt: &str, width: usize) -> Vec<std::ops::Range<usize>> {
+    use unicode_segmentation::UnicodeSegmentation;
+    use unicode_width::UnicodeWidthStr;
+
+    let width = width.max(1);
+    let mut rows = Vec::new();
+    let mut offset = 0;
+    for line in prompt.split('\n') {
+        let end = offset + line.len();
+        let mut start = offset;
+        let mut col = 0;
+        for (idx, g) in line.grapheme_indices(true) {
+            let w = g.width();
+            if col + w > width && col > 0 {
+                rows.push(start..offset + idx);
+                start = offset + idx;
+                col = 0;
+            }
+            col += w;
+        }
+        rows.push(start..end);
+        if col >= width {
+            rows.push(end..end);
+        }
+        offset = end + 1;
+    }
+    rows
+}
+
+/// Row of `input_rows` the cursor is on and its column in it. At a break the cursor goes to the
+/// start of the next row, where typing will put the next character.
+pub fn input_cursor(
+    prompt: &str,
+    rows: &[std::ops::Range<usize>],
+    cursor: usize,
+) -> (usize, usize) {
+    use unicode_width::UnicodeWidthStr;
+
+    let row = rows.iter().rposition(|r| r.start <= cursor).unwrap_or(0);
+    let col = rows.get(row).map_or(0, |r| prompt[r.start..cursor].width());
+    (row, col)
+}
+
+/// Tool calls and their results, the messages that go in the side panel when it is open
+fn is_activity(message: &Message) -> bool {
+    matches!(message.content.action, Action::Tool(_))
+}
+
+/// Rows for `text`, wrapped to `width` or, with `wrap` off, only broken where it has newlines
+fn text_rows(text: &str, width: usize, wrap: bool) -> Vec<String> {
+    match wrap {
+        true => wrap_text(text, width),
+        false => text.split('\n').map(str::to_string).collect(),
+    }
+}
+
+/// Laid out rows of each message in one pane, kept between frames. A message's text never
+/// changes once it is in, so only new messages, ones whose marks changed and a new pane shape
+/// cost a rewrap, a long session doesn't redo all of its history every frame.
+#[derive(Default)]
+pub struct PaneCache {
+    /// Width and wrap mode the rows were laid out for
+    shape: (usize, bool),
+    rows: HashMap<u64, CachedRows>,
+    /// Messages found already laid out, and ones that had to be, since the last clear
+    hits: u64,
+    misses: u64,
+}
+
+struct CachedRows {
+    /// The marks drawn in front of the text when these rows were made
+    marks: (bool, SendStatus),
+    rows: Vec<String>,
+    /// Leading rows that only hold the label, a reply's markdown starts after them
+    label_rows: usize,
+}
+
+/// A `PaneCache` for each pane messages are drawn in
+#[derive(Default)]
+pub struct RenderCache {
+    pub chat: PaneCache,
+    pub side: PaneCache,
+}
+
+impl RenderCache {
+    /// Entries, hit rate and the bytes of text held, for /cache
+    pub fn stats(&self) -> String {
+        let panes = [("chat", &self.chat), ("side panel", &self.side)];
+        panes
+            .iter()
+            .map(|(name, pane)| {
+                let lookups = pane.hits + pane.misses;
+                let rate = match lookups {
+                    0 => 0.0,
+                    n => pane.hits as f64 * 100.0 / n as f64,
+                };
+                let bytes = pane
+                    .rows
+                    .values()
+                    .flat_map(|c| &c.rows)
+                    .map(String::len)
+                    .sum::<usize>();
+                format!(
+                    "{name}: {} messages, {rate:.1}% hits, {}",
+                    pane.rows.len(),
+                    human_bytes(bytes)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+}
+
+impl PaneCache {
+    /// Bring the rows of the messages `include` picks up to date, dropping the ones for messages
+    /// that are gone
+    fn update(
+        &mut self,
+        app: &crate::AppState,
+        include: &impl Fn(&Message) -> bool,
+        width: usize,
+        wrap: bool,
+    ) {
+        if self.shape != (width, wrap) {
+            self.shape = (width, wrap);
+            self.rows.clear();
+        }
+
+        let mut seen = HashSet::new();
+        for m in app.messages.iter().filter(|m| include(m)) {
+            seen.insert(m.id);
+            let marks = (m.bookmarked, m.status);
+            if self.rows.get(&m.id).is_some_and(|c| c.marks == marks) {
+                self.hits += 1;
+                continue;
+            }
+            self.misses += 1;
+
+            let mark = match m.bookmarked {
+                true => "★ ",
+                false => "",
+            };
+            let sent = match (m.role == MessageRoles::User, m.status) {
+                (false, _) => "",
+                (true, SendStatus::Pending) => " …",
+                (true, SendStatus::Sent) => " ✓",
+                (true, SendStatus::Failed) => " ✗",
+            };
+            let label = format!("{mark}{}{sent}:", app.role_label(m));
+            // a reply's label gets its own row, so its first line can be told apart as a fence
+            // or a heading like any other
+            let (rows, label_rows) = match is_prose(m) {
+                true => {
+                    let mut rows = text_rows(&label, width, wrap);
+                    let label_rows = rows.len();
+                    rows.extend(text_rows(&m.render_text(), width, wrap));
+                    (rows, label_rows)
+                }
+                false => (
+                    text_rows(&format!("{label} {}", m.render_text()), width, wrap),
+                    0,
+                ),
+            };
+            self.rows.insert(
+                m.id,
+                CachedRows {
+                    marks,
+                    rows,
+                    label_rows,
+                },
+            );
+        }
+        self.rows.retain(|id, _| seen.contains(id));
+    }
+}
+
+/// Wrapped and capped lines for the messages `include` picks, along with the line the selected
+/// message starts on if it was one of them. `owners` gets the message index of every line.
+fn message_lines<'c>(
+    app: &crate::AppState,
+    include: impl Fn(&Message) -> bool,
+    cache: &'c mut PaneCache,
+    width: usize,
+    wrap: bool,
+    owners: &mut Vec<Option<usize>>,
+    inner_scroll_max: &mut usize,
+) -> (Vec<Line<'c>>, Option<usize>) {
+    cache.update(app, &include, width, wrap);
+    let cache = &*cache;
+
+    let mut lines = Vec::new();
+    let mut selected_start = None;
+
+    for (idx, m) in app.messages.iter().enumerate().filter(|(_, m)| include(m)) {
+        let Some(cached) = cache.rows.get(&m.id) else {
+            continue;
+        };
+        if app.is_folded(m) {
+            continue;
+        }
+
+        if app.selected == Some(idx) {
+            selected_start = Some(lines.len());
+            let style = Style::default().bg(Color::DarkGray);
+            let (rows, max_offset) =
+                cap_rows(&cached.rows, app.config.max_message_lines, app.inner_scroll);
+            *inner_scroll_max = max_offset;
+            owners.extend(rows.iter().map(|_| Some(idx)));
+            let label_rows = cached
+                .label_rows
+                .saturating_sub(app.inner_scroll.min(max_offset));
+            push_rows(&mut lines, rows, style, is_prose(m).then_some(label_rows));
+        } else {
+            let style = match m.role == MessageRoles::Error {
+                true => Style::default().fg(Color::Red),
+                false => Style::default(),
+            };
+            let (rows, _) = cap_rows(&cached.rows, app.config.max_message_lines, 0);
+            owners.extend(rows.iter().map(|_| Some(idx)));
+            push_rows(
+                &mut lines,
+                rows,
+                style,
+                is_prose(m).then_some(cached.label_rows),
+            );
+        }
+
+        if app.collapsed.contains(&m.id) {
+            let folded = app
+                .messages
+                .iter()
+                .filter(|other| other.parent_id == Some(m.id) && include(other))
+                .count();
+            owners.push(Some(idx));
+            lines.push(Line::styled(
+                format!("  ▸ {folded} more in this turn (Ctrl+O to unfold)"),
+                Style::default().fg(Color::Gray),
+            ));
+        }
+    }
+
+    (lines, selected_start)
+}
+
+/// Chat replies from the model get markdown styling, everything else is shown as it is
+fn is_prose(message: &Message) -> bool {
+    message.role == MessageRoles::Assistant && message.content.action == Action::Chat
+}
+
+/// `markdown` is how many of the rows are the label when they are a reply's, `None` otherwise
+fn push_rows<'c>(
+    lines: &mut Vec<Line<'c>>,
+    rows: Vec<Cow<'c, str>>,
+    style: Style,
+    markdown: Option<usize>,
+) {
+    let Some(mut label_rows) = markdown else {
+        lines.extend(rows.into_iter().map(|r| Line::styled(r, style)));
+        return;
+    };
+
+    let mut in_code = false;
+    for row in rows {
+        lines.push(match row {
+            Cow::Borrowed(row) if label_rows > 0 => {
+                label_rows -= 1;
+                Line::styled(row, style)
+            }
+            Cow::Borrowed(row) => markdown_line(row, &mut in_code).patch_style(style),
+            // only the "more lines" markers cap_rows adds are owned, they aren't part of the reply
+            Cow::Owned(row) => Line::styled(row, style.fg(Color::Gray)),
+        });
+    }
+}
+
+/// Caret for the end of a reply that is still streaming in, empty while blinked off
+fn stream_caret(app: &crate::AppState) -> &'static str {
+    use crate::config::StreamCursor;
+    let phase = match app.config.stream_cursor_blink_ms {
+        0 => 0,
+        ms => (app.started.elapsed().as_millis() / ms as u128) as usize,
+    };
+    match app.config.stream_cursor {
+        StreamCursor::None => "",
+        StreamCursor::Spinner => SPINNER[phase % SPINNER.len()],
+        _ if phase % 2 == 1 => "",
+        StreamCursor::Block => "▍",
+        StreamCursor::Underscore => "_",
+    }
+}
+
+/// Pin to the bottom, unless there is a selected message to keep in view
+fn pinned_scroll(len: usize, height: usize, selected_start: Option<usize>) -> usize {
+    let bottom = len.saturating_sub(height);
+    selected_start.map_or(bottom, |start| start.min(bottom))
+}
+
+pub fn chat_ui(f: &mut ratatui::Frame, app: &crate::AppState) {
+    use unicode_width::UnicodeWidthStr;
+
+    let border = match app.minimal {
+        true => 0,
+        false => 1,
+    };
+    // the box is as wide as the screen less the margin, its border and the prefix
+    let input_width = f
+        .area()
+        .width
+        .saturating_sub(2 + 2 * border + PROMPT_PREFIX_WIDTH) as usize;
+    let input_rows = input_rows(&app.prompt, input_width);
+    let input_lines = input_rows.len().min(INPUT_MAX_LINES);
     let chunks = Layout::default()
         .direction(Direction::Vertical)
         .margin(1)
-        .constraints([Constraint::Min(1), Constraint::Length(3)].as_ref())
+        .constraints(
+            [
+                Constraint::Min(1),
+                Constraint::Length(input_lines as u16 + 2 * border),
+                Constraint::Length(1),
+            ]
+            .as_ref(),
+        )
         .split(f.area());
 
-    let chat_messages: Vec<Line> = app
-        .messages
-        .iter()
-        .map(|m| {
-            Line::from(Span::raw(format!(
-                "{}: {}",
-                m.role.to_string(),
-                m.to_string()
-            )))
-        })
-        .collect();
-
-    let messages_block = Paragraph::new(ratatui::text::Text::from(chat_messages))
-        .block(Block::default().borders(Borders::ALL).title("Chat"))
-        .wrap(ratatui::widgets::Wrap { trim: true })
-        .scroll((
-            app.messages
-                .len()
-                .saturating_sub((chunks[0].height - 2) as usize) as u16,
-            0,
-        ));
+    let mut layout = app.layout.borrow_mut();
+    layout.links.clear();
+    layout.line_owners.clear();
+    layout.inner_scroll_max = 0;
+
+    // tool calls and results move out of the conversation when there is room for a second column
+    let split = app.side_panel && chunks[0].width >= SIDE_PANEL_MIN_WIDTH;
+    let (chat_area, side_area) = match split {
+        true => {
+            let columns = Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints([Constraint::Percentage(60), Constraint::Percentage(40)])
+                .split(chunks[0]);
+            (columns[0], Some(columns[1]))
+        }
+        false => (chunks[0], None),
+    };
 
-    f.render_widget(messages_block, chunks[0]);
+    let inner_width = chat_area.width.saturating_sub(2 * border) as usize;
+    let inner_height = chat_area.height.saturating_sub(2 * border) as usize;
 
-    let input_text = if app.waiting {
-        format!("> {} (waiting...)", &app.prompt)
-    } else {
-        format!("> {}", app.prompt)
+    let mut line_owners = Vec::new();
+    let mut cache = app.render_cache.borrow_mut();
+    let cache = &mut *cache;
+    let (mut chat_lines, selected_start) = message_lines(
+        app,
+        |m| !split || !is_activity(m),
+        &mut cache.chat,
+        inner_width,
+        app.wrap,
+        &mut line_owners,
+        &mut layout.inner_scroll_max,
+    );
+
+    if app.messages.is_empty()
+        && app.streaming.is_empty()
+        && let Some(greeting) = app.config.greeting()
+    {
+        let text = format!("{}: {}", app.config.assistant_name, greeting);
+        let rows = text_rows(&text, inner_width, app.wrap);
+        line_owners.extend(rows.iter().map(|_| None));
+        chat_lines.extend(
+            rows.into_iter()
+                .map(|r| Line::styled(r, Style::default().fg(Color::Gray))),
+        );
+    }
+
+    let caret = stream_caret(app);
+    for partial in app.streaming.values() {
+        // laid out like the reply it will become, label on a row of its own
+        let label = format!("{}:", app.config.assistant_name);
+        let mut rows = text_rows(&label, inner_width, app.wrap);
+        rows.extend(text_rows(
+            &format!("{partial}{caret}"),
+            inner_width,
+            app.wrap,
+        ));
+        line_owners.extend(rows.iter().map(|_| None));
+        chat_lines.extend(rows.into_iter().map(Line::raw));
+    }
+
+    // a selection is kept in view before anything else, then wherever the chat was scrolled back to
+    let scroll = match (selected_start, app.scrollback) {
+        (None, Some(top)) => top.min(chat_lines.len().saturating_sub(inner_height)),
+        _ => pinned_scroll(chat_lines.len(), inner_height, selected_start),
     };
+    layout.chat_view = Viewport::new(chat_lines.len(), inner_height);
+    layout.line_owners = line_owners;
+    layout.chat_area = chat_area;
+    layout.input_area = chunks[1];
+    layout.border = border;
+    layout.chat_scroll = scroll;
+
+    let chat_view = ScrollView::new(chat_lines, pane("Chat", app.minimal))
+        .offset(scroll)
+        .h_offset(app.h_scroll);
+    layout.h_scroll_max = chat_view.h_offset_max(chat_area);
+    f.render_widget(chat_view, chat_area);
+
+    if let Some(side_area) = side_area {
+        // only the chat pane maps clicks back to messages, these owners are thrown away
+        let (side_lines, selected_start) = message_lines(
+            app,
+            is_activity,
+            &mut cache.side,
+            side_area.width.saturating_sub(2 * border) as usize,
+            // the side panel has no sideways scroll, it always wraps
+            true,
+            &mut Vec::new(),
+            &mut layout.inner_scroll_max,
+        );
+        let side_height = side_area.height.saturating_sub(2 * border) as usize;
+        let scroll = pinned_scroll(side_lines.len(), side_height, selected_start);
+
+        f.render_widget(
+            ScrollView::new(side_lines, pane("Tools", app.minimal)).offset(scroll),
+            side_area,
+        );
+    }
+
+    // goes by the worker's tasks rather than `waiting`, so it stops the moment the last one ends
+    let spinner =
+        SPINNER[(app.started.elapsed().as_millis() / BUSY_SPINNER_MS) as usize % SPINNER.len()];
+    let mut input_text = String::new();
+    for (n, row) in input_rows.iter().enumerate() {
+        // continuation rows line up under the first, past the "> "
+        let prefix = match n {
+            0 => "> ",
+            _ => "\n  ",
+        };
+        input_text.push_str(prefix);
+        input_text.push_str(&app.prompt[row.clone()]);
+    }
+    match (app.busy.is_busy(), app.queued.len()) {
+        (false, _) => {}
+        (true, 0) => input_text.push_str(&format!(" ({spinner} waiting...)")),
+        (true, queued) => input_text.push_str(&format!(" ({spinner} waiting..., {queued} queued)")),
+    }
+
+    // the input box follows the cursor once the prompt has more lines than it shows
+    let (cursor_line, cursor_col) = input_cursor(&app.prompt, &input_rows, app.cursor);
+    let input_scroll = cursor_line.saturating_sub(INPUT_MAX_LINES - 1);
+    layout.input_scroll = input_scroll;
 
+    // slash commands get checked as they are typed, green if Enter would run them, red if not
+    let (input_color, input_title) = match crate::chat::validate_command(&app.prompt) {
+        None => (Color::Yellow, "Input".to_string()),
+        Some(Ok(())) => (Color::Green, "Input".to_string()),
+        Some(Err(reason)) => (Color::Red, format!("Input ({reason})")),
+    };
+    let input_block =
+        pane(&input_title, app.minimal).border_style(Style::default().fg(input_color));
+    // without a border to color the prompt itself shows it
+    let text_color = match app.minimal {
+        true => input_color,
+        false => Color::Yellow,
+    };
     let input = Paragraph::new(input_text)
-        .style(Style::default().fg(Color::Yellow))
-        .block(Block::default().borders(Borders::ALL).title("Input"));
+        .style(Style::default().fg(text_color))
+        .block(input_block)
+        .scroll((input_scroll as u16, 0));
     f.render_widget(input, chunks[1]);
 
-    use ratatui::layout::Position;
+    let backend = app.config.backend.as_str();
+    let mut connection_text = format!("{backend} {} ({})", app.host, app.connection);
+    if app.config.is_paid(&app.host) {
+        connection_text = format!("paid {connection_text}");
+    }
+    if let Some(left) = app.budget_remaining() {
+        connection_text = format!("{left} tokens left | {connection_text}");
+    }
+    if let Some(totals) = app.totals_text() {
+        connection_text = format!("{totals} | {connection_text}");
+    }
+    if let Some(stats) = app.nerd_stats() {
+        connection_text = format!("{stats} | {connection_text}");
+    }
+    let status_chunks = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints(
+            [
+                Constraint::Min(1),
+                Constraint::Length(connection_text.width() as u16),
+            ]
+            .as_ref(),
+        )
+        .split(chunks[2]);
+
+    if let Some(search) = &app.search {
+        let found = search.current(&app.history).unwrap_or_default();
+        let search_line = Paragraph::new(format!("(reverse-i-search)'{}': {found}", search.query))
+            .style(Style::default().fg(Color::Yellow));
+        f.render_widget(search_line, status_chunks[0]);
+    } else if let Some(status) = &app.status {
+        let status_line =
+            Paragraph::new(format!("{} {}", status.at.format("%H:%M:%S"), status.text))
+                .style(Style::default().fg(Color::Gray));
+        f.render_widget(status_line, status_chunks[0]);
+    }
+
+    let connection_color = match app.connection {
+        crate::ConnectionState::Checking => Color::Gray,
+        crate::ConnectionState::Connected(_) => Color::Green,
+        crate::ConnectionState::Unreachable(_) => Color::Red,
+    };
+    let connection = Paragraph::new(connection_text)
+        .style(Style::default().fg(connection_color))
+        .alignment(ratatui::layout::Alignment::Right);
+    f.render_widget(connection, status_chunks[1]);
+
     f.set_cursor_position(Position::new(
-        // the +3 comes from the 3 'characters' of space between the terminal edge and the text location
-        // this places the text cursor after the last entered character
-        chunks[1].x + app.prompt.len() as u16 + 3,
-        chunks[1].y + 1,
+        // columns, not bytes, so wide and multi-byte characters don't push the cursor off
+        chunks[1].x + layout.input_text_offset() + cursor_col as u16,
+        chunks[1].y + border + (cursor_line - input_scroll) as u16,
     ));
+
+    // the tab bar sits in the top margin, so a single tab looks the same as before there were any
+    if app.tabs.len() > 1 {
+        let area = f.area();
+        let bar = Rect::new(area.x + 1, area.y, area.width.saturating_sub(2), 1);
+        let spans = (0..app.tabs.len())
+            .map(|idx| {
+                let style = match idx == app.active_tab {
+                    true => Style::default()
+                        .fg(Color::Black)
+                        .bg(Color::Yellow)
+                        .add_modifier(Modifier::BOLD),
+                    false => Style::default().fg(Color::Gray),
+                };
+                Span::styled(format!(" {} ", app.tab_title(idx)), style)
+            })
+            .collect::<Vec<_>>();
+        f.render_widget(Paragraph::new(Line::from(spans)), bar);
+    }
+
+    if let Some(popup) = &app.popup {
+        draw_popup(f, popup, &mut layout);
+    }
+
+    // every color on screen goes through here, so nothing drawn above needs to know the terminal
+    app.color_depth.fit_buffer(f.buffer_mut());
+
+    // a popup would cover parts of urls and leave links to whatever half is still showing
+    if app.popup.is_none() && app.config.hyperlinks {
+        let panes = std::iter::once(chat_area).chain(side_area);
+        for pane in panes {
+            let inner = pane.inner(Margin::new(border, border));
+            layout.links.extend(find_links(f.buffer_mut(), inner));
+        }
+    }
+}
+
+/// Urls drawn in `area`. A url wrapped onto the next row is followed there, each row's piece
+/// becomes its own link to the whole url.
+fn find_links(buf: &Buffer, area: Rect) -> Vec<Link> {
+    const SCHEMES: [&str; 2] = ["https://", "http://"];
+
+    // one char per cell, so an index into a row is also a column offset
+    let rows = area
+        .rows()
+        .map(|row| {
+            row.columns()
+                .map(|p| buf[p].symbol().chars().next().unwrap_or(' '))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let is_url_char = |c: &char| !c.is_whitespace() && !c.is_control() && !"<>\"'`│".contains(*c);
+
+    let mut links = Vec::new();
+    for (y, row) in rows.iter().enumerate() {
+        let text = row.iter().collect::<String>();
+        let mut from = 0;
+        while let Some(start) = SCHEMES
+            .iter()
+            .filter_map(|s| text[from..].find(s).map(|idx| from + idx))
+            .min()
+        {
+            // `find` gives a byte offset, the cell is however many chars come before it
+            let start = text[..start].chars().count();
+            let len = row[start..].iter().take_while(|c| is_url_char(c)).count();
+            let mut pieces = vec![(y, start, len)];
+
+            let (mut row_y, mut end) = (y, start + len);
+            while end == row.len() && row_y + 1 < rows.len() {
+                row_y += 1;
+                let more = rows[row_y].iter().take_while(|c| is_url_char(c)).count();
+                if more == 0 {
+                    break;
+                }
+                pieces.push((row_y, 0, more));
+                end = more;
+            }
+
+            let mut url = pieces
+                .iter()
+                .flat_map(|&(y, x, len)| rows[y][x..x + len].iter())
+                .collect::<String>();
+            // sentence punctuation right after a url isn't part of it, a closing paren only
+            // belongs to it if the url opened one, as wikipedia's do
+            let mut cut = 0;
+            while let Some(last) = url.chars().last() {
+                let unbalanced = last == ')' && url.matches(')').count() > url.matches('(').count();
+                if !".,;:!?]".contains(last) && !unbalanced {
+                    break;
+                }
+                url.pop();
+                cut += 1;
+            }
+            while cut > 0 {
+                let Some(last) = pieces.last_mut() else { break };
+                let take = cut.min(last.2);
+                last.2 -= take;
+                cut -= take;
+                if last.2 == 0 {
+                    pieces.pop();
+                }
+            }
+
+            for &(y, x, len) in &pieces {
+                let position = Position::new(area.x + x as u16, area.y + y as u16);
+                links.push(Link {
+                    position,
+                    text: rows[y][x..x + len].iter().collect(),
+                    url: url.clone(),
+                    fg: buf[position].fg,
+                    bg: buf[position].bg,
+                });
+            }
+
+            if row_y > y {
+                break;
+            }
+            from = text
+                .char_indices()
+                .nth(start + len.max(1))
+                .map_or(text.len(), |(idx, _)| idx);
+        }
+    }
+    links
+}
+
+/// Draw the frame's urls again over themselves, wrapped in OSC 8 so the terminal makes them
+/// clickable. The text and colors don't change, ratatui's idea of the screen stays right.
+pub fn write_links(out: &mut impl std::io::Write, links: &[Link]) -> std::io::Result<()> {
+    // ratatui's own crossterm, its colors only convert into that version's
+    use ratatui::crossterm::{self, cursor, style};
+
+    if links.is_empty() {
+        return Ok(());
+    }
+    crossterm::queue!(out, cursor::SavePosition)?;
+    for link in links {
+        crossterm::queue!(
+            out,
+            cursor::MoveTo(link.position.x, link.position.y),
+            style::SetForegroundColor(link.fg.into()),
+            style::SetBackgroundColor(link.bg.into()),
+            style::Print(format!(
+                "\x1b]8;;{}\x1b\\{}\x1b]8;;\x1b\\",
+                link.url, link.text
+            )),
+        )?;
+    }
+    crossterm::queue!(out, style::ResetColor, cursor::RestorePosition)?;
+    out.flush()
+}
+
+/// Put `text` on the clipboard with OSC 52. The terminal does the copying, so it works over ssh
+/// and needs no display server, but one without OSC 52 ignores it and there is no telling.
+pub fn copy_to_clipboard(text: &str) -> std::io::Result<()> {
+    use std::io::{IsTerminal, Write};
+
+    let mut out = std::io::stdout();
+    if !out.is_terminal() {
+        return Err(std::io::Error::other("stdout is not a terminal"));
+    }
+    write!(out, "\x1b]52;c;{}\x07", base64(text.as_bytes()))?;
+    out.flush()
+}
+
+/// Standard base64 with padding, all OSC 52 needs
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char),
+                false => out.push('='),
+            }
+        }
+    }
+    out
+}
+
+fn draw_popup(f: &mut ratatui::Frame, popup: &Popup, layout: &mut FrameLayout) {
+    let full = f.area();
+    let area = Rect::new(
+        full.x + full.width / 10,
+        full.y + full.height / 10,
+        full.width - full.width / 5,
+        full.height - full.height / 5,
+    );
+    let rows = wrap_text(&popup.body, area.width.saturating_sub(2) as usize);
+    let title = format!(
+        "{} (Up/Down/PgUp/PgDn to scroll, Esc to close)",
+        popup.title
+    );
+    let view = ScrollView::new(
+        rows.into_iter().map(Line::raw).collect(),
+        Block::default().borders(Borders::ALL).title(title),
+    )
+    .offset(popup.scroll);
+    layout.popup_view = view.viewport(area);
+
+    f.render_widget(Clear, area);
+    f.render_widget(view, area);
+}
+
+/// Cut `rows` down to at most `cap` rows starting at `offset`, replacing what was cut with a
+/// marker line so it's clear there is more to see. Returns the rows along with the largest valid
+/// offset, `offset` itself is clamped to that.
+pub fn cap_rows(rows: &[String], cap: Option<usize>, offset: usize) -> (Vec<Cow<'_, str>>, usize) {
+    let cap = match cap {
+        Some(cap) if cap > 0 && rows.len() > cap => cap,
+        _ => return (rows.iter().map(|r| Cow::Borrowed(r.as_str())).collect(), 0),
+    };
+
+    let max_offset = rows.len() - cap;
+    let offset = offset.min(max_offset);
+    let hidden_below = max_offset - offset;
+
+    let mut capped = Vec::with_capacity(cap + 2);
+    if offset > 0 {
+        capped.push(Cow::Owned(format!("  ↑ {offset} more lines")));
+    }
+    capped.extend(
+        rows[offset..offset + cap]
+            .iter()
+            .map(|r| Cow::Borrowed(r.as_str())),
+    );
+    if hidden_below > 0 {
+        capped.push(Cow::Owned(format!(
+            "  ↓ {hidden_below} more lines (Alt+Up/Down to select, Shift+Up/Down to scroll)"
+        )));
+    }
+
+    (capped, max_offset)
+}
+
+/// Word wrap `text` into rows no wider than `width` display columns, breaking overlong words
+pub fn wrap_text(text: &str, width: usize) -> Vec<String> {
+    use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+    let width = width.max(1);
+    let mut rows = Vec::new();
+
+    for line in text.split('\n') {
+        let mut row = String::new();
+        let mut row_width = 0;
+
+        for word in line.split_inclusive(' ') {
+            // start a new row if the word won't fit on this one
+            if row_width > 0 && row_width + word.trim_end_matches(' ').width() > width {
+                rows.push(std::mem::take(&mut row).trim_end().to_string());
+                row_width = 0;
+            }
+
+            for c in word.chars() {
+                let w = c.width().unwrap_or(0);
+                if row_width + w > width {
+                    // spaces at the end of a row just disappear
+                    if c == ' ' {
+                        continue;
+                    }
+                    rows.push(std::mem::take(&mut row));
+                    row_width = 0;
+                }
+                row.push(c);
+                row_width += w;
+            }
+        }
+        rows.push(row.trim_end().to_string());
+    }
+
+    rows
+}
+
+/// Byte count in the largest unit that keeps it above 1, e.g. `12.3 KiB`
+pub fn human_bytes(bytes: usize) -> String {
+    const UNITS: [&str; 3] = ["KiB", "MiB", "GiB"];
+
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reply_opening_with_a_fence_is_styled_as_code() {
+        let rows = ["Assistant:", "```rust", "let x = 1;", "```", "after"];
+        let mut lines = Vec::new();
+        let rows = rows.iter().map(|r| Cow::Borrowed(*r)).collect();
+        push_rows(&mut lines, rows, Style::default(), Some(1));
+
+        assert_eq!(lines[0].style.fg, None);
+        assert_eq!(lines[2].style.fg, Some(Color::Cyan));
+        // the closing fence ends the block rather than opening one
+        assert_eq!(lines[4].style.fg, None);
+    }
+
+    #[test]
+    fn label_rows_are_not_markdown() {
+        let mut lines = Vec::new();
+        push_rows(
+            &mut lines,
+            vec![Cow::Borrowed("# bot:"), Cow::Borrowed("hello")],
+            Style::default(),
+            Some(1),
+        );
+        assert!(!lines[0].style.add_modifier.contains(Modifier::BOLD));
+    }
+
+    fn row_texts(prompt: &str, width: usize) -> Vec<&str> {
+        input_rows(prompt, width)
+            .into_iter()
+            .map(|r| &prompt[r])
+            .collect()
+    }
+
+    #[test]
+    fn input_rows_break_at_the_column() {
+        assert_eq!(row_texts("hello world", 4), ["hell", "o wo", "rld"]);
+        assert_eq!(row_texts("ab\ncd", 10), ["ab", "cd"]);
+        assert_eq!(row_texts("", 10), [""]);
+    }
+
+    #[test]
+    fn input_rows_add_a_row_after_a_full_line() {
+        assert_eq!(row_texts("abcd", 4), ["abcd", ""]);
+        assert_eq!(row_texts("abcd\nx", 4), ["abcd", "", "x"]);
+    }
+
+    #[test]
+    fn input_rows_keep_wide_characters_whole() {
+        // each of these takes two columns, a third can't fit on a row of five
+        assert_eq!(row_texts("日本語です", 5), ["日本", "語で", "す"]);
+    }
+
+    #[test]
+    fn input_cursor_follows_the_rows() {
+        let prompt = "hello world";
+        let rows = input_rows(prompt, 4);
+        assert_eq!(input_cursor(prompt, &rows, 0), (0, 0));
+        assert_eq!(input_cursor(prompt, &rows, 2), (0, 2));
+        // at a break the cursor is at the start of the next row
+        assert_eq!(input_cursor(prompt, &rows, 4), (1, 0));
+        assert_eq!(input_cursor(prompt, &rows, prompt.len()), (2, 3));
+
+        let prompt = "abcd";
+        let rows = input_rows(prompt, 4);
+        assert_eq!(input_cursor(prompt, &rows, 4), (1, 0));
+
+        let prompt = "日本語";
+        let rows = input_rows(prompt, 10);
+        assert_eq!(input_cursor(prompt, &rows, "日本".len()), (0, 4));
+    }
+
+    #[test]
+    fn column_to_cursor_counts_display_columns() {
+        assert_eq!(column_to_cursor("hello", 0), 0);
+        assert_eq!(column_to_cursor("hello", 3), 3);
+        assert_eq!(column_to_cursor("hello", 99), 5);
+        // either column of a wide character lands in front of it
+        let prompt = "a日b";
+        assert_eq!(column_to_cursor(prompt, 1), 1);
+        assert_eq!(column_to_cursor(prompt, 2), 1);
+        assert_eq!(column_to_cursor(prompt, 3), 1 + "日".len());
+    }
+
+    #[test]
+    fn column_to_cursor_never_splits_a_cluster() {
+        // a flag is two code points drawn as one
+        let prompt = "🇳🇴x";
+        assert_eq!(column_to_cursor(prompt, 1), 0);
+        assert_eq!(column_to_cursor(prompt, 2), "🇳🇴".len());
+    }
+
+    #[test]
+    fn indexed_rgb_follows_the_xterm_palette() {
+        assert_eq!(indexed_rgb(16), (0, 0, 0));
+        assert_eq!(indexed_rgb(196), (255, 0, 0));
+        assert_eq!(indexed_rgb(231), (255, 255, 255));
+        assert_eq!(indexed_rgb(232), (8, 8, 8));
+        assert_eq!(indexed_rgb(255), (238, 238, 238));
+    }
+
+    #[test]
+    fn nearest_256_finds_palette_colors_exactly() {
+        for idx in 16..=255u8 {
+            let rgb = indexed_rgb(idx);
+            assert_eq!(indexed_rgb(nearest_256(rgb)), rgb, "{idx}");
+        }
+    }
+
+    #[test]
+    fn nearest_256_prefers_the_gray_ramp_for_grays() {
+        assert_eq!(nearest_256((128, 128, 128)), 244);
+        assert_eq!(nearest_256((250, 10, 5)), 196);
+    }
+
+    #[test]
+    fn wrap_text_breaks_between_words() {
+        assert_eq!(
+            wrap_text("the quick brown fox", 10),
+            ["the quick", "brown fox"]
+        );
+        assert_eq!(wrap_text("a\n\nb", 10), ["a", "", "b"]);
+        // a word longer than the row is broken up rather than left to overflow
+        assert_eq!(wrap_text("abcdefghij", 4), ["abcd", "efgh", "ij"]);
+    }
+
+    #[test]
+    fn pinned_scroll_counts_wrapped_rows() {
+        // a reply wrapped to 30 rows in a 10 row pane shows its last 10
+        assert_eq!(pinned_scroll(30, 10, None), 20);
+        assert_eq!(pinned_scroll(5, 10, None), 0);
+        // a selection is kept in view, but never scrolled past the bottom
+        assert_eq!(pinned_scroll(30, 10, Some(4)), 4);
+        assert_eq!(pinned_scroll(30, 10, Some(25)), 20);
+    }
+
+    #[test]
+    fn cap_rows_marks_what_is_hidden() {
+        let rows = (0..10).map(|i| i.to_string()).collect::<Vec<_>>();
+        let (shown, max_offset) = cap_rows(&rows, Some(4), 3);
+        assert_eq!(max_offset, 6);
+        assert_eq!(shown.len(), 6);
+        assert_eq!(shown[0], "  ↑ 3 more lines");
+        assert_eq!(shown[1], "3");
+        assert!(shown[5].starts_with("  ↓ 3 more lines"));
+
+        let (shown, _) = cap_rows(&rows, None, 0);
+        assert_eq!(shown.len(), 10);
+    }
+
+    #[test]
+    fn nearest_16_picks_the_closest_ansi_color() {
+        assert_eq!(nearest_16((250, 10, 10)), Color::LightRed);
+        assert_eq!(nearest_16((190, 20, 20)), Color::Red);
+        assert_eq!(nearest_16((80, 80, 240)), Color::LightBlue);
+        assert_eq!(nearest_16((120, 120, 130)), Color::DarkGray);
+        // the 256 palette goes through its RGB value on the way down
+        assert_eq!(
+            ColorDepth::Ansi16.fit(Color::Indexed(46)),
+            Color::LightGreen
+        );
+        assert_eq!(ColorDepth::Ansi16.fit(Color::Rgb(0, 0, 0)), Color::Black);
+        // named colors are the terminal's to draw
+        assert_eq!(ColorDepth::Ansi16.fit(Color::Cyan), Color::Cyan);
+    }
+
+    /// Not a correctness test, a timing one for the benches' missing case: laying out a long
+    /// history every frame once the cache has it all. Run it with
+    /// `cargo test --release message_lines_timing -- --ignored --nocapture`.
+    #[test]
+    #[ignore = "timing only"]
+    fn message_lines_timing_with_a_warm_cache() {
+        const ITERATIONS: u32 = 50;
+        for count in [100, 1_000, 10_000] {
+            let (mut app, _rx) = crate::tests::app_with(Default::default());
+            for i in 0..count {
+                let (role, text) = match i % 2 {
+                    0 => (MessageRoles::User, format!("question {i}, about crows")),
+                    _ => (
+                        MessageRoles::Assistant,
+                        format!(
+                            "## Crows\nCrow {i} is **very** clever. {}",
+                            "A crow is a bird. ".repeat(12)
+                        ),
+                    ),
+                };
+                app.push_message(Message::new(
+                    role,
+                    Action::Chat,
+                    HashMap::from([("response".to_string(), text)]),
+                ));
+            }
+
+            let mut cache = PaneCache::default();
+            let layout = |cache: &mut PaneCache| {
+                let (mut owners, mut inner_scroll_max) = (Vec::new(), 0);
+                let (lines, _) = message_lines(
+                    &app,
+                    |_| true,
+                    cache,
+                    80,
+                    true,
+                    &mut owners,
+                    &mut inner_scroll_max,
+                );
+                std::hint::black_box(lines.len());
+            };
+            layout(&mut cache);
+
+            let start = std::time::Instant::now();
+            for _ in 0..ITERATIONS {
+                layout(&mut cache);
+            }
+            let per_frame = start.elapsed() / ITERATIONS;
+            assert_eq!(cache.misses, count as u64);
+            println!("message_lines, {count:>6} messages: {per_frame:?}/frame");
+        }
+    }
+
+    /// `count` numbered lines drawn in a bordered 20x6 box scrolled to `offset`, row by row
+    fn scrolled(count: usize, offset: usize) -> Vec<String> {
+        let area = Rect::new(0, 0, 20, 6);
+        let mut buf = Buffer::empty(area);
+        let lines = (0..count).map(|n| Line::raw(format!("line {n}"))).collect();
+        ScrollView::new(lines, Block::default().borders(Borders::ALL))
+            .offset(offset)
+            .render(area, &mut buf);
+        (0..area.height)
+            .map(|y| (0..area.width).map(|x| buf[(x, y)].symbol()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn scroll_view_clamps_its_offset_and_draws_a_scrollbar() {
+        // 4 rows inside the border, so 16 is as far down as 20 lines go
+        let rows = scrolled(20, 100);
+        assert!(rows[1].starts_with("│line 16"), "{rows:?}");
+        assert!(rows[4].starts_with("│line 19"), "{rows:?}");
+        let bar: String = rows[1..5]
+            .iter()
+            .map(|r| r.chars().last().unwrap())
+            .collect();
+        assert_ne!(bar, "││││", "no scrollbar in {rows:?}");
+
+        let rows = scrolled(20, 0);
+        assert!(rows[1].starts_with("│line 0"), "{rows:?}");
+
+        // lines that all fit get a plain border
+        let rows = scrolled(3, 5);
+        assert!(rows[1].starts_with("│line 0"), "{rows:?}");
+        assert!(rows[1..5].iter().all(|r| r.ends_with('│')), "{rows:?}");
+    }
+
+    #[test]
+    fn human_bytes_picks_the_unit() {
+        assert_eq!(human_bytes(0), "0 B");
+        assert_eq!(human_bytes(1023), "1023 B");
+        assert_eq!(human_bytes(1024), "1.0 KiB");
+        assert_eq!(human_bytes(12_595), "12.3 KiB");
+        assert_eq!(human_bytes(5 * 1024 * 1024), "5.0 MiB");
+        // GiB is the last unit, anything bigger stays in it
+        assert_eq!(human_bytes(3 << 40), "3072.0 GiB");
+    }
+
+    #[test]
+    fn pinned_scroll_shows_the_top_of_a_selection_taller_than_the_pane() {
+        let (mut app, _rx) = crate::tests::app_with(crate::config::Config {
+            max_message_lines: None,
+            ..Default::default()
+        });
+        let tall = (1..=40).map(|i| format!("row {i}")).collect::<Vec<_>>();
+        for (role, text) in [
+            (MessageRoles::User, "before".to_string()),
+            (MessageRoles::Assistant, tall.join("\n")),
+            (MessageRoles::User, "after".to_string()),
+        ] {
+            app.push_message(Message::new(
+                role,
+                Action::Chat,
+                HashMap::from([("response".to_string(), text)]),
+            ));
+        }
+        let screen = |app: &crate::AppState| {
+            let mut terminal =
+                ratatui::Terminal::new(ratatui::backend::TestBackend::new(60, 16)).unwrap();
+            terminal.draw(|f| chat_ui(f, app)).unwrap();
+            let buffer = terminal.backend().buffer();
+            (0..16)
+                .map(|y| (0..60).map(|x| buffer[(x, y)].symbol()).collect::<String>())
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        // a row ending in `text` once the borders, scrollbar and padding are trimmed
+        let shows = |screen: &str, text: &str| {
+            screen
+                .lines()
+                .any(|row| row.trim_matches(['│', '║', '█', ' ']).ends_with(text))
+        };
+
+        // nothing selected pins the bottom: the end of the tall reply and what follows
+        let bottom = screen(&app);
+        assert!(shows(&bottom, "after"));
+        assert!(shows(&bottom, "row 40"));
+        assert!(!shows(&bottom, "row 1"));
+
+        // selecting it scrolls to its first row, the rest runs off the bottom
+        app.select(Some(1));
+        let selected = screen(&app);
+        assert!(shows(&selected, "row 1"));
+        assert!(!shows(&selected, "before"));
+        assert!(!shows(&selected, "after"));
+    }
 }
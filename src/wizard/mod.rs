@@ -0,0 +1,158 @@
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::Path;
+
+use crate::{PROBE_TIMEOUT, chat, config};
+
+/// Whether this looks like a first run: no config at the default location and someone at a
+/// terminal to answer questions
+pub fn needed() -> bool {
+    let missing = config::Config::default_path().is_some_and(|p| !p.exists());
+    missing && std::io::stdin().is_terminal() && std::io::stdout().is_terminal()
+}
+
+/// Ask for the host, the model and the theme, then write them to the default config path.
+///
+/// Every question has a default that an empty line accepts, so Enter all the way through gives
+/// the same setup as having no config at all.
+pub async fn run(input: &mut impl BufRead, output: &mut impl Write) -> anyhow::Result<()> {
+    let path = config::Config::default_path()
+        .ok_or_else(|| anyhow::anyhow!("no home directory to keep the config in"))?;
+    setup(&path, input, output).await
+}
+
+/// The questions of [`run`], writing the answers to `path`
+async fn setup(
+    path: &Path,
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+) -> anyhow::Result<()> {
+    let mut config = config::Config::default();
+
+    writeln!(
+        output,
+        "No config found, a few questions to get set up (Enter keeps the default)."
+    )?;
+
+    config.host = loop {
+        let host = ask(input, output, "Ollama host", &config.host)?;
+        match reqwest::Url::parse(&host) {
+            Ok(_) => break host.trim_end_matches('/').to_string(),
+            Err(e) => writeln!(output, "  {host} is not a valid url: {e}")?,
+        }
+    };
+
+    let client = reqwest::Client::new();
+    match chat::fetch_tags(&client, &config.host, PROBE_TIMEOUT).await {
+        Ok(models) if !models.is_empty() => {
+            writeln!(output, "Models on {}:", config.host)?;
+            for (idx, model) in models.iter().enumerate() {
+                writeln!(output, "  {}) {model}", idx + 1)?;
+            }
+            config.model = pick_model(input, output, &models, &config.model)?;
+        }
+        Ok(_) => {
+            writeln!(output, "{} has no models pulled yet.", config.host)?;
+            config.model = ask(input, output, "Model", &config.model)?;
+        }
+        Err(e) => {
+            writeln!(output, "Couldn't list models on {}: {e}", config.host)?;
+            config.model = ask(input, output, "Model", &config.model)?;
+        }
+    }
+
+    let theme = ask(input, output, "Theme, bordered or minimal", "bordered")?;
+    config.minimal_ui = theme.eq_ignore_ascii_case("minimal");
+
+    config.save(path)?;
+    writeln!(output, "Wrote {}", path.display())?;
+    Ok(())
+}
+
+/// A model by its number in the list or by name
+fn pick_model(
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+    models: &[String],
+    default: &str,
+) -> anyhow::Result<String> {
+    let default = match chat::has_model(models, default) {
+        true => default,
+        false => &models[0],
+    };
+    let answer = ask(input, output, "Model (number or name)", default)?;
+
+    Ok(match answer.parse::<usize>() {
+        Ok(n) if (1..=models.len()).contains(&n) => models[n - 1].clone(),
+        _ => answer,
+    })
+}
+
+/// Print a question and read one answer, an empty line (or end of input) takes the default
+fn ask(
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+    question: &str,
+    default: &str,
+) -> anyhow::Result<String> {
+    write!(output, "{question} [{default}]: ")?;
+    output.flush()?;
+
+    let mut line = String::new();
+    input.read_line(&mut line)?;
+    Ok(match line.trim() {
+        "" => default.to_string(),
+        answer => answer.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn scripted_answers_end_up_in_the_config() {
+        let dir = std::env::temp_dir().join(format!("oxiai-wizard-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("script.json");
+        std::fs::write(&script, "[]").unwrap();
+        let host = crate::mock::start(&script).await.unwrap();
+
+        // a bad host first, then the mock, the first listed model and the minimal theme
+        let answers = format!("not a url\n{host}/\n1\nminimal\n");
+        let path = dir.join("config.json");
+        let mut output = Vec::new();
+        setup(&path, &mut answers.as_bytes(), &mut output)
+            .await
+            .unwrap();
+
+        let config = config::Config::load(Some(&path)).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert!(output.contains("not a url is not a valid url"));
+        assert!(output.contains("  1) mock"));
+        assert_eq!(config.host, host);
+        assert_eq!(config.model, "mock");
+        assert!(config.minimal_ui);
+    }
+
+    #[tokio::test]
+    async fn enter_all_the_way_keeps_the_defaults() {
+        let dir = std::env::temp_dir().join(format!("oxiai-wizard-empty-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.json");
+
+        // an unreachable host so the model question falls back to asking for a name
+        let answers = "http://127.0.0.1:9\n\n\n";
+        setup(&path, &mut answers.as_bytes(), &mut Vec::new())
+            .await
+            .unwrap();
+
+        let config = config::Config::load(Some(&path)).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let defaults = config::Config::default();
+        assert_eq!(config.model, defaults.model);
+        assert!(!config.minimal_ui);
+    }
+}
@@ -0,0 +1,115 @@
+//! Exit codes of the ways `oxiai` can stop before the UI comes up: 0 for `--help`, 2 for a
+//! command line (or config host) that doesn't make sense, 1 for anything it couldn't open or read.
+//! `doctor` has its own test next to it.
+
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+/// A scratch directory for one test, removed when it goes out of scope
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("oxiai-exit-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    /// A config file in the directory holding `json`
+    fn config(&self, json: &str) -> PathBuf {
+        let path = self.0.join("config.json");
+        std::fs::write(&path, json).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Exit code of `oxiai` run with `args`, the wizard off and nothing on stdin
+fn exit_code(args: &[&str]) -> i32 {
+    let output = Command::new(env!("CARGO_BIN_EXE_oxiai"))
+        .arg("--no-wizard")
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    output.status.code().expect("oxiai was killed by a signal")
+}
+
+#[test]
+fn help_is_a_success() {
+    assert_eq!(exit_code(&["--help"]), 0);
+}
+
+#[test]
+fn bad_arguments_exit_with_2() {
+    assert_eq!(exit_code(&["--no-such-flag"]), 2);
+    assert_eq!(exit_code(&["--host", "not a url"]), 2);
+    assert_eq!(exit_code(&["--temperature", "-1"]), 2);
+}
+
+#[test]
+fn a_bad_host_in_the_config_exits_with_2() {
+    let dir = TempDir::new("config-host");
+    let config = dir.config(r#"{"host": "not a url"}"#);
+    assert_eq!(exit_code(&["--config", config.to_str().unwrap()]), 2);
+}
+
+#[test]
+fn serve_without_a_transport_exits_with_2() {
+    let dir = TempDir::new("serve");
+    let config = dir.config("{}");
+    assert_eq!(
+        exit_code(&["--config", config.to_str().unwrap(), "serve"]),
+        2
+    );
+}
+
+#[test]
+fn a_config_that_cant_be_read_exits_with_1() {
+    let dir = TempDir::new("config");
+    let missing = dir.0.join("missing.json");
+    assert_eq!(exit_code(&["--config", missing.to_str().unwrap()]), 1);
+    let broken = dir.config("{ not json");
+    assert_eq!(exit_code(&["--config", broken.to_str().unwrap()]), 1);
+}
+
+#[test]
+fn a_log_file_that_cant_be_opened_exits_with_1() {
+    let dir = TempDir::new("log");
+    let log = dir.0.join("no/such/dir/oxiai.log");
+    assert_eq!(exit_code(&["--log-file", log.to_str().unwrap()]), 1);
+}
+
+#[test]
+fn a_missing_mock_script_exits_with_1() {
+    let dir = TempDir::new("mock");
+    let config = dir.config("{}");
+    let script = dir.0.join("missing.json");
+    let args = [
+        "--config",
+        config.to_str().unwrap(),
+        "--mock",
+        script.to_str().unwrap(),
+    ];
+    assert_eq!(exit_code(&args), 1);
+}
+
+#[test]
+fn a_missing_working_directory_exits_with_1() {
+    let dir = TempDir::new("workdir");
+    let config = dir.config("{}");
+    let workdir = dir.0.join("missing");
+    let args = [
+        "--config",
+        config.to_str().unwrap(),
+        "--workdir",
+        workdir.to_str().unwrap(),
+    ];
+    assert_eq!(exit_code(&args), 1);
+}